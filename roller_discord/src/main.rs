@@ -1,20 +1,52 @@
 use std::env;
+use std::time::Duration;
 
-use serenity::prelude::{Client as SerenityClient, GatewayIntents};
+use roller_lang::{
+    discord::{start, Options},
+    dynamodb::{make_client, DDBClient},
+    environments::{
+        caching_environment::CachingEnvironment, dynamodb_environment::DynamoDBEnvironment,
+    },
+    repl::REPL,
+    sqlite::SqliteClient,
+};
+
+const CACHE_METRICS_INTERVAL: Duration = Duration::from_secs(300);
 
 #[tokio::main]
 pub async fn main() {
     let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
-    let intents = GatewayIntents::GUILD_MESSAGES
-        | GatewayIntents::DIRECT_MESSAGES
-        | GatewayIntents::MESSAGE_CONTENT;
-
-    let mut client = SerenityClient::builder(&token, intents)
-        .event_handler(roller_lang::discord::Handler)
-        .await
-        .expect("Err creating client");
+    let options = Options {
+        message_commands: env::var("MESSAGE_COMMANDS").as_deref() != Ok("off"),
+        embeds: env::var("EMBEDS").as_deref() != Ok("off"),
+    };
 
-    if let Err(why) = client.start().await {
-        println!("Client error: {:?}", why);
+    match env::var("STORAGE_BACKEND").as_deref() {
+        Ok("sqlite") => {
+            let client = match env::var("SQLITE_PATH") {
+                Ok(path) => SqliteClient::open(path),
+                Err(_) => SqliteClient::with_default_path(),
+            };
+            let repl = REPL::new_sqlite(client.expect("cannot open sqlite database"));
+            start(&token, repl, options).await
+        }
+        Ok("dynamodb") | Err(_) => {
+            let client = DDBClient::with_default_table(
+                make_client(env::var("DYNAMODB_ENDPOINT").ok().as_deref())
+                    .await
+                    .expect("cannot start DDB client"),
+            );
+            let environment = CachingEnvironment::new(DynamoDBEnvironment::new(client));
+            let metrics = environment.metrics();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(CACHE_METRICS_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    println!("Environment cache {}", metrics);
+                }
+            });
+            start(&token, REPL::with_environment(environment), options).await
+        }
+        Ok(other) => panic!("Unknown STORAGE_BACKEND: {}", other),
     }
 }
@@ -0,0 +1,79 @@
+// Compares the two ways of building a template call's environment: snapshotting
+// the caller's whole scope into a HashMapEnvironment, and layering the argument
+// bindings over the caller's environment with lazy, cached lookups.
+
+use std::collections::HashMap;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use roller_lang::environments::hash_map_environment::HashMapEnvironment;
+use roller_lang::environments::layered_environment::LayeredEnvironment;
+use roller_lang::environments::sqlite_environment::SqliteEnvironment;
+use roller_lang::repl::REPLContext;
+use roller_lang::sqlite::SqliteClient;
+use roller_lang::types::{Environment, Expression};
+use tokio::runtime::Runtime;
+
+const SCOPE_SIZES: [usize; 3] = [10, 100, 1000];
+// Variables a typical template body refers to besides its own arguments.
+const USED_VARIABLES: [&str; 3] = ["var_0", "var_1", "var_2"];
+
+fn bindings() -> HashMap<String, Expression> {
+    HashMap::from([("arg".to_string(), Expression::Integer(1))])
+}
+
+async fn snapshot_call<E: Environment>(env: &E, ctx: &REPLContext) {
+    let closure = env.closure(ctx).await.unwrap();
+    let mut new_env = HashMapEnvironment::from_context_and_initial_values(ctx, closure);
+    for (name, value) in bindings() {
+        new_env.set(ctx, &name, &value).await.unwrap();
+    }
+    for name in USED_VARIABLES {
+        new_env.get(ctx, name).await.unwrap();
+    }
+}
+
+async fn layered_call<E: Environment + Sync>(env: &E, ctx: &REPLContext) {
+    let new_env = LayeredEnvironment::new(env).layer(bindings());
+    for name in USED_VARIABLES {
+        new_env.get(ctx, name).await.unwrap();
+    }
+}
+
+fn bench_backend<E: Environment + Sync>(
+    c: &mut Criterion,
+    backend: &str,
+    make_env: impl Fn() -> E,
+) {
+    let runtime = Runtime::new().unwrap();
+    let ctx = &REPLContext::new("bench".to_string(), "user".to_string());
+    let mut group = c.benchmark_group(format!("template_call/{}", backend));
+
+    for size in SCOPE_SIZES {
+        let mut env = make_env();
+        runtime.block_on(async {
+            for i in 0..size {
+                env.set(ctx, &format!("var_{}", i), &Expression::Integer(i as i64))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        group.bench_with_input(BenchmarkId::new("snapshot", size), &env, |b, env| {
+            b.iter(|| runtime.block_on(snapshot_call(env, ctx)))
+        });
+        group.bench_with_input(BenchmarkId::new("layered", size), &env, |b, env| {
+            b.iter(|| runtime.block_on(layered_call(env, ctx)))
+        });
+    }
+    group.finish();
+}
+
+fn template_environments(c: &mut Criterion) {
+    bench_backend(c, "hash_map", HashMapEnvironment::new);
+    bench_backend(c, "sqlite", || {
+        SqliteEnvironment::new(SqliteClient::open_in_memory().unwrap())
+    });
+}
+
+criterion_group!(benches, template_environments);
+criterion_main!(benches);
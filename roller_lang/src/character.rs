@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::RollerError;
+use crate::parser::parse_expression;
+use crate::state;
+use crate::types::{Context, Environment, Expression};
+
+const CHARACTERS_STATE: &str = "characters";
+
+// Variables starting with this, e.g. {char.str_mod}, are fields of the
+// character being played.
+pub const CHAR_PREFIX: &str = "char.";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldKind {
+    Attribute,
+    Skill,
+    Derived,
+    Template,
+}
+
+impl FieldKind {
+    // What `!char set` makes of an expression: numbers are attributes and
+    // template literals are templates. Anything else is derived from the
+    // other fields whenever it's used.
+    pub fn of(expr: &Expression) -> Self {
+        match expr {
+            Expression::Integer(_) => FieldKind::Attribute,
+            Expression::DiceRollTemplate { .. } => FieldKind::Template,
+            _ => FieldKind::Derived,
+        }
+    }
+}
+
+// A character sheet. Fields are kept as they were written and parsed when
+// they're used, so derived values pick up changes to what they refer to.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Character {
+    pub attributes: BTreeMap<String, String>,
+    pub skills: BTreeMap<String, String>,
+    pub derived: BTreeMap<String, String>,
+    pub templates: BTreeMap<String, String>,
+}
+
+impl Character {
+    fn sections(&self) -> [(&'static str, &BTreeMap<String, String>); 4] {
+        [
+            ("attributes", &self.attributes),
+            ("skills", &self.skills),
+            ("derived", &self.derived),
+            ("templates", &self.templates),
+        ]
+    }
+
+    fn source(&self, field: &str) -> Option<&String> {
+        self.sections()
+            .into_iter()
+            .find_map(|(_, section)| section.get(field))
+    }
+
+    pub fn field(&self, field: &str) -> Result<Expression, RollerError> {
+        let source = self.source(field).ok_or_else(|| {
+            RollerError::EvalError(format!("your character doesn't have {}", field))
+        })?;
+        parse_expression(source)
+    }
+
+    // Sets field, moving it to kind's section if it was in another.
+    pub fn set(
+        &mut self,
+        field: &str,
+        kind: FieldKind,
+        expr: &Expression,
+        source: &str,
+    ) -> Result<(), RollerError> {
+        if self.refers_to(expr, field) {
+            return Err(RollerError::EvalError(format!(
+                "{} can't be worked out from itself",
+                field
+            )));
+        }
+        for section in [
+            &mut self.attributes,
+            &mut self.skills,
+            &mut self.derived,
+            &mut self.templates,
+        ] {
+            section.remove(field);
+        }
+        let section = match kind {
+            FieldKind::Attribute => &mut self.attributes,
+            FieldKind::Skill => &mut self.skills,
+            FieldKind::Derived => &mut self.derived,
+            FieldKind::Template => &mut self.templates,
+        };
+        section.insert(field.to_string(), source.to_string());
+        Ok(())
+    }
+
+    // Whether expr, set as field, would end up referring back to field.
+    fn refers_to(&self, expr: &Expression, field: &str) -> bool {
+        let mut pending = field_refs(expr);
+        let mut seen = HashSet::new();
+        while let Some(name) = pending.pop() {
+            if name == field {
+                return true;
+            }
+            if seen.insert(name.clone()) {
+                if let Ok(expr) = self.field(&name) {
+                    pending.extend(field_refs(&expr));
+                }
+            }
+        }
+        false
+    }
+}
+
+// The character fields expr refers to.
+fn field_refs(expr: &Expression) -> Vec<String> {
+    match expr {
+        Expression::Variable(name) => name
+            .strip_prefix(CHAR_PREFIX)
+            .map(|field| vec![field.to_string()])
+            .unwrap_or_default(),
+        Expression::Term(left, right, _) => [field_refs(left), field_refs(right)].concat(),
+        Expression::DiceRoll { count, sides } => [field_refs(count), field_refs(sides)].concat(),
+        Expression::DiceRollTemplate { expressions, .. } => {
+            expressions.iter().flat_map(field_refs).collect()
+        }
+        Expression::DiceRollTemplateCall {
+            template_expression,
+            args,
+        } => field_refs(template_expression)
+            .into_iter()
+            .chain(args.iter().flat_map(field_refs))
+            .collect(),
+        Expression::Integer(_) => vec![],
+    }
+}
+
+// Everyone's characters are kept in their own scope, along with which one
+// they're playing.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Characters {
+    pub active: Option<String>,
+    pub sheets: BTreeMap<String, Character>,
+    // The version the sheets were loaded at, if they were loaded.
+    #[serde(skip)]
+    pub version: Option<u64>,
+}
+
+impl Characters {
+    pub async fn load<E: Environment + Sync, C: Context + Send>(
+        env: &E,
+        ctx: C,
+    ) -> Result<Self, RollerError> {
+        let (characters, version) = state::load(env, ctx, CHARACTERS_STATE, "characters").await?;
+        Ok(Characters {
+            version: Some(version),
+            ..characters.unwrap_or_default()
+        })
+    }
+
+    pub async fn save<E: Environment, C: Context + Send>(
+        &self,
+        env: &mut E,
+        ctx: C,
+    ) -> Result<(), RollerError> {
+        state::save(env, ctx, CHARACTERS_STATE, "characters", self, self.version).await
+    }
+
+    // Adds an empty sheet and starts playing it.
+    pub fn create(&mut self, name: &str) -> Result<(), RollerError> {
+        if self.sheets.contains_key(name) {
+            return Err(RollerError::EvalError(format!(
+                "you already have a character called {}",
+                name
+            )));
+        }
+        self.sheets.insert(name.to_string(), Character::default());
+        self.active = Some(name.to_string());
+        Ok(())
+    }
+
+    pub fn play(&mut self, name: &str) -> Result<(), RollerError> {
+        self.sheet(name)?;
+        self.active = Some(name.to_string());
+        Ok(())
+    }
+
+    pub fn sheet(&self, name: &str) -> Result<&Character, RollerError> {
+        self.sheets.get(name).ok_or_else(|| {
+            RollerError::EvalError(format!("you don't have a character called {}", name))
+        })
+    }
+
+    fn active_name(&self) -> Result<&String, RollerError> {
+        self.active.as_ref().ok_or_else(|| {
+            RollerError::EvalError("make a character with !char create first".to_string())
+        })
+    }
+
+    pub fn active(&self) -> Result<&Character, RollerError> {
+        self.sheet(self.active_name()?)
+    }
+
+    pub fn active_mut(&mut self) -> Result<&mut Character, RollerError> {
+        let name = self.active_name()?.clone();
+        self.sheets
+            .get_mut(&name)
+            .ok_or_else(|| RollerError::EvalError(format!("{} has gone missing", name)))
+    }
+
+    // name's sheet, or the active one's, for `!char show`.
+    pub fn show(&self, name: Option<&String>) -> Result<String, RollerError> {
+        let name = match name {
+            Some(name) => name,
+            None => self.active_name()?,
+        };
+        Ok(format!("{}\n{}", name, self.sheet(name)?))
+    }
+}
+
+impl Display for Character {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut lines = vec![];
+        for (heading, section) in self.sections() {
+            if !section.is_empty() {
+                let fields: Vec<String> = section
+                    .iter()
+                    .map(|(name, source)| format!("{} => {}", name, source))
+                    .collect();
+                lines.push(format!("{}: {}", heading, fields.join(", ")));
+            }
+        }
+        if lines.is_empty() {
+            write!(f, "an empty sheet")
+        } else {
+            write!(f, "{}", lines.join("\n"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(character: &mut Character, field: &str, source: &str) -> Result<(), RollerError> {
+        let expr = parse_expression(source).unwrap();
+        character.set(field, FieldKind::of(&expr), &expr, source)
+    }
+
+    #[test]
+    fn test_sheet() {
+        let mut characters = Characters::default();
+        characters.create("Thorn").unwrap();
+        assert!(characters.create("Thorn").is_err());
+        let thorn = characters.active_mut().unwrap();
+        set(thorn, "str", "16").unwrap();
+        set(thorn, "str_mod", "{char.str} - 10").unwrap();
+        set(thorn, "attack", "(m) => (1d20 + {char.str_mod} + {m})").unwrap();
+        let stealth = parse_expression("{char.str_mod} + 2").unwrap();
+        thorn
+            .set("stealth", FieldKind::Skill, &stealth, "{char.str_mod} + 2")
+            .unwrap();
+        assert_eq!(
+            characters.show(None).unwrap(),
+            "Thorn\nattributes: str => 16\nskills: stealth => {char.str_mod} + 2\n\
+             derived: str_mod => {char.str} - 10\n\
+             templates: attack => (m) => (1d20 + {char.str_mod} + {m})"
+        );
+
+        // Fields can't be worked out from themselves, even indirectly.
+        let thorn = characters.active_mut().unwrap();
+        assert!(set(thorn, "str", "{char.stealth} + 1").is_err());
+        assert!(set(thorn, "str", "{char.str}").is_err());
+        set(thorn, "str", "18").unwrap();
+
+        characters.create("Wren").unwrap();
+        assert_eq!(characters.show(None).unwrap(), "Wren\nan empty sheet");
+        characters.play("Thorn").unwrap();
+        assert!(characters.play("Nobody").is_err());
+        assert_eq!(
+            characters.active().unwrap().field("str").unwrap(),
+            Expression::Integer(18)
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::RollerError;
+use crate::state;
+use crate::types::{Context, Environment, Guild};
+
+const CONFIG_STATE: &str = "config";
+const MAX_PREFIX_LEN: usize = 5;
+
+// A server's settings, kept in its guild scope. Settings saved before one was
+// added read as its default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // What statements start with instead of `!`, e.g. when another bot uses it.
+    pub prefix: String,
+    pub locale: String,
+    // The most dice one statement can roll, and the most sides a die can have.
+    pub max_dice: usize,
+    pub max_sides: i64,
+    // Whether rolls show each die as well as the total.
+    pub breakdown: bool,
+    // The channels the bot answers in, or every channel if empty.
+    pub channels: Vec<u64>,
+    // The version the settings were loaded at, so saving them can't undo a
+    // change made in the meantime.
+    #[serde(skip)]
+    pub version: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            prefix: "!".to_string(),
+            locale: "en".to_string(),
+            max_dice: 1000,
+            max_sides: 1_000_000,
+            breakdown: true,
+            channels: vec![],
+            version: None,
+        }
+    }
+}
+
+impl Config {
+    // The settings for ctx's server, or the defaults if none have been changed.
+    pub async fn load<E: Environment + Sync, C: Context + Send>(
+        env: &E,
+        ctx: C,
+    ) -> Result<Self, RollerError> {
+        let (config, version) = state::load(env, Guild(ctx), CONFIG_STATE, "settings").await?;
+        Ok(Config {
+            version: Some(version),
+            ..config.unwrap_or_default()
+        })
+    }
+
+    pub async fn save<E: Environment, C: Context + Send>(
+        &self,
+        env: &mut E,
+        ctx: C,
+    ) -> Result<(), RollerError> {
+        state::save(
+            env,
+            Guild(ctx),
+            CONFIG_STATE,
+            "settings",
+            self,
+            self.version,
+        )
+        .await
+    }
+
+    pub fn allows_channel(&self, channel_id: u64) -> bool {
+        self.channels.is_empty() || self.channels.contains(&channel_id)
+    }
+
+    // Changes the setting called key, as written by `!config key value`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), RollerError> {
+        let invalid = |expected: &str| {
+            RollerError::EvalError(format!("{} should be {}, not {}", key, expected, value))
+        };
+        match key {
+            "prefix" => {
+                if value.is_empty()
+                    || value.chars().count() > MAX_PREFIX_LEN
+                    || value.contains(char::is_whitespace)
+                {
+                    return Err(invalid("up to 5 characters without spaces"));
+                }
+                self.prefix = value.to_string();
+            }
+            "locale" => {
+                if !rust_i18n::available_locales!().contains(&value) {
+                    return Err(invalid(&rust_i18n::available_locales!().join(", ")));
+                }
+                self.locale = value.to_string();
+            }
+            "max-dice" => {
+                self.max_dice = value
+                    .parse()
+                    .ok()
+                    .filter(|max| *max > 0)
+                    .ok_or_else(|| invalid("a positive number"))?;
+            }
+            "max-sides" => {
+                self.max_sides = value
+                    .parse()
+                    .ok()
+                    .filter(|max| *max > 0)
+                    .ok_or_else(|| invalid("a positive number"))?;
+            }
+            "breakdown" => {
+                self.breakdown = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(invalid("on or off")),
+                };
+            }
+            "channels" => {
+                self.channels = match value {
+                    "all" => vec![],
+                    _ => value
+                        .split_whitespace()
+                        .map(|channel| {
+                            channel
+                                .strip_prefix("<#")
+                                .and_then(|channel| channel.strip_suffix('>'))
+                                .and_then(|channel| channel.parse().ok())
+                        })
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| invalid("all or a list of #channels"))?,
+                };
+            }
+            _ => {
+                return Err(RollerError::EvalError(format!(
+                    "there's no setting called {}",
+                    key
+                )))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "prefix: {}", self.prefix)?;
+        writeln!(f, "locale: {}", self.locale)?;
+        writeln!(f, "max-dice: {}", self.max_dice)?;
+        writeln!(f, "max-sides: {}", self.max_sides)?;
+        writeln!(
+            f,
+            "breakdown: {}",
+            if self.breakdown { "on" } else { "off" }
+        )?;
+        if self.channels.is_empty() {
+            write!(f, "channels: all")
+        } else {
+            let channels: Vec<String> = self
+                .channels
+                .iter()
+                .map(|channel| format!("<#{}>", channel))
+                .collect();
+            write!(f, "channels: {}", channels.join(" "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let mut config = Config::default();
+        config.set("prefix", "?").unwrap();
+        config.set("max-dice", "20").unwrap();
+        config.set("breakdown", "off").unwrap();
+        config.set("channels", "<#1> <#22>").unwrap();
+        assert_eq!(
+            config.to_string(),
+            "prefix: ?\nlocale: en\nmax-dice: 20\nmax-sides: 1000000\nbreakdown: off\nchannels: <#1> <#22>"
+        );
+        assert!(config.allows_channel(22) && !config.allows_channel(3));
+
+        for (key, value) in [
+            ("prefix", "! !"),
+            ("locale", "xx"),
+            ("max-dice", "0"),
+            ("breakdown", "yes"),
+            ("channels", "#general"),
+            ("colour", "red"),
+        ] {
+            assert!(config.set(key, value).is_err(), "{} {}", key, value);
+        }
+        config.set("channels", "all").unwrap();
+        assert!(config.allows_channel(3));
+
+        // Settings saved before max-sides existed.
+        let saved: Config = serde_json::from_str(r#"{"prefix": "?"}"#).unwrap();
+        assert_eq!(saved.prefix, "?");
+        assert_eq!(saved.max_sides, Config::default().max_sides);
+    }
+}
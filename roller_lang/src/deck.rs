@@ -0,0 +1,233 @@
+use std::fmt::{self, Display};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::error::RollerError;
+use crate::state;
+use crate::types::{Context, DeckKind, Environment, Global};
+
+const SUITS: [&str; 4] = ["♠", "♥", "♦", "♣"];
+const RANKS: [&str; 13] = [
+    "A", "2", "3", "4", "5", "6", "7", "8", "9", "10", "J", "Q", "K",
+];
+const MAJOR_ARCANA: [&str; 22] = [
+    "The Fool",
+    "The Magician",
+    "The High Priestess",
+    "The Empress",
+    "The Emperor",
+    "The Hierophant",
+    "The Lovers",
+    "The Chariot",
+    "Strength",
+    "The Hermit",
+    "Wheel of Fortune",
+    "Justice",
+    "The Hanged Man",
+    "Death",
+    "Temperance",
+    "The Devil",
+    "The Tower",
+    "The Star",
+    "The Moon",
+    "The Sun",
+    "Judgement",
+    "The World",
+];
+const TAROT_SUITS: [&str; 4] = ["Wands", "Cups", "Swords", "Pentacles"];
+const TAROT_RANKS: [&str; 14] = [
+    "Ace", "Two", "Three", "Four", "Five", "Six", "Seven", "Eight", "Nine", "Ten", "Page",
+    "Knight", "Queen", "King",
+];
+
+fn state_name(name: &str) -> String {
+    format!("deck:{}", name)
+}
+
+impl DeckKind {
+    // The cards in a new deck of this kind, in order.
+    pub fn cards(&self) -> Vec<String> {
+        match self {
+            DeckKind::Standard => SUITS
+                .iter()
+                .flat_map(|suit| RANKS.iter().map(move |rank| format!("{}{}", rank, suit)))
+                .chain(["Red Joker".to_string(), "Black Joker".to_string()])
+                .collect(),
+            DeckKind::Tarot => MAJOR_ARCANA
+                .iter()
+                .map(|card| card.to_string())
+                .chain(TAROT_SUITS.iter().flat_map(|suit| {
+                    TAROT_RANKS
+                        .iter()
+                        .map(move |rank| format!("{} of {}", rank, suit))
+                }))
+                .collect(),
+            DeckKind::Custom(cards) => cards.clone(),
+        }
+    }
+}
+
+// A channel's deck of cards. Cards are drawn from the end of pile and stay in
+// drawn until they're discarded, so every card is always in one of the three.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Deck {
+    pub pile: Vec<String>,
+    pub drawn: Vec<String>,
+    pub discards: Vec<String>,
+    // The version the deck was loaded at. A new deck replaces any deck of the
+    // same name.
+    #[serde(skip)]
+    pub version: Option<u64>,
+}
+
+impl Deck {
+    // A shuffled deck of kind.
+    pub fn new(kind: &DeckKind, rng: &mut impl Rng) -> Self {
+        let mut deck = Deck {
+            pile: kind.cards(),
+            ..Deck::default()
+        };
+        deck.shuffle(rng);
+        deck
+    }
+
+    pub async fn load<E: Environment + Sync, C: Context + Send>(
+        env: &E,
+        ctx: C,
+        name: &str,
+    ) -> Result<Self, RollerError> {
+        match state::load(env, Global(ctx), &state_name(name), "deck").await? {
+            (Some(deck), version) => Ok(Deck {
+                version: Some(version),
+                ..deck
+            }),
+            (None, _) => Err(RollerError::EvalError(format!(
+                "there's no deck called {} here",
+                name
+            ))),
+        }
+    }
+
+    pub async fn save<E: Environment, C: Context + Send>(
+        &self,
+        env: &mut E,
+        ctx: C,
+        name: &str,
+    ) -> Result<(), RollerError> {
+        state::save(
+            env,
+            Global(ctx),
+            &state_name(name),
+            "deck",
+            self,
+            self.version,
+        )
+        .await
+    }
+
+    pub fn size(&self) -> usize {
+        self.pile.len() + self.drawn.len() + self.discards.len()
+    }
+
+    // Shuffles the cards left to draw, leaving drawn and discarded ones out.
+    pub fn shuffle(&mut self, rng: &mut impl Rng) {
+        self.pile.shuffle(rng);
+    }
+
+    // Puts every card back in the pile and shuffles it.
+    pub fn reshuffle(&mut self, rng: &mut impl Rng) {
+        self.pile.append(&mut self.drawn);
+        self.pile.append(&mut self.discards);
+        self.shuffle(rng);
+    }
+
+    pub fn draw(&mut self, count: usize) -> Result<&[String], RollerError> {
+        if count > self.pile.len() {
+            return Err(RollerError::EvalError(format!(
+                "there {} only {} left to draw, reshuffle to put the rest back",
+                if self.pile.len() == 1 { "is" } else { "are" },
+                cards(self.pile.len())
+            )));
+        }
+        let start = self.drawn.len();
+        for _ in 0..count {
+            self.drawn.extend(self.pile.pop());
+        }
+        Ok(&self.drawn[start..])
+    }
+
+    // The next count cards to be drawn, in the order they'd be drawn.
+    pub fn peek(&self, count: usize) -> Vec<&String> {
+        self.pile.iter().rev().take(count).collect()
+    }
+
+    // Moves the drawn cards to the discard pile, returning how many there were.
+    pub fn discard(&mut self) -> usize {
+        let count = self.drawn.len();
+        self.discards.append(&mut self.drawn);
+        count
+    }
+}
+
+// "1 card" or "n cards".
+pub fn cards(count: usize) -> String {
+    if count == 1 {
+        "1 card".to_string()
+    } else {
+        format!("{} cards", count)
+    }
+}
+
+impl Display for Deck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} to draw, {} discarded",
+            cards(self.pile.len()),
+            self.discards.len()
+        )?;
+        if !self.drawn.is_empty() {
+            write!(f, "\ndrawn: {}", self.drawn.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::mock::StepRng;
+
+    #[test]
+    fn test_draw() {
+        assert_eq!(DeckKind::Standard.cards().len(), 54);
+        assert_eq!(DeckKind::Tarot.cards().len(), 78);
+        assert_eq!(DeckKind::Tarot.cards()[77], "King of Pentacles");
+
+        let cards = DeckKind::Custom(vec![
+            "Sun".to_string(),
+            "Moon".to_string(),
+            "Star".to_string(),
+        ]);
+        let mut deck = Deck::new(&cards, &mut StepRng::new(0, 1));
+        let peeked: Vec<String> = deck.peek(2).into_iter().cloned().collect();
+        assert_eq!(deck.draw(2).unwrap(), peeked.as_slice());
+        assert!(deck.draw(2).is_err());
+        assert_eq!(deck.discard(), 2);
+        deck.draw(1).unwrap();
+        assert!(deck.pile.is_empty());
+        assert_eq!(
+            deck.to_string(),
+            format!("0 cards to draw, 2 discarded\ndrawn: {}", deck.drawn[0])
+        );
+
+        deck.reshuffle(&mut StepRng::new(0, 1));
+        assert_eq!(deck.pile.len(), 3);
+        assert_eq!(deck.size(), 3);
+        let mut pile = deck.pile.clone();
+        pile.sort();
+        assert_eq!(pile, vec!["Moon", "Star", "Sun"]);
+    }
+}
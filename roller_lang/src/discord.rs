@@ -1,50 +1,794 @@
+pub mod buttons;
+pub mod embeds;
+pub mod slash_commands;
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
 use serenity::{
+    all::{
+        ChannelId, Command, CommandInteraction, ComponentInteraction, CreateAttachment,
+        CreateAutocompleteResponse, CreateEmbed, CreateInteractionResponse,
+        CreateInteractionResponseMessage, CreateMessage, EditInteractionResponse, EditMessage,
+        GuildId, Interaction, Member, User, UserId,
+    },
     async_trait,
     model::{channel::Message, gateway::Ready},
-    prelude::{Context, EventHandler, TypeMapKey},
+    prelude::{Client, Context, EventHandler, GatewayIntents, TypeMapKey},
 };
 
 use crate::{
-    dynamodb::{make_client, DDBClient},
-    environments::dynamodb_environment::DynamoDBEnvironment,
+    config::Config,
+    error::RollerError,
+    export::DEFAULT_EXPORT_NAME,
+    render::Renderer,
     repl::{REPLContext, REPL},
+    roll_log::DEFAULT_LOG_NAME,
+    schema::MigrationReport,
+    types::{Context as _, Environment, Guild, Output, SharedEnvironment, Statement},
 };
+use embeds::EmbedRenderer;
+
+#[derive(Clone)]
+pub enum Reply {
+    Text(String),
+    Embed(Box<CreateEmbed>),
+    File(String, String),
+    // A reply sent by DM to the author and recipients, leaving only the notice
+    // in the channel.
+    Secret {
+        notice: String,
+        reply: Box<Reply>,
+        recipients: Vec<u64>,
+    },
+}
+
+impl Reply {
+    fn message(self) -> CreateMessage {
+        match self {
+            Reply::Text(text) => CreateMessage::new().content(format!("{}\n", text)),
+            Reply::Embed(embed) => CreateMessage::new().embed(*embed),
+            Reply::File(name, contents) => {
+                CreateMessage::new().add_file(CreateAttachment::bytes(contents, name))
+            }
+            Reply::Secret { notice, .. } => CreateMessage::new().content(notice),
+        }
+    }
+
+    fn interaction_response(self) -> CreateInteractionResponseMessage {
+        match self {
+            Reply::Text(text) => CreateInteractionResponseMessage::new().content(text),
+            Reply::Embed(embed) => CreateInteractionResponseMessage::new().embed(*embed),
+            Reply::File(name, contents) => CreateInteractionResponseMessage::new()
+                .add_file(CreateAttachment::bytes(contents, name)),
+            Reply::Secret { notice, .. } => CreateInteractionResponseMessage::new().content(notice),
+        }
+    }
+}
+
+// Sends a secret reply by DM to its author and recipients, returning what's
+// left to post in the channel.
+async fn deliver(ctx: &Context, author: UserId, reply: Reply) -> Reply {
+    let Reply::Secret {
+        notice,
+        reply,
+        mut recipients,
+    } = reply
+    else {
+        return reply;
+    };
+    recipients.push(author.get());
+    recipients.sort();
+    recipients.dedup();
+
+    let mut undelivered = vec![];
+    for recipient in recipients {
+        let sent = match UserId::new(recipient).create_dm_channel(&ctx.http).await {
+            Ok(channel) => channel
+                .id
+                .send_message(&ctx.http, (*reply).clone().message())
+                .await
+                .map(|_| ()),
+            Err(err) => Err(err),
+        };
+        if let Err(why) = sent {
+            println!("Error sending secret roll to {}: {:?}", recipient, why);
+            undelivered.push(format!("<@{}>", recipient));
+        }
+    }
+    if undelivered.is_empty() {
+        Reply::Text(notice)
+    } else {
+        Reply::Text(format!(
+            "{} (couldn't send it to {})",
+            notice,
+            undelivered.join(", ")
+        ))
+    }
+}
+
+// Bot wide settings, read from the environment at startup.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    // `!` commands need the privileged MESSAGE_CONTENT intent; slash commands don't.
+    pub message_commands: bool,
+    // Rolls are sent as plain text when embeds are off, e.g. in channels where
+    // the bot can't embed links.
+    pub embeds: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            message_commands: true,
+            embeds: true,
+        }
+    }
+}
+
+// The name and text of the message's first attachment, or an error asking for
+// what to attach.
+async fn read_attachment(msg: &Message, expected: &str) -> Result<(String, String), RollerError> {
+    let attachment = msg
+        .attachments
+        .first()
+        .ok_or_else(|| RollerError::EvalError(format!("attach {} to import", expected)))?;
+    let document = attachment.download().await.map_err(|err| {
+        RollerError::EvalError(format!(
+            "failed to download {}: {}",
+            attachment.filename, err
+        ))
+    })?;
+    let document = String::from_utf8(document).map_err(|_| {
+        RollerError::EvalError(format!("{} isn't a text file", attachment.filename))
+    })?;
+    Ok((attachment.filename.clone(), document))
+}
+
+// Runs a message, sending `!export` and `!log export` results as an attachment and reading
+// `!import` and `!table import` documents from the message's first attachment.
+async fn exec<E: Environment + Sync>(
+    repl: &mut REPL<E>,
+    repl_ctx: &REPLContext,
+    renderer: &EmbedRenderer,
+    msg: &Message,
+    stmt: &Statement,
+) -> Result<Reply, RollerError> {
+    match stmt {
+        Statement::Export(name) => {
+            let name = name.clone().unwrap_or(DEFAULT_EXPORT_NAME.to_string());
+            let export = Statement::Export(Some(name.clone()));
+            Ok(Reply::File(
+                name,
+                repl.exec_statement(repl_ctx, &export).await?.to_string(),
+            ))
+        }
+        Statement::LogExport(name, period) => {
+            let name = name.clone().unwrap_or(DEFAULT_LOG_NAME.to_string());
+            let export = Statement::LogExport(Some(name.clone()), *period);
+            Ok(Reply::File(
+                name,
+                repl.exec_statement(repl_ctx, &export).await?.to_string(),
+            ))
+        }
+        Statement::Import(_, on_conflict) => {
+            let (name, document) = read_attachment(msg, "an exported .json or .yaml file").await?;
+            let report = repl
+                .import(repl_ctx, &name, &document, *on_conflict)
+                .await?;
+            Ok(Reply::Text(report))
+        }
+        Statement::TableImport(_) => {
+            let (name, document) = read_attachment(msg, "a .csv or .yaml file of tables").await?;
+            Ok(Reply::Text(
+                repl.import_tables(repl_ctx, &name, &document).await?,
+            ))
+        }
+        // Quoted, so the reply doesn't start with the prefix and read as a
+        // command, but can still be copied to change and send back.
+        Statement::Edit(_) => Ok(Reply::Text(format!(
+            "`{}`",
+            repl.exec_statement(repl_ctx, stmt).await?
+        ))),
+        _ => Ok(renderer.render(&repl.exec_statement(repl_ctx, stmt).await?)),
+    }
+}
+
+pub struct Handler<E> {
+    environment: PhantomData<E>,
+    options: Options,
+    // Server settings by guild scope, with when they were read.
+    configs: Mutex<HashMap<String, (Instant, Config)>>,
+}
+
+impl<E> Handler<E> {
+    pub fn new(options: Options) -> Self {
+        Handler {
+            environment: PhantomData,
+            options,
+            configs: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<E> Default for Handler<E> {
+    fn default() -> Self {
+        Handler::new(Options::default())
+    }
+}
 
-pub struct Handler;
+// The name a roll is credited to: the author's server nickname if they have one.
+fn display_name(msg: &Message) -> String {
+    msg.member
+        .as_ref()
+        .and_then(|member| member.nick.clone())
+        .unwrap_or_else(|| msg.author.display_name().to_string())
+}
 
-impl TypeMapKey for REPL<DynamoDBEnvironment> {
-    type Value = REPL<DynamoDBEnvironment>;
+// Whether msg's author can change the server's settings or GMs, which takes the
+// Manage Server permission. Anyone can change the settings of their DMs.
+async fn is_admin(ctx: &Context, msg: &Message) -> bool {
+    let (Some(guild_id), Some(member)) = (msg.guild_id, msg.member.as_deref()) else {
+        return msg.guild_id.is_none();
+    };
+    match guild_id.to_partial_guild(&ctx.http).await {
+        Ok(guild) => guild
+            .partial_member_permissions(msg.author.id, member)
+            .manage_guild(),
+        Err(why) => {
+            println!("Error fetching server {}: {:?}", guild_id, why);
+            false
+        }
+    }
+}
+
+fn not_allowed_here() -> RollerError {
+    RollerError::EvalError("I've been turned off in this channel".to_string())
+}
+
+fn member_name(member: Option<&Member>, user: &User) -> String {
+    match member {
+        Some(member) => member.display_name().to_string(),
+        None => user.display_name().to_string(),
+    }
+}
+
+// Scopes are keyed on ids, which unlike names can't be changed; names are only
+// used to credit rolls.
+fn user_context(channel_id: ChannelId, guild_id: Option<GuildId>, user: &User) -> REPLContext {
+    let repl_ctx = REPLContext::new(channel_id.to_string(), user.id.to_string());
+    match guild_id {
+        Some(guild_id) => repl_ctx.with_guild(guild_id.to_string()),
+        None => repl_ctx,
+    }
+    .with_home_scope()
+}
+
+// Reads a list of Discord usernames and the ids they belong to, one
+// `username,id` pair to a line.
+pub fn read_user_ids(text: &str) -> Result<HashMap<String, u64>, RollerError> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            line.split_once(',')
+                .and_then(|(name, id)| Some((name.trim().to_string(), id.trim().parse().ok()?)))
+                .ok_or_else(|| {
+                    RollerError::EvalError(format!("expected username,id but got {}", line))
+                })
+        })
+        .collect()
+}
+
+// Moves what's saved in the user scopes among scopes that are keyed on a
+// username, from before scopes were keyed on user ids, to the id user_ids
+// gives for that name. Names without an id are left alone and counted as
+// failures, as are scopes that couldn't be moved.
+pub async fn migrate_usernames<E: Environment + Sync>(
+    repl: &mut REPL<E>,
+    scopes: &[String],
+    user_ids: &HashMap<String, u64>,
+) -> MigrationReport {
+    let mut report = MigrationReport::default();
+    for key in scopes {
+        let Some(legacy) = REPLContext::from_user_context_key(key) else {
+            continue;
+        };
+        let name = (&legacy).user_id();
+        if name.parse::<u64>().is_ok() {
+            continue;
+        }
+        report.scanned += 1;
+        let Some(id) = user_ids.get(&name) else {
+            report
+                .failures
+                .push(format!("no id for {} in {}", name, key));
+            continue;
+        };
+        let to = REPLContext::new((&legacy).scope_name(), id.to_string());
+        match repl.move_scope(&legacy, &to).await {
+            Ok(_) => report.migrated += 1,
+            Err(err) => report.failures.push(format!("{}: {}", key, err)),
+        }
+    }
+    report
+}
+
+// Saves the roll behind a message's buttons, reporting whether the buttons
+// will work.
+async fn remember_roll<E: Environment + Sync>(
+    repl: &mut REPL<E>,
+    message_id: &str,
+    stmt: &Statement,
+) -> bool {
+    match repl.remember_roll(message_id, stmt).await {
+        Ok(()) => true,
+        Err(err) => {
+            println!("Error: {} remembering roll for message {}", err, message_id);
+            false
+        }
+    }
+}
+
+impl<E: Environment + Send + Sync + 'static> TypeMapKey for REPL<E> {
+    type Value = REPL<E>;
+}
+
+// How long a server's settings are used before being read again, so changes
+// made through another shard are picked up.
+const CONFIG_TTL: Duration = Duration::from_secs(60);
+
+// Each event runs on its own fork of the REPL, so events are handled
+// concurrently rather than one at a time behind a write lock.
+async fn fork<E: SharedEnvironment + 'static>(ctx: &Context) -> REPL<E> {
+    ctx.data.read().await.get::<REPL<E>>().unwrap().fork()
 }
 
 #[async_trait]
-impl EventHandler for Handler {
+impl<E: SharedEnvironment + 'static> EventHandler for Handler<E> {
     async fn message(&self, ctx: Context, msg: Message) {
-        if !msg.content.starts_with('!') {
+        // Bots, this one included, don't run commands.
+        if msg.author.bot {
             return;
         }
+        let repl = &mut fork::<E>(&ctx).await;
+        let config = self
+            .config(
+                repl,
+                &user_context(msg.channel_id, msg.guild_id, &msg.author),
+            )
+            .await;
+        if !msg.content.starts_with(&config.prefix) {
+            return;
+        }
+        let stmt = repl.parse_prefixed(&msg.content, &config.prefix);
+        // Settings can be seen and changed from any channel, so turning the
+        // bot off everywhere can be undone.
+        if !config.allows_channel(msg.channel_id.get()) && !matches!(stmt, Ok(Statement::Config(_)))
+        {
+            return;
+        }
+        let admin = matches!(
+            stmt,
+            Ok(Statement::Config(Some(_)) | Statement::AddGm(_) | Statement::RemoveGm(_))
+        ) && is_admin(&ctx, &msg).await;
 
-        let mut data = ctx.data.write().await;
-        let repl = data.get_mut::<REPL<DynamoDBEnvironment>>().unwrap();
-        let repl_ctx = &REPLContext::new(msg.channel_id.to_string(), msg.author.name);
-        let response = match repl.exec(repl_ctx, &msg.content).await {
-            Ok(eval_result) => format!("{}\n", eval_result),
+        // Successful rolls get buttons to roll them again.
+        let (response, roll) = self.respond(repl, &msg, stmt, admin).await;
+        let mut response = match response {
+            Ok(reply) => deliver(&ctx, msg.author.id, reply).await.message(),
             Err(err) => {
                 println!("Error: {} parsing or evaluating msg: {}", err, &msg.content);
-                format!("{}\n", err)
+                CreateMessage::new().content(format!("{}\n", err))
+            }
+        };
+        if roll.is_some() {
+            response = response.components(buttons::buttons());
+        }
+        let mut sent = match msg.channel_id.send_message(&ctx.http, response).await {
+            Ok(sent) => sent,
+            Err(why) => {
+                println!("Error sending message: {:?}", why);
+                return;
             }
         };
-        if let Err(why) = msg.channel_id.say(&ctx.http, response).await {
-            println!("Error sending message: {:?}", why);
+        if let Some(stmt) = roll {
+            if !remember_roll(repl, &sent.id.to_string(), &stmt).await {
+                let remove_buttons = EditMessage::new().components(vec![]);
+                if let Err(why) = sent.edit(&ctx.http, remove_buttons).await {
+                    println!("Error removing buttons: {:?}", why);
+                }
+            }
+        }
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        match interaction {
+            Interaction::Command(command) => self.run_command(&ctx, &command).await,
+            Interaction::Autocomplete(command) => autocomplete::<E>(&ctx, &command).await,
+            Interaction::Component(component) => self.press_button(&ctx, &component).await,
+            _ => {}
         }
     }
 
     async fn ready(&self, ctx: Context, ready: Ready) {
-        let mut data = ctx.data.write().await;
-        let ddb_client = DDBClient::with_default_table(
-            make_client(false).await.expect("cannot start DDB client"),
-        );
-        let repl = REPL::new(ddb_client);
-        data.insert::<REPL<DynamoDBEnvironment>>(repl);
         println!("{} is connected!", ready.user.name);
+        if let Err(why) = Command::set_global_commands(&ctx.http, slash_commands::commands()).await
+        {
+            println!("Error registering slash commands: {:?}", why);
+        }
+    }
+}
+
+fn command_context(command: &CommandInteraction) -> REPLContext {
+    user_context(command.channel_id, command.guild_id, &command.user)
+}
+
+impl<E: SharedEnvironment + 'static> Handler<E> {
+    // Runs the statement parsed from a message, returning the reply along
+    // with the statement if it was a successful roll.
+    async fn respond(
+        &self,
+        repl: &mut REPL<E>,
+        msg: &Message,
+        stmt: Result<Statement, RollerError>,
+        admin: bool,
+    ) -> (Result<Reply, RollerError>, Option<Statement>) {
+        let mut repl_ctx = self
+            .user_context(repl, msg.channel_id, msg.guild_id, &msg.author)
+            .await;
+        if admin {
+            repl_ctx = repl_ctx.as_admin();
+        }
+        let renderer = EmbedRenderer::new(display_name(msg), self.options.embeds)
+            .with_breakdown((&repl_ctx).config().breakdown);
+        match stmt {
+            Ok(stmt) => {
+                let response = exec(repl, &repl_ctx, &renderer, msg, &stmt).await;
+                if matches!(stmt, Statement::Config(Some(_))) && response.is_ok() {
+                    let key = Guild(&repl_ctx).user_context_key();
+                    self.configs.lock().unwrap().remove(&key);
+                }
+                let roll = matches!(stmt, Statement::Roll(_)) && response.is_ok();
+                (response, roll.then_some(stmt))
+            }
+            Err(err) => (Err(err), None),
+        }
+    }
+
+    // The settings of the server repl_ctx is in. They're cached for a while so
+    // that messages meant for other bots don't each cost a read.
+    async fn config(&self, repl: &REPL<E>, repl_ctx: &REPLContext) -> Config {
+        let key = Guild(repl_ctx).user_context_key();
+        let cached = self.configs.lock().unwrap().get(&key).cloned();
+        if let Some((read_at, config)) = cached {
+            if read_at.elapsed() < CONFIG_TTL {
+                return config;
+            }
+        }
+        match repl.config(repl_ctx).await {
+            Ok(config) => {
+                let entry = (Instant::now(), config.clone());
+                self.configs.lock().unwrap().insert(key, entry);
+                config
+            }
+            Err(err) => {
+                println!("Error: {} reading settings for {}", err, key);
+                Config::default()
+            }
+        }
+    }
+
+    // The context a user's statements in a channel run in, with the server's
+    // settings.
+    async fn user_context(
+        &self,
+        repl: &mut REPL<E>,
+        channel_id: ChannelId,
+        guild_id: Option<GuildId>,
+        user: &User,
+    ) -> REPLContext {
+        let repl_ctx = user_context(channel_id, guild_id, user);
+        let config = self.config(repl, &repl_ctx).await;
+        repl_ctx.with_config(config)
+    }
+
+    async fn run_command(&self, ctx: &Context, command: &CommandInteraction) {
+        let embeds = self.options.embeds;
+        let repl = &mut fork::<E>(ctx).await;
+        let repl_ctx = &self
+            .user_context(repl, command.channel_id, command.guild_id, &command.user)
+            .await;
+        let config = repl_ctx.config();
+        let parsed = if config.allows_channel(command.channel_id.get()) {
+            slash_commands::statement_text(command).and_then(|input| repl.parse(&input))
+        } else {
+            Err(not_allowed_here())
+        };
+        let (response, stmt) = match parsed {
+            Ok(stmt) => (repl.exec_statement(repl_ctx, &stmt).await, Some(stmt)),
+            Err(err) => (Err(err), None),
+        };
+        let display_name = member_name(command.member.as_deref(), &command.user);
+        let (reply, roll) = match response {
+            Ok(output) => (
+                EmbedRenderer::new(display_name, embeds)
+                    .with_breakdown(config.breakdown)
+                    .render(&output),
+                stmt.filter(|_| matches!(output, Output::Roll(_))),
+            ),
+            Err(err) => {
+                println!("Error: {} running /{}", err, command.data.name);
+                (Reply::Text(err.to_string()), None)
+            }
+        };
+
+        let mut message = reply.interaction_response();
+        if roll.is_some() {
+            message = message.components(buttons::buttons());
+        }
+        let builder = CreateInteractionResponse::Message(message);
+        if let Err(why) = command.create_response(&ctx.http, builder).await {
+            println!("Error responding to /{}: {:?}", command.data.name, why);
+            return;
+        }
+        if let Some(stmt) = roll {
+            let remembered = match command.get_response(&ctx.http).await {
+                Ok(sent) => remember_roll(repl, &sent.id.to_string(), &stmt).await,
+                Err(_) => false,
+            };
+            if !remembered {
+                let remove_buttons = EditInteractionResponse::new().components(vec![]);
+                if let Err(why) = command.edit_response(&ctx.http, remove_buttons).await {
+                    println!("Error removing buttons: {:?}", why);
+                }
+            }
+        }
+    }
+
+    // Rolls the statement behind a result message's buttons again, for whoever
+    // pressed them, and posts the new result with buttons of its own.
+    async fn press_button(&self, ctx: &Context, component: &ComponentInteraction) {
+        let embeds = self.options.embeds;
+        let repl = &mut fork::<E>(ctx).await;
+        let repl_ctx = &self
+            .user_context(
+                repl,
+                component.channel_id,
+                component.guild_id,
+                &component.user,
+            )
+            .await;
+        let config = repl_ctx.config();
+        let message_id = component.message.id.to_string();
+        let response = if config.allows_channel(component.channel_id.get()) {
+            buttons::press(repl, repl_ctx, &message_id, &component.data.custom_id).await
+        } else {
+            Err(not_allowed_here())
+        };
+
+        let display_name = member_name(component.member.as_ref(), &component.user);
+        let message = match &response {
+            Ok(output) => EmbedRenderer::new(display_name, embeds)
+                .with_breakdown(config.breakdown)
+                .render(output)
+                .interaction_response()
+                .components(buttons::buttons()),
+            Err(err) => {
+                println!("Error: {} pressing {}", err, component.data.custom_id);
+                Reply::Text(err.to_string())
+                    .interaction_response()
+                    .ephemeral(true)
+            }
+        };
+        let builder = CreateInteractionResponse::Message(message);
+        if let Err(why) = component.create_response(&ctx.http, builder).await {
+            println!(
+                "Error responding to {}: {:?}",
+                component.data.custom_id, why
+            );
+            return;
+        }
+        if response.is_err() {
+            return;
+        }
+
+        // The new message repeats the same roll.
+        let remembered = match (
+            component.get_response(&ctx.http).await,
+            repl.pending_roll(&message_id).await,
+        ) {
+            (Ok(sent), Ok(Some(stmt))) => remember_roll(repl, &sent.id.to_string(), &stmt).await,
+            _ => false,
+        };
+        if !remembered {
+            let remove_buttons = EditInteractionResponse::new().components(vec![]);
+            if let Err(why) = component.edit_response(&ctx.http, remove_buttons).await {
+                println!("Error removing buttons: {:?}", why);
+            }
+        }
+    }
+}
+
+async fn autocomplete<E: SharedEnvironment + 'static>(ctx: &Context, command: &CommandInteraction) {
+    let Some(option) = command.data.autocomplete() else {
+        return;
+    };
+    let names = fork::<E>(ctx)
+        .await
+        .variable_names(&command_context(command))
+        .await
+        .unwrap_or_default();
+
+    let choices = slash_commands::complete(option.name, option.value, &names)
+        .into_iter()
+        .fold(CreateAutocompleteResponse::new(), |response, choice| {
+            response.add_string_choice(choice.clone(), choice)
+        });
+    let builder = CreateInteractionResponse::Autocomplete(choices);
+    if let Err(why) = command.create_response(&ctx.http, builder).await {
+        println!("Error sending completions: {:?}", why);
+    }
+}
+
+// Slash commands work without the privileged MESSAGE_CONTENT intent; it's only
+// requested when `!` message commands are enabled.
+pub async fn start<E: SharedEnvironment + 'static>(token: &str, repl: REPL<E>, options: Options) {
+    let mut intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::DIRECT_MESSAGES;
+    if options.message_commands {
+        intents |= GatewayIntents::MESSAGE_CONTENT;
+    }
+
+    let mut client = Client::builder(token, intents)
+        .event_handler(Handler::<E>::new(options))
+        .type_map_insert::<REPL<E>>(repl)
+        .await
+        .expect("Err creating client");
+
+    if let Err(why) = client.start().await {
+        println!("Client error: {:?}", why);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::environments::sqlite_environment::SqliteEnvironment;
+    use crate::repl::HOME_SCOPE;
+    use crate::sqlite::SqliteClient;
+
+    const MESSAGES: u64 = 400;
+    const CHANNELS: u64 = 8;
+    const USERS: u64 = 25;
+
+    fn message(channel: u64, user: u64, content: &str) -> Message {
+        let mut msg = Message::default();
+        msg.channel_id = ChannelId::new(channel);
+        msg.author.id = UserId::new(user);
+        msg.author.name = format!("user{}", user);
+        msg.content = content.to_string();
+        msg
+    }
+
+    // The reply to !edit mustn't read as a command if the bot sees it.
+    #[tokio::test]
+    async fn test_edit_reply() {
+        let mut repl = REPL::new_sqlite(SqliteClient::open_in_memory().unwrap());
+        let handler = Handler::<SqliteEnvironment>::new(Options {
+            message_commands: true,
+            embeds: false,
+        });
+        let set = message(1, 1, "!set bonus 2");
+        let stmt = repl.parse(&set.content);
+        handler
+            .respond(&mut repl, &set, stmt, false)
+            .await
+            .0
+            .unwrap();
+        let edit = message(1, 1, "!edit bonus");
+        let stmt = repl.parse(&edit.content);
+        match handler.respond(&mut repl, &edit, stmt, false).await.0 {
+            Ok(Reply::Text(text)) => assert_eq!(text, "`!set@1 bonus 2`"),
+            _ => panic!("expected the set command"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_migrate_usernames() {
+        let client = SqliteClient::open_in_memory().unwrap();
+        let mut repl = REPL::new_sqlite(client.clone());
+        let ann = &REPLContext::new("7".to_string(), "ann".to_string());
+        let bo = &REPLContext::new("7".to_string(), "bo".to_string());
+        repl.exec(ann, "!set bonus 2").await.unwrap();
+        repl.exec(&ann.scope_context(HOME_SCOPE), "!set str 3")
+            .await
+            .unwrap();
+        repl.exec(bo, "!set bonus 5").await.unwrap();
+
+        let user_ids = read_user_ids("ann,1\n\n cy , 3\n").unwrap();
+        let report = migrate_usernames(&mut repl, &client.scopes().unwrap(), &user_ids).await;
+        assert_eq!((report.scanned, report.migrated), (3, 2));
+        assert_eq!(
+            report.failures,
+            vec!["no id for bo in scope:7#scope_type:user#user:bo"]
+        );
+
+        let user = User::default();
+        let mut ann_now = user.clone();
+        ann_now.id = UserId::new(1);
+        let ann_now = &user_context(ChannelId::new(7), None, &ann_now);
+        assert_eq!(
+            repl.exec(ann_now, "!roll {bonus} + {str}").await.unwrap(),
+            "5"
+        );
+        assert_eq!(repl.exec(bo, "!roll {bonus}").await.unwrap(), "5");
+        assert!(read_user_ids("ann").is_err());
+    }
+
+    // Simulates a busy bot: every task sets a bonus in its own scope and rolls
+    // with it, all at once on a shared database. Every roll lands in its
+    // channel's shared log, so none may be lost to a concurrent one.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_messages() {
+        let repl = REPL::new_sqlite(SqliteClient::open_in_memory().unwrap());
+        let handler = Arc::new(Handler::<SqliteEnvironment>::new(Options {
+            message_commands: true,
+            embeds: false,
+        }));
+
+        let tasks: Vec<_> = (0..MESSAGES)
+            .map(|i| {
+                let (mut repl, handler) = (repl.fork(), handler.clone());
+                tokio::spawn(async move {
+                    let (channel, user) = (1 + i % CHANNELS, 1 + i % USERS);
+                    let set = message(channel, user, &format!("!set bonus {}", user * 100));
+                    let stmt = repl.parse(&set.content);
+                    handler
+                        .respond(&mut repl, &set, stmt, false)
+                        .await
+                        .0
+                        .unwrap();
+                    let roll = message(channel, user, "!roll 1d20 + {bonus}");
+                    let stmt = repl.parse(&roll.content);
+                    match handler.respond(&mut repl, &roll, stmt, false).await {
+                        (Ok(Reply::Text(text)), Some(_)) => (user, text),
+                        _ => panic!("expected a roll"),
+                    }
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            let (user, text) = task.await.unwrap();
+            let total: i64 = text
+                .rsplit("= **")
+                .next()
+                .and_then(|total| total.split("**").next())
+                .and_then(|total| total.parse().ok())
+                .unwrap();
+            assert!((1..=20).contains(&(total - user as i64 * 100)), "{}", text);
+        }
+
+        let mut repl = repl.fork();
+        for channel in 1..=CHANNELS {
+            let luck = message(channel, 1, "!luck channel");
+            let stmt = repl.parse(&luck.content);
+            let Ok(Reply::Text(text)) = handler.respond(&mut repl, &luck, stmt, false).await.0
+            else {
+                panic!("expected stats");
+            };
+            let rolls = MESSAGES / CHANNELS;
+            assert!(
+                text.starts_with(&format!(
+                    "rolls here, out of {} logged: {} rolls",
+                    rolls, rolls
+                )),
+                "{}",
+                text
+            );
+        }
     }
 }
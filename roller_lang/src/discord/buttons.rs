@@ -0,0 +1,116 @@
+use serenity::all::{ButtonStyle, CreateActionRow, CreateButton};
+
+use crate::error::RollerError;
+use crate::repl::{REPLContext, REPL};
+use crate::types::{Environment, Output, RollResult, Statement};
+
+pub const REROLL: &str = "reroll";
+pub const ADVANTAGE: &str = "advantage";
+
+// The buttons attached to every roll result.
+pub fn buttons() -> Vec<CreateActionRow> {
+    vec![CreateActionRow::Buttons(vec![
+        CreateButton::new(REROLL)
+            .label("Reroll")
+            .style(ButtonStyle::Primary),
+        CreateButton::new(ADVANTAGE)
+            .label("Roll with advantage")
+            .style(ButtonStyle::Secondary),
+    ])]
+}
+
+// Keeps the higher of two rolls of the same expression.
+pub fn advantage(first: RollResult, second: RollResult) -> RollResult {
+    let (kept, dropped) = if second.total > first.total {
+        (second, first)
+    } else {
+        (first, second)
+    };
+    RollResult {
+        expression: format!(
+            "{} with advantage ({} dropped)",
+            kept.expression, dropped.total
+        ),
+        ..kept
+    }
+}
+
+// Repeats the roll remembered for message_id, as asked for by the button with
+// the given custom id, for the user in repl_ctx.
+pub async fn press<E: Environment + Sync>(
+    repl: &mut REPL<E>,
+    repl_ctx: &REPLContext,
+    message_id: &str,
+    button: &str,
+) -> Result<Output, RollerError> {
+    let stmt = repl.pending_roll(message_id).await?.ok_or_else(|| {
+        RollerError::EvalError("this roll can't be repeated any more".to_string())
+    })?;
+    match button {
+        REROLL => repl.exec_statement(repl_ctx, &stmt).await,
+        ADVANTAGE => roll_with_advantage(repl, repl_ctx, &stmt).await,
+        button => Err(RollerError::EvalError(format!("unknown button {}", button))),
+    }
+}
+
+// Rolls stmt twice and keeps the higher roll, which is the only one logged.
+async fn roll_with_advantage<E: Environment + Sync>(
+    repl: &mut REPL<E>,
+    repl_ctx: &REPLContext,
+    stmt: &Statement,
+) -> Result<Output, RollerError> {
+    let (first, first_seed) = repl.exec_unlogged(repl_ctx, stmt).await?;
+    let (second, second_seed) = repl.exec_unlogged(repl_ctx, stmt).await?;
+    match (first, second) {
+        (Output::Roll(first), Output::Roll(second)) => {
+            let seed = if second.total > first.total {
+                second_seed
+            } else {
+                first_seed
+            };
+            let kept = advantage(first, second);
+            repl.log_roll(repl_ctx, &kept, seed).await?;
+            Ok(Output::Roll(kept))
+        }
+        (first, _) => Ok(first),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::DieRoll;
+
+    fn d20(result: i64) -> RollResult {
+        RollResult {
+            expression: "1d20".to_string(),
+            dice: vec![DieRoll {
+                sides: 20,
+                results: vec![result],
+            }],
+            total: result,
+        }
+    }
+
+    #[test]
+    fn test_advantage() {
+        let kept = advantage(d20(4), d20(17));
+        assert_eq!(kept.total, 17);
+        assert_eq!(kept.dice, d20(17).dice);
+        assert_eq!(kept.expression, "1d20 with advantage (4 dropped)");
+        assert_eq!(advantage(d20(9), d20(9)).total, 9);
+    }
+
+    #[tokio::test]
+    async fn test_roll_with_advantage_logs_kept_roll() {
+        let mut repl = REPL::default();
+        let ctx = &REPLContext::new("table".to_string(), "1".to_string());
+        let stmt = repl.parse("!roll 1d20").unwrap();
+        let Output::Roll(kept) = roll_with_advantage(&mut repl, ctx, &stmt).await.unwrap() else {
+            panic!("expected a roll");
+        };
+        let log = repl.exec(ctx, "!log").await.unwrap();
+        assert_eq!(log.lines().count(), 1, "{}", log);
+        assert!(log.contains(&kept.expression), "{}", log);
+    }
+}
@@ -0,0 +1,130 @@
+use serenity::all::{Colour, CreateEmbed, CreateEmbedAuthor, CreateEmbedFooter};
+
+use super::Reply;
+use crate::render::{breakdown, Renderer};
+use crate::types::{Critical, Output, RollResult};
+
+// Discord rejects embed fields over 1024 characters and messages over 2000;
+// the plain text breakdown leaves room for the lines around it.
+const MAX_FIELD_LEN: usize = 1024;
+const MAX_TEXT_BREAKDOWN_LEN: usize = 1500;
+
+// Renders rolls as embeds credited to the person who rolled, or as plain text
+// when embeds are turned off.
+pub struct EmbedRenderer {
+    display_name: String,
+    embeds: bool,
+    breakdown: bool,
+}
+
+impl EmbedRenderer {
+    pub fn new(display_name: String, embeds: bool) -> Self {
+        EmbedRenderer {
+            display_name,
+            embeds,
+            breakdown: true,
+        }
+    }
+
+    // Whether to list each die rolled as well as the total.
+    pub fn with_breakdown(mut self, breakdown: bool) -> Self {
+        self.breakdown = breakdown;
+        self
+    }
+
+    fn embed(&self, roll: &RollResult) -> CreateEmbed {
+        let mut embed = CreateEmbed::new()
+            .author(CreateEmbedAuthor::new(&self.display_name))
+            .title(&roll.expression)
+            .description(format!("**{}**", roll.total))
+            .colour(colour(roll.critical()));
+        if self.breakdown && !roll.dice.is_empty() {
+            embed = embed.field("Dice", breakdown(roll, MAX_FIELD_LEN), false);
+        }
+        if let Some(critical) = roll.critical() {
+            embed = embed.footer(CreateEmbedFooter::new(critical_text(critical)));
+        }
+        embed
+    }
+
+    fn text(&self, roll: &RollResult) -> String {
+        let mut text = format!("**{}** rolled `{}`\n", self.display_name, roll.expression);
+        if self.breakdown && !roll.dice.is_empty() {
+            text.push_str(&breakdown(roll, MAX_TEXT_BREAKDOWN_LEN));
+            text.push('\n');
+        }
+        text.push_str(&format!("= **{}**", roll.total));
+        if let Some(critical) = roll.critical() {
+            text.push_str(&format!(" ({})", critical_text(critical)));
+        }
+        text
+    }
+}
+
+impl Renderer for EmbedRenderer {
+    type Rendered = Reply;
+
+    fn render(&self, output: &Output) -> Reply {
+        match output {
+            Output::Roll(roll) if self.embeds => Reply::Embed(Box::new(self.embed(roll))),
+            Output::Roll(roll) => Reply::Text(self.text(roll)),
+            Output::SecretRoll(roll, recipients) => Reply::Secret {
+                notice: format!("{} rolled secretly", self.display_name),
+                reply: Box::new(self.render(&Output::Roll(roll.clone()))),
+                recipients: recipients.clone(),
+            },
+            Output::Text(text) => Reply::Text(text.clone()),
+        }
+    }
+}
+
+fn colour(critical: Option<Critical>) -> Colour {
+    match critical {
+        Some(Critical::Success) => Colour::DARK_GREEN,
+        Some(Critical::Failure) => Colour::RED,
+        None => Colour::BLURPLE,
+    }
+}
+
+fn critical_text(critical: Critical) -> &'static str {
+    match critical {
+        Critical::Success => "critical hit!",
+        Critical::Failure => "fumble!",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::DieRoll;
+
+    #[test]
+    fn test_plain_text_fallback() {
+        let roll = Output::Roll(RollResult {
+            expression: "1d20 + 5".to_string(),
+            dice: vec![DieRoll {
+                sides: 20,
+                results: vec![1],
+            }],
+            total: 6,
+        });
+        match EmbedRenderer::new("Ann".to_string(), false).render(&roll) {
+            Reply::Text(text) => assert_eq!(
+                text,
+                "**Ann** rolled `1d20 + 5`\n1d20 [1]\n= **6** (fumble!)"
+            ),
+            _ => panic!("expected a plain text reply"),
+        }
+        match EmbedRenderer::new("Ann".to_string(), false)
+            .with_breakdown(false)
+            .render(&roll)
+        {
+            Reply::Text(text) => assert_eq!(text, "**Ann** rolled `1d20 + 5`\n= **6** (fumble!)"),
+            _ => panic!("expected a plain text reply"),
+        }
+        assert!(matches!(
+            EmbedRenderer::new("Ann".to_string(), true).render(&roll),
+            Reply::Embed(_)
+        ));
+    }
+}
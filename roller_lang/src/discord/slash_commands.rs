@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use serenity::all::{
+    CommandDataOptionValue, CommandInteraction, CommandOptionType, CreateCommand,
+    CreateCommandOption,
+};
+
+use crate::error::RollerError;
+use crate::types::HelpTopic;
+
+// Discord shows at most 25 autocomplete choices.
+const MAX_CHOICES: usize = 25;
+
+pub fn commands() -> Vec<CreateCommand> {
+    vec![
+        CreateCommand::new("roll")
+            .description("Evaluate a dice expression")
+            .add_option(expression_option("The dice to roll, e.g. 2d6 + {bonus}")),
+        CreateCommand::new("set")
+            .description("Save the value of a dice expression to a variable")
+            .add_option(
+                CreateCommandOption::new(CommandOptionType::String, "name", "Variable to set")
+                    .required(true)
+                    .set_autocomplete(true),
+            )
+            .add_option(expression_option("The value to save")),
+        CreateCommand::new("vars").description("Show your saved variables"),
+        CreateCommand::new("help")
+            .description("Explain the bot's commands")
+            .add_option(HelpTopic::ALL.iter().fold(
+                CreateCommandOption::new(CommandOptionType::String, "topic", "What to explain"),
+                |option, topic| option.add_string_choice(topic.name(), topic.name()),
+            )),
+    ]
+}
+
+fn expression_option(description: &str) -> CreateCommandOption {
+    CreateCommandOption::new(CommandOptionType::String, "expression", description)
+        .required(true)
+        .set_autocomplete(true)
+}
+
+// The `!` command a slash command stands for, so both go through the same
+// parser and evaluator.
+pub fn statement_text(command: &CommandInteraction) -> Result<String, RollerError> {
+    let options: HashMap<&str, &str> = command
+        .data
+        .options
+        .iter()
+        .filter_map(|option| match &option.value {
+            CommandDataOptionValue::String(value) => Some((option.name.as_str(), value.as_str())),
+            _ => None,
+        })
+        .collect();
+    let option = |name: &str| {
+        options.get(name).copied().ok_or_else(|| {
+            RollerError::ParserError(format!("/{} needs {}", command.data.name, name))
+        })
+    };
+
+    match command.data.name.as_str() {
+        "roll" => Ok(format!("!roll {}", option("expression")?)),
+        "set" => Ok(format!(
+            "!set {} {}",
+            option("name")?,
+            option("expression")?
+        )),
+        "vars" => Ok("!print-env".to_string()),
+        "help" => match options.get("topic") {
+            Some(topic) => Ok(format!("!help {}", topic)),
+            None => Ok("!help".to_string()),
+        },
+        name => Err(RollerError::ParserError(format!(
+            "unknown command /{}",
+            name
+        ))),
+    }
+}
+
+// Suggestions for the option being typed: variable names for a name option,
+// and for an expression, the expression with a trailing `{partial` completed.
+pub fn complete(option: &str, partial: &str, names: &[String]) -> Vec<String> {
+    if option == "name" {
+        return names
+            .iter()
+            .filter(|name| name.starts_with(partial))
+            .take(MAX_CHOICES)
+            .cloned()
+            .collect();
+    }
+
+    let Some((head, var_prefix)) = partial.rsplit_once('{') else {
+        return vec![];
+    };
+    if var_prefix.contains('}') {
+        return vec![];
+    }
+    names
+        .iter()
+        .filter(|name| name.starts_with(var_prefix))
+        .take(MAX_CHOICES)
+        .map(|name| format!("{}{{{}}}", head, name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete() {
+        let names = vec![
+            "attack".to_string(),
+            "athletics".to_string(),
+            "bonus".to_string(),
+        ];
+        assert_eq!(complete("name", "at", &names), vec!["attack", "athletics"]);
+        assert_eq!(
+            complete("expression", "1d20 + {att", &names),
+            vec!["1d20 + {attack}"]
+        );
+        assert_eq!(complete("expression", "{", &names).len(), 3);
+        assert!(complete("expression", "1d20 + {bonus}", &names).is_empty());
+        assert!(complete("expression", "1d20", &names).is_empty());
+    }
+}
@@ -1,14 +1,55 @@
-use crate::types::Expression;
+use crate::error::RollerError;
+use crate::roll_log::LoggedRoll;
+use crate::schema::{self, MigrationReport, CURRENT_VERSION};
+use crate::types::{Expression, VariableVersion};
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::BehaviorVersion;
-use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::error::{BuildError, DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_dynamodb::operation::put_item::PutItemError;
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_dynamodb::types::{AttributeValue, Delete, Put, TransactWriteItem};
 use aws_sdk_dynamodb::{Client, Error};
+use rand::Rng;
 use serde_dynamo::aws_sdk_dynamodb_1::{from_item, to_item};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-const LOCALSTACK_ENDPOINT: &str = "http://localhost:4566/";
+pub const LOCALSTACK_ENDPOINT: &str = "http://localhost:4566/";
 const DEFAULT_TABLE_NAME: &str = "dice-roller-bot";
 
+const SCHEMA_VERSION_ATTRIBUTE: &str = "schema_version";
+const SOURCE_ATTRIBUTE: &str = "source";
+// Attributes written by serializing an Expression.
+const EXPRESSION_ATTRIBUTES: &[&str] = &["expression_type", "expression"];
+
+// Number of versions of each variable kept in its history.
+const HISTORY_LENGTH: u64 = 10;
+
+const STATE_ATTRIBUTE: &str = "state";
+
+const PENDING_ROLL_SK: &str = "pending_roll";
+const STATEMENT_ATTRIBUTE: &str = "statement";
+// Pending rolls are tagged with an expiry time, so a TTL on this attribute can
+// clean up the buttons nobody presses.
+const EXPIRES_AT_ATTRIBUTE: &str = "expires_at";
+const PENDING_ROLL_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Logged rolls are items of their own, sorted by when they were rolled and
+// expired by the same TTL.
+const ROLL_PREFIX: &str = "roll#";
+const ROLL_ATTRIBUTE: &str = "roll";
+
+const MAX_ATTEMPTS: u32 = 5;
+const BASE_BACKOFF_MS: u64 = 25;
+const THROTTLING_ERROR_CODES: &[&str] = &[
+    "ProvisionedThroughputExceededException",
+    "RequestLimitExceeded",
+    "ThrottlingException",
+];
+
 #[derive(Debug, Clone)]
 pub struct DDBClient {
     pub client: Client,
@@ -24,8 +65,14 @@ impl DDBClient {
         DDBClient::new(client, DEFAULT_TABLE_NAME.to_string())
     }
 
-    pub async fn get_expression(&self, pk: &str, sk: &str) -> Result<Expression, ()> {
-        match self
+    // The expression at (pk, sk) upgraded to the current schema, or None if there
+    // is no such item.
+    pub async fn get_expression(
+        &self,
+        pk: &str,
+        sk: &str,
+    ) -> Result<Option<Expression>, RollerError> {
+        let res = self
             .client
             .get_item()
             .table_name(&self.table_name)
@@ -33,67 +80,1120 @@ impl DDBClient {
             .key("sk", AttributeValue::S(sk.to_string()))
             .send()
             .await
-        {
-            Ok(res) => match from_item(res.item().ok_or(())?.clone()) {
-                Ok(expr) => Ok(expr),
-                Err(_) => Err(()),
-            },
-            Err(_) => Err(()),
+            .map_err(|err| {
+                RollerError::StorageError(format!(
+                    "failed to read variable: {}",
+                    DisplayErrorContext(&err)
+                ))
+            })?;
+
+        res.item().map(expression_from_item).transpose()
+    }
+
+    // The source text the item at (pk, sk) was saved with, if any.
+    pub async fn get_source(&self, pk: &str, sk: &str) -> Result<Option<String>, RollerError> {
+        let res = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("pk", AttributeValue::S(pk.to_string()))
+            .key("sk", AttributeValue::S(sk.to_string()))
+            .projection_expression("#source")
+            .expression_attribute_names("#source", SOURCE_ATTRIBUTE)
+            .send()
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!(
+                    "failed to read variable: {}",
+                    DisplayErrorContext(&err)
+                ))
+            })?;
+
+        Ok(res.item().and_then(source))
+    }
+
+    // Saves expr, and the source text it was parsed from if there is any, as the
+    // next version of the item at (pk, sk), and records it in the item's history.
+    pub async fn set_expression(
+        &self,
+        pk: &str,
+        sk: &str,
+        expr: &Expression,
+        source: Option<&str>,
+    ) -> Result<u64, RollerError> {
+        let current_version = self.get_version(pk, sk).await?;
+        self.put_expression_version(pk, sk, expr, source, current_version)
+            .await
+    }
+
+    // Writes expr as version expected_version + 1, failing if someone else has
+    // written the item since expected_version was read. The current item and its
+    // history entry are written in one transaction, which also drops the history
+    // entry that falls out of the retained window.
+    pub async fn put_expression_version(
+        &self,
+        pk: &str,
+        sk: &str,
+        expr: &Expression,
+        source: Option<&str>,
+        expected_version: u64,
+    ) -> Result<u64, RollerError> {
+        let version = expected_version + 1;
+        let mut item = item_from_expression(expr)?;
+        if let Some(source) = source {
+            item.insert(
+                SOURCE_ATTRIBUTE.to_string(),
+                AttributeValue::S(source.to_string()),
+            );
         }
+        item.insert("pk".to_string(), AttributeValue::S(pk.to_string()));
+        item.insert(
+            "version".to_string(),
+            AttributeValue::N(version.to_string()),
+        );
+
+        let mut current = item.clone();
+        current.insert("sk".to_string(), AttributeValue::S(sk.to_string()));
+        let mut put_current = Put::builder()
+            .table_name(&self.table_name)
+            .set_item(Some(current))
+            .expression_attribute_names("#version", "version");
+        // Items written before versioning have no version attribute and count as
+        // version 0.
+        put_current = if expected_version == 0 {
+            put_current.condition_expression("attribute_not_exists(#version)")
+        } else {
+            put_current
+                .condition_expression("#version = :expected_version")
+                .expression_attribute_values(
+                    ":expected_version",
+                    AttributeValue::N(expected_version.to_string()),
+                )
+        };
+
+        let mut history = item;
+        history.insert(
+            "sk".to_string(),
+            AttributeValue::S(history_key(sk, version)),
+        );
+        let put_history = Put::builder()
+            .table_name(&self.table_name)
+            .set_item(Some(history));
+
+        let mut transact_items = vec![
+            TransactWriteItem::builder()
+                .put(put_current.build().map_err(build_error)?)
+                .build(),
+            TransactWriteItem::builder()
+                .put(put_history.build().map_err(build_error)?)
+                .build(),
+        ];
+        if version > HISTORY_LENGTH {
+            let expired = Delete::builder()
+                .table_name(&self.table_name)
+                .key("pk", AttributeValue::S(pk.to_string()))
+                .key(
+                    "sk",
+                    AttributeValue::S(history_key(sk, version - HISTORY_LENGTH)),
+                );
+            transact_items.push(
+                TransactWriteItem::builder()
+                    .delete(expired.build().map_err(build_error)?)
+                    .build(),
+            );
+        }
+
+        retry_throttled(|| {
+            self.client
+                .transact_write_items()
+                .set_transact_items(Some(transact_items.clone()))
+                .send()
+        })
+        .await
+        .map_err(|err| match err.as_service_error() {
+            Some(TransactWriteItemsError::TransactionCanceledException(cancelled))
+                if cancelled
+                    .cancellation_reasons()
+                    .iter()
+                    .any(|reason| reason.code() == Some("ConditionalCheckFailed")) =>
+            {
+                RollerError::ConflictError(
+                    "variable was changed by someone else, please try again".to_string(),
+                )
+            }
+            _ => RollerError::StorageError(format!(
+                "failed to save variable: {}",
+                DisplayErrorContext(&err)
+            )),
+        })?;
+        Ok(version)
+    }
+
+    // The version of the item at (pk, sk), or 0 if it doesn't exist or predates
+    // versioning.
+    pub async fn get_version(&self, pk: &str, sk: &str) -> Result<u64, RollerError> {
+        let res = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("pk", AttributeValue::S(pk.to_string()))
+            .key("sk", AttributeValue::S(sk.to_string()))
+            .projection_expression("#version")
+            .expression_attribute_names("#version", "version")
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!(
+                    "failed to read variable: {}",
+                    DisplayErrorContext(&err)
+                ))
+            })?;
+
+        match res.item().and_then(|item| item.get("version")) {
+            Some(AttributeValue::N(version)) => version.parse().map_err(|_| {
+                RollerError::StorageError(format!("invalid variable version {}", version))
+            }),
+            _ => Ok(0),
+        }
+    }
+
+    // The retained versions of the item at (pk, sk), newest first.
+    pub async fn get_history(
+        &self,
+        pk: &str,
+        sk: &str,
+    ) -> Result<Vec<VariableVersion>, RollerError> {
+        let prefix = history_prefix(sk);
+        let mut history = vec![];
+        let mut exclusive_start_key = None;
+
+        loop {
+            let res = retry_throttled(|| {
+                self.client
+                    .query()
+                    .table_name(&self.table_name)
+                    .key_condition_expression("#pk = :pk AND begins_with(#sk, :sk_prefix)")
+                    .expression_attribute_names("#pk", "pk")
+                    .expression_attribute_names("#sk", "sk")
+                    .expression_attribute_values(":pk", AttributeValue::S(pk.to_string()))
+                    .expression_attribute_values(":sk_prefix", AttributeValue::S(prefix.clone()))
+                    .scan_index_forward(false)
+                    .set_exclusive_start_key(exclusive_start_key.clone())
+                    .send()
+            })
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!(
+                    "failed to read variable history: {}",
+                    DisplayErrorContext(&err)
+                ))
+            })?;
+
+            for item in res.items() {
+                let version = match item.get("sk").and_then(|sk| sk.as_s().ok()) {
+                    // Skip other variables whose names happen to extend this one's prefix.
+                    Some(sk) => match sk[prefix.len()..].parse() {
+                        Ok(version) => version,
+                        Err(_) => continue,
+                    },
+                    None => continue,
+                };
+                let expression = expression_from_item(item)?;
+                history.push(VariableVersion {
+                    version,
+                    expression,
+                    source: source(item),
+                });
+            }
+
+            exclusive_start_key = res.last_evaluated_key().cloned();
+            if exclusive_start_key.is_none() {
+                break;
+            }
+        }
+        Ok(history)
+    }
+
+    // Fetches every item in the partition whose sort key starts with sk_prefix,
+    // following last_evaluated_key across pages. Results are keyed by the sort key
+    // with the prefix removed.
+    pub async fn get_all_in_scope(
+        &self,
+        pk: &str,
+        sk_prefix: &str,
+    ) -> Result<HashMap<String, Expression>, RollerError> {
+        Ok(self
+            .get_all_with_sources_in_scope(pk, sk_prefix)
+            .await?
+            .into_iter()
+            .map(|(name, (expr, _))| (name, expr))
+            .collect())
     }
 
-    pub async fn set_expression(&self, pk: &str, sk: &str, expr: &Expression) {
-        if let Ok(item) = to_item(expr) {
-            let _ = self
+    // Like get_all_in_scope, along with the source text each item was saved with.
+    // An item that can't be read fails the whole listing rather than going
+    // missing from it.
+    pub async fn get_all_with_sources_in_scope(
+        &self,
+        pk: &str,
+        sk_prefix: &str,
+    ) -> Result<HashMap<String, (Expression, Option<String>)>, RollerError> {
+        let mut new_env = HashMap::new();
+        let mut exclusive_start_key = None;
+
+        loop {
+            let res = self
                 .client
-                .put_item()
+                .query()
                 .table_name(&self.table_name)
-                .set_item(Some(item))
-                .item("pk", AttributeValue::S(pk.to_string()))
-                .item("sk", AttributeValue::S(sk.to_string()))
+                .key_condition_expression("#pk = :pk AND begins_with(#sk, :sk_prefix)")
+                .projection_expression(
+                    "#sk, #schema_version, #expression_type, #expression, #source",
+                )
+                .expression_attribute_names("#pk", "pk")
+                .expression_attribute_names("#sk", "sk")
+                .expression_attribute_names("#schema_version", SCHEMA_VERSION_ATTRIBUTE)
+                .expression_attribute_names("#expression_type", "expression_type")
+                .expression_attribute_names("#expression", "expression")
+                .expression_attribute_names("#source", SOURCE_ATTRIBUTE)
+                .expression_attribute_values(":pk", AttributeValue::S(pk.to_string()))
+                .expression_attribute_values(":sk_prefix", AttributeValue::S(sk_prefix.to_string()))
+                .set_exclusive_start_key(exclusive_start_key)
                 .send()
-                .await;
+                .await
+                .map_err(|err| {
+                    RollerError::StorageError(format!(
+                        "failed to read variables: {}",
+                        DisplayErrorContext(&err)
+                    ))
+                })?;
+
+            for item in res.items() {
+                let Some(name) = item
+                    .get("sk")
+                    .and_then(|sk| sk.as_s().ok())
+                    .and_then(|sk| sk.strip_prefix(sk_prefix))
+                else {
+                    continue;
+                };
+                let expr = expression_from_item(item).map_err(|err| {
+                    RollerError::StorageError(format!("failed to read {}: {}", name, err))
+                })?;
+                new_env.insert(name.to_string(), (expr, source(item)));
+            }
+
+            exclusive_start_key = res.last_evaluated_key().cloned();
+            if exclusive_start_key.is_none() {
+                break;
+            }
         }
+
+        Ok(new_env)
+    }
+
+    // The JSON state saved under name in the pk partition, if any.
+    pub async fn get_state(&self, pk: &str, name: &str) -> Result<Option<Value>, RollerError> {
+        Ok(self.get_state_version(pk, name).await?.0)
     }
 
-    pub async fn get_all_in_scope(&self, pk: &str) -> Result<HashMap<String, Expression>, ()> {
-        let response = self
+    // Like get_state, along with the version the state was saved as, or 0 if it
+    // hasn't been or predates versioning.
+    pub async fn get_state_version(
+        &self,
+        pk: &str,
+        name: &str,
+    ) -> Result<(Option<Value>, u64), RollerError> {
+        let res = self
             .client
-            .query()
+            .get_item()
             .table_name(&self.table_name)
-            .key_condition_expression("#pk = :pk")
-            .expression_attribute_names("#pk", "pk")
-            .expression_attribute_values(":pk", AttributeValue::S(pk.to_string()))
+            .key("pk", AttributeValue::S(pk.to_string()))
+            .key("sk", AttributeValue::S(state_key(name)))
+            .consistent_read(true)
             .send()
-            .await;
-
-        match response {
-            Ok(res) => {
-                let mut new_env = HashMap::new();
-                for item in res.items() {
-                    match item.get("sk") {
-                        Some(sk) => match from_item(item.clone()) {
-                            Ok(expr) => {
-                                new_env.insert(sk.as_s().unwrap().to_string(), expr);
-                            }
-                            Err(_) => return Err(()),
-                        },
-                        None => return Err(()),
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!(
+                    "failed to read {}: {}",
+                    name,
+                    DisplayErrorContext(&err)
+                ))
+            })?;
+
+        let Some(item) = res.item() else {
+            return Ok((None, 0));
+        };
+        let version = match item.get("version") {
+            Some(AttributeValue::N(version)) => version.parse().map_err(|_| {
+                RollerError::StorageError(format!("invalid {} version {}", name, version))
+            })?,
+            _ => 0,
+        };
+        match item.get(STATE_ATTRIBUTE) {
+            Some(AttributeValue::S(value)) => serde_json::from_str(value)
+                .map(|value| (Some(value), version))
+                .map_err(|err| {
+                    RollerError::StorageError(format!("failed to read {}: {}", name, err))
+                }),
+            _ => Ok((None, version)),
+        }
+    }
+
+    // Saves value over whatever state is saved under name, as its next version.
+    pub async fn put_state(&self, pk: &str, name: &str, value: &Value) -> Result<(), RollerError> {
+        retry_throttled(|| {
+            self.client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("pk", AttributeValue::S(pk.to_string()))
+                .key("sk", AttributeValue::S(state_key(name)))
+                .update_expression("SET #state = :state ADD #version :one")
+                .expression_attribute_names("#state", STATE_ATTRIBUTE)
+                .expression_attribute_names("#version", "version")
+                .expression_attribute_values(":state", AttributeValue::S(value.to_string()))
+                .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+                .send()
+        })
+        .await
+        .map_err(|err| {
+            RollerError::StorageError(format!(
+                "failed to save {}: {}",
+                name,
+                DisplayErrorContext(&err)
+            ))
+        })?;
+        Ok(())
+    }
+
+    // Saves value as version expected_version + 1 of the state, failing if
+    // someone else has saved it since expected_version was read.
+    pub async fn put_state_version(
+        &self,
+        pk: &str,
+        name: &str,
+        value: &Value,
+        expected_version: u64,
+    ) -> Result<(), RollerError> {
+        // State saved before versioning has no version attribute and counts as
+        // version 0, like variables.
+        let condition = if expected_version == 0 {
+            "attribute_not_exists(#version)"
+        } else {
+            "#version = :expected_version"
+        };
+        retry_throttled(|| {
+            let mut update = self
+                .client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("pk", AttributeValue::S(pk.to_string()))
+                .key("sk", AttributeValue::S(state_key(name)))
+                .update_expression("SET #state = :state, #version = :version")
+                .condition_expression(condition)
+                .expression_attribute_names("#state", STATE_ATTRIBUTE)
+                .expression_attribute_names("#version", "version")
+                .expression_attribute_values(":state", AttributeValue::S(value.to_string()))
+                .expression_attribute_values(
+                    ":version",
+                    AttributeValue::N((expected_version + 1).to_string()),
+                );
+            if expected_version > 0 {
+                update = update.expression_attribute_values(
+                    ":expected_version",
+                    AttributeValue::N(expected_version.to_string()),
+                );
+            }
+            update.send()
+        })
+        .await
+        .map_err(|err| match err.as_service_error() {
+            Some(UpdateItemError::ConditionalCheckFailedException(_)) => {
+                RollerError::ConflictError(format!("{} was changed by someone else", name))
+            }
+            _ => RollerError::StorageError(format!(
+                "failed to save {}: {}",
+                name,
+                DisplayErrorContext(&err)
+            )),
+        })?;
+        Ok(())
+    }
+
+    // Moves every item in the from_pk partition, current values, history and
+    // state alike, to to_pk. Items to_pk already has are kept and the old copies
+    // dropped. Returns the number of items moved.
+    pub async fn move_partition(&self, from_pk: &str, to_pk: &str) -> Result<u64, RollerError> {
+        if from_pk == to_pk {
+            return Ok(0);
+        }
+        let mut moved = 0;
+        let mut exclusive_start_key = None;
+
+        loop {
+            let res = retry_throttled(|| {
+                self.client
+                    .query()
+                    .table_name(&self.table_name)
+                    .key_condition_expression("#pk = :pk")
+                    .expression_attribute_names("#pk", "pk")
+                    .expression_attribute_values(":pk", AttributeValue::S(from_pk.to_string()))
+                    .set_exclusive_start_key(exclusive_start_key.clone())
+                    .send()
+            })
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!(
+                    "failed to read {}: {}",
+                    from_pk,
+                    DisplayErrorContext(&err)
+                ))
+            })?;
+
+            for item in res.items() {
+                let Some(sk) = item.get("sk") else {
+                    continue;
+                };
+                let mut moved_item = item.clone();
+                moved_item.insert("pk".to_string(), AttributeValue::S(to_pk.to_string()));
+                let put = retry_throttled(|| {
+                    self.client
+                        .put_item()
+                        .table_name(&self.table_name)
+                        .set_item(Some(moved_item.clone()))
+                        .condition_expression("attribute_not_exists(pk)")
+                        .send()
+                })
+                .await;
+                match put {
+                    Ok(_) => moved += 1,
+                    Err(err)
+                        if matches!(
+                            err.as_service_error(),
+                            Some(PutItemError::ConditionalCheckFailedException(_))
+                        ) => {}
+                    Err(err) => {
+                        return Err(RollerError::StorageError(format!(
+                            "failed to move {:?} to {}: {}",
+                            sk,
+                            to_pk,
+                            DisplayErrorContext(&err)
+                        )))
                     }
                 }
-                Ok(new_env)
+
+                retry_throttled(|| {
+                    self.client
+                        .delete_item()
+                        .table_name(&self.table_name)
+                        .key("pk", AttributeValue::S(from_pk.to_string()))
+                        .key("sk", sk.clone())
+                        .send()
+                })
+                .await
+                .map_err(|err| {
+                    RollerError::StorageError(format!(
+                        "failed to remove {:?} from {}: {}",
+                        sk,
+                        from_pk,
+                        DisplayErrorContext(&err)
+                    ))
+                })?;
+            }
+
+            exclusive_start_key = res.last_evaluated_key().cloned();
+            if exclusive_start_key.is_none() {
+                break;
+            }
+        }
+
+        Ok(moved)
+    }
+
+    // Saves the statement a message's buttons repeat, keyed by the message id.
+    pub async fn put_pending_roll(
+        &self,
+        message_id: &str,
+        statement: &str,
+    ) -> Result<(), RollerError> {
+        let expires_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            + PENDING_ROLL_LIFETIME;
+        retry_throttled(|| {
+            self.client
+                .put_item()
+                .table_name(&self.table_name)
+                .set_item(Some(pending_roll_item(message_id, statement, expires_at)))
+                .send()
+        })
+        .await
+        .map_err(|err| {
+            RollerError::StorageError(format!(
+                "failed to save roll: {}",
+                DisplayErrorContext(&err)
+            ))
+        })?;
+        Ok(())
+    }
+
+    // Adds a roll to the log kept in the pk partition.
+    pub async fn put_roll(&self, pk: &str, roll: &LoggedRoll) -> Result<(), RollerError> {
+        let item = roll_item(pk, roll)?;
+        retry_throttled(|| {
+            self.client
+                .put_item()
+                .table_name(&self.table_name)
+                .set_item(Some(item.clone()))
+                .send()
+        })
+        .await
+        .map_err(|err| {
+            RollerError::StorageError(format!("failed to log roll: {}", DisplayErrorContext(&err)))
+        })?;
+        Ok(())
+    }
+
+    // The rolls logged in the pk partition at or after since, oldest first.
+    // With a limit, only the newest that many.
+    pub async fn get_rolls(
+        &self,
+        pk: &str,
+        since: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<LoggedRoll>, RollerError> {
+        let mut rolls = vec![];
+        let mut exclusive_start_key = None;
+
+        loop {
+            let remaining = limit.map(|limit| limit.saturating_sub(rolls.len()));
+            if remaining == Some(0) {
+                break;
+            }
+            let res = retry_throttled(|| {
+                self.client
+                    .query()
+                    .table_name(&self.table_name)
+                    .key_condition_expression("#pk = :pk AND #sk BETWEEN :from AND :to")
+                    .expression_attribute_names("#pk", "pk")
+                    .expression_attribute_names("#sk", "sk")
+                    .expression_attribute_values(":pk", AttributeValue::S(pk.to_string()))
+                    .expression_attribute_values(
+                        ":from",
+                        AttributeValue::S(format!("{}{:020}#", ROLL_PREFIX, since)),
+                    )
+                    // '~' sorts after every digit, so this takes in every roll.
+                    .expression_attribute_values(
+                        ":to",
+                        AttributeValue::S(format!("{}~", ROLL_PREFIX)),
+                    )
+                    .scan_index_forward(false)
+                    .set_limit(remaining.map(|remaining| remaining.min(i32::MAX as usize) as i32))
+                    .set_exclusive_start_key(exclusive_start_key.clone())
+                    .send()
+            })
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!(
+                    "failed to read roll log: {}",
+                    DisplayErrorContext(&err)
+                ))
+            })?;
+
+            for item in res.items() {
+                rolls.push(roll_from_item(item)?);
+            }
+
+            exclusive_start_key = res.last_evaluated_key().cloned();
+            if exclusive_start_key.is_none() {
+                break;
+            }
+        }
+
+        rolls.reverse();
+        Ok(rolls)
+    }
+
+    // The statement saved for message_id's buttons, if there is one.
+    pub async fn get_pending_roll(&self, message_id: &str) -> Result<Option<String>, RollerError> {
+        let res = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("pk", AttributeValue::S(pending_roll_pk(message_id)))
+            .key("sk", AttributeValue::S(PENDING_ROLL_SK.to_string()))
+            .send()
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!(
+                    "failed to read roll: {}",
+                    DisplayErrorContext(&err)
+                ))
+            })?;
+
+        Ok(res
+            .item()
+            .and_then(|item| item.get(STATEMENT_ATTRIBUTE))
+            .and_then(|statement| statement.as_s().ok())
+            .cloned())
+    }
+
+    // Every partition key in the table, i.e. every scope with something saved
+    // in it.
+    pub async fn partitions(&self) -> Result<Vec<String>, RollerError> {
+        let mut partitions = BTreeSet::new();
+        let mut exclusive_start_key = None;
+
+        loop {
+            let res = retry_throttled(|| {
+                self.client
+                    .scan()
+                    .table_name(&self.table_name)
+                    .projection_expression("#pk")
+                    .expression_attribute_names("#pk", "pk")
+                    .set_exclusive_start_key(exclusive_start_key.clone())
+                    .send()
+            })
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!(
+                    "failed to scan table: {}",
+                    DisplayErrorContext(&err)
+                ))
+            })?;
+
+            for item in res.items() {
+                if let Some(AttributeValue::S(pk)) = item.get("pk") {
+                    partitions.insert(pk.clone());
+                }
+            }
+
+            exclusive_start_key = res.last_evaluated_key().cloned();
+            if exclusive_start_key.is_none() {
+                break;
+            }
+        }
+
+        Ok(partitions.into_iter().collect())
+    }
+
+    // Rewrites every stored expression, including history entries, that was
+    // saved with an older schema in the current layout. Items that fail to
+    // upgrade are left alone and counted, so one bad item doesn't stop the run.
+    pub async fn migrate_all(&self) -> Result<MigrationReport, RollerError> {
+        let mut report = MigrationReport::default();
+        let mut exclusive_start_key = None;
+
+        loop {
+            let res = retry_throttled(|| {
+                self.client
+                    .scan()
+                    .table_name(&self.table_name)
+                    .set_exclusive_start_key(exclusive_start_key.clone())
+                    .send()
+            })
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!(
+                    "failed to scan table: {}",
+                    DisplayErrorContext(&err)
+                ))
+            })?;
+
+            for item in res.items() {
+                if !item.contains_key("expression_type") {
+                    continue;
+                }
+                report.scanned += 1;
+                let schema_version = schema_version(item)?;
+                if schema_version >= CURRENT_VERSION {
+                    continue;
+                }
+                match self.migrate_item(item, schema_version).await {
+                    Ok(()) => report.migrated += 1,
+                    Err(err) => report.failures.push(format!(
+                        "{} in {}: {}",
+                        attribute_string(item, "sk"),
+                        attribute_string(item, "pk"),
+                        err
+                    )),
+                }
+            }
+
+            exclusive_start_key = res.last_evaluated_key().cloned();
+            if exclusive_start_key.is_none() {
+                break;
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn migrate_item(
+        &self,
+        item: &HashMap<String, AttributeValue>,
+        schema_version: u32,
+    ) -> Result<(), RollerError> {
+        let mut migrated = item_from_expression(&expression_from_item(item)?)?;
+        for key in ["pk", "sk", "version", SOURCE_ATTRIBUTE] {
+            if let Some(value) = item.get(key) {
+                migrated.insert(key.to_string(), value.clone());
+            }
+        }
+
+        // Don't overwrite a value saved since the scan; it's already current.
+        let put = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .set_item(Some(migrated))
+            .expression_attribute_names("#schema_version", SCHEMA_VERSION_ATTRIBUTE);
+        let put = if schema_version == 0 {
+            put.condition_expression("attribute_not_exists(#schema_version)")
+        } else {
+            put.condition_expression("#schema_version = :schema_version")
+                .expression_attribute_values(
+                    ":schema_version",
+                    AttributeValue::N(schema_version.to_string()),
+                )
+        };
+
+        match retry_throttled(|| put.clone().send()).await {
+            Ok(_) => Ok(()),
+            Err(err)
+                if matches!(
+                    err.as_service_error(),
+                    Some(PutItemError::ConditionalCheckFailedException(_))
+                ) =>
+            {
+                Ok(())
+            }
+            Err(err) => Err(RollerError::StorageError(format!(
+                "failed to save variable: {}",
+                DisplayErrorContext(&err)
+            ))),
+        }
+    }
+}
+
+fn attribute_string(item: &HashMap<String, AttributeValue>, name: &str) -> String {
+    match item.get(name) {
+        Some(AttributeValue::S(value)) => value.clone(),
+        value => format!("{:?}", value),
+    }
+}
+
+fn source(item: &HashMap<String, AttributeValue>) -> Option<String> {
+    item.get(SOURCE_ATTRIBUTE)?.as_s().ok().cloned()
+}
+
+fn schema_version(item: &HashMap<String, AttributeValue>) -> Result<u32, RollerError> {
+    match item.get(SCHEMA_VERSION_ATTRIBUTE) {
+        Some(AttributeValue::N(version)) => version
+            .parse()
+            .map_err(|_| RollerError::StorageError(format!("invalid schema version {}", version))),
+        _ => Ok(0),
+    }
+}
+
+// Reads the expression attributes of an item, upgrading them from the schema
+// they were saved with.
+fn expression_from_item(item: &HashMap<String, AttributeValue>) -> Result<Expression, RollerError> {
+    let attributes: HashMap<String, AttributeValue> = item
+        .iter()
+        .filter(|(key, _)| EXPRESSION_ATTRIBUTES.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let value = from_item(attributes).map_err(|err| {
+        RollerError::StorageError(format!("failed to read stored variable: {}", err))
+    })?;
+    schema::upgrade(value, schema_version(item)?, source(item).as_deref())
+}
+
+fn item_from_expression(expr: &Expression) -> Result<HashMap<String, AttributeValue>, RollerError> {
+    let mut item: HashMap<String, AttributeValue> = to_item(expr).map_err(|err| {
+        RollerError::StorageError(format!("failed to serialize variable: {}", err))
+    })?;
+    item.insert(
+        SCHEMA_VERSION_ATTRIBUTE.to_string(),
+        AttributeValue::N(CURRENT_VERSION.to_string()),
+    );
+    Ok(item)
+}
+
+fn history_prefix(sk: &str) -> String {
+    format!("history:{}#version:", sk)
+}
+
+// Versions are zero padded so history entries sort in version order.
+fn history_key(sk: &str, version: u64) -> String {
+    format!("{}{:020}", history_prefix(sk), version)
+}
+
+fn state_key(name: &str) -> String {
+    format!("state:{}", name)
+}
+
+fn pending_roll_pk(message_id: &str) -> String {
+    format!("message:{}", message_id)
+}
+
+fn pending_roll_item(
+    message_id: &str,
+    statement: &str,
+    expires_at: Duration,
+) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        (
+            "pk".to_string(),
+            AttributeValue::S(pending_roll_pk(message_id)),
+        ),
+        (
+            "sk".to_string(),
+            AttributeValue::S(PENDING_ROLL_SK.to_string()),
+        ),
+        (
+            STATEMENT_ATTRIBUTE.to_string(),
+            AttributeValue::S(statement.to_string()),
+        ),
+        (
+            EXPIRES_AT_ATTRIBUTE.to_string(),
+            AttributeValue::N(expires_at.as_secs().to_string()),
+        ),
+    ])
+}
+
+// Timestamps are zero padded so rolls sort in the order they were made. The
+// seed, or a random number for unseeded rolls, keeps rolls made in the same
+// second apart.
+fn roll_key(roll: &LoggedRoll) -> String {
+    let seed = roll.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    format!("{}{:020}#{:020}", ROLL_PREFIX, roll.timestamp, seed)
+}
+
+fn roll_item(pk: &str, roll: &LoggedRoll) -> Result<HashMap<String, AttributeValue>, RollerError> {
+    let json = serde_json::to_string(roll)
+        .map_err(|err| RollerError::StorageError(format!("invalid roll: {}", err)))?;
+    Ok(HashMap::from([
+        ("pk".to_string(), AttributeValue::S(pk.to_string())),
+        ("sk".to_string(), AttributeValue::S(roll_key(roll))),
+        (ROLL_ATTRIBUTE.to_string(), AttributeValue::S(json)),
+        (
+            EXPIRES_AT_ATTRIBUTE.to_string(),
+            AttributeValue::N(roll.expires_at().to_string()),
+        ),
+    ]))
+}
+
+fn roll_from_item(item: &HashMap<String, AttributeValue>) -> Result<LoggedRoll, RollerError> {
+    let json = item
+        .get(ROLL_ATTRIBUTE)
+        .and_then(|roll| roll.as_s().ok())
+        .ok_or_else(|| RollerError::StorageError("logged roll has no roll".to_string()))?;
+    serde_json::from_str(json)
+        .map_err(|err| RollerError::StorageError(format!("invalid logged roll: {}", err)))
+}
+
+fn build_error(err: BuildError) -> RollerError {
+    RollerError::StorageError(format!("failed to build request: {}", err))
+}
+
+fn is_throttling<E: ProvideErrorMetadata, R>(err: &SdkError<E, R>) -> bool {
+    err.code()
+        .is_some_and(|code| THROTTLING_ERROR_CODES.contains(&code))
+}
+
+// Exponential backoff with full jitter, so throttled writers don't retry in step.
+pub(crate) fn backoff(attempt: u32) -> Duration {
+    let max_delay = BASE_BACKOFF_MS << attempt;
+    Duration::from_millis(rand::thread_rng().gen_range(0..=max_delay))
+}
+
+async fn retry_throttled<T, E, R, F, Fut>(mut operation: F) -> Result<T, SdkError<E, R>>
+where
+    E: ProvideErrorMetadata,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E, R>>>,
+{
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Err(err) if is_throttling(&err) && attempt + 1 < MAX_ATTEMPTS => {
+                tokio::time::sleep(backoff(attempt)).await;
+                attempt += 1;
             }
-            Err(_) => Err(()),
+            result => return result,
         }
     }
 }
 
-pub async fn make_client(use_localstack: bool) -> Result<Client, Error> {
+// A client for endpoint, e.g. LOCALSTACK_ENDPOINT, or for AWS's own endpoint in
+// the configured region if it's None.
+pub async fn make_client(endpoint: Option<&str>) -> Result<Client, Error> {
     let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
     let mut config = aws_config::defaults(BehaviorVersion::latest()).region(region_provider);
-    if use_localstack {
-        config = config.endpoint_url(LOCALSTACK_ENDPOINT);
+    if let Some(endpoint) = endpoint {
+        config = config.endpoint_url(endpoint);
     }
     let config = config.load().await;
     Ok(Client::new(&config))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::error::ErrorMetadata;
+    use aws_sdk_dynamodb::types::error::{
+        ProvisionedThroughputExceededException, ResourceNotFoundException,
+    };
+
+    fn throttled() -> SdkError<PutItemError, ()> {
+        SdkError::service_error(
+            PutItemError::ProvisionedThroughputExceededException(
+                ProvisionedThroughputExceededException::builder()
+                    .meta(
+                        ErrorMetadata::builder()
+                            .code("ProvisionedThroughputExceededException")
+                            .build(),
+                    )
+                    .build(),
+            ),
+            (),
+        )
+    }
+
+    fn not_found() -> SdkError<PutItemError, ()> {
+        SdkError::service_error(
+            PutItemError::ResourceNotFoundException(
+                ResourceNotFoundException::builder()
+                    .meta(
+                        ErrorMetadata::builder()
+                            .code("ResourceNotFoundException")
+                            .build(),
+                    )
+                    .build(),
+            ),
+            (),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_retry_throttled() {
+        let mut attempts = 0;
+        let result = retry_throttled(|| {
+            attempts += 1;
+            let result = if attempts < 3 {
+                Err(throttled())
+            } else {
+                Ok(attempts)
+            };
+            async move { result }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_throttled(|| {
+            attempts += 1;
+            async { Err(throttled()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, MAX_ATTEMPTS);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_throttled(|| {
+            attempts += 1;
+            async { Err(not_found()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_pending_roll_item() {
+        let item = pending_roll_item("123", "!roll 1d20", Duration::from_secs(60));
+        assert_eq!(item["pk"], AttributeValue::S("message:123".to_string()));
+        assert_eq!(
+            item[STATEMENT_ATTRIBUTE],
+            AttributeValue::S("!roll 1d20".to_string())
+        );
+        assert_eq!(
+            item[EXPIRES_AT_ATTRIBUTE],
+            AttributeValue::N("60".to_string())
+        );
+        // Migrations only touch items holding an expression.
+        assert!(!item.contains_key("expression_type"));
+    }
+
+    #[test]
+    fn test_roll_item() {
+        let roll = LoggedRoll {
+            user: "1".to_string(),
+            channel: "channel".to_string(),
+            expression: "d20".to_string(),
+            dice: vec![],
+            total: 12,
+            timestamp: 1_000,
+            seed: Some(42),
+        };
+        let item = roll_item("scope:channel", &roll).unwrap();
+        assert_eq!(
+            item["sk"],
+            AttributeValue::S(format!("roll#{:020}#{:020}", 1_000, 42))
+        );
+        assert_eq!(
+            item[EXPIRES_AT_ATTRIBUTE],
+            AttributeValue::N(roll.expires_at().to_string())
+        );
+        assert_eq!(roll_from_item(&item).unwrap(), roll);
+        // Migrations only touch items holding an expression.
+        assert!(!item.contains_key("expression_type"));
+
+        // Unseeded rolls made in the same second still get keys of their own.
+        let unseeded = LoggedRoll { seed: None, ..roll };
+        assert_ne!(roll_key(&unseeded), roll_key(&unseeded));
+    }
+
+    #[test]
+    fn test_expression_item_schema() {
+        let expr = Expression::DiceRoll {
+            count: Box::new(Expression::Integer(2)),
+            sides: Box::new(Expression::Variable("sides".to_string())),
+        };
+        let mut item = item_from_expression(&expr).unwrap();
+        assert_eq!(schema_version(&item).unwrap(), CURRENT_VERSION);
+        item.insert("pk".to_string(), AttributeValue::S("pk".to_string()));
+        item.insert("version".to_string(), AttributeValue::N("3".to_string()));
+        assert_eq!(expression_from_item(&item).unwrap(), expr);
+
+        // Items saved before the schema was versioned are upgraded on read.
+        item.remove(SCHEMA_VERSION_ATTRIBUTE);
+        assert_eq!(schema_version(&item).unwrap(), 0);
+        assert_eq!(expression_from_item(&item).unwrap(), expr);
+
+        item.insert(
+            SCHEMA_VERSION_ATTRIBUTE.to_string(),
+            AttributeValue::N((CURRENT_VERSION + 1).to_string()),
+        );
+        assert!(expression_from_item(&item).is_err());
+
+        // Items the migrations can't read fall back to their source text.
+        item.insert(
+            SCHEMA_VERSION_ATTRIBUTE.to_string(),
+            AttributeValue::N("0".to_string()),
+        );
+        item.insert(
+            "expression_type".to_string(),
+            AttributeValue::S("exploding_dice".to_string()),
+        );
+        assert!(expression_from_item(&item).is_err());
+        item.insert(
+            SOURCE_ATTRIBUTE.to_string(),
+            AttributeValue::S("2d{sides}".to_string()),
+        );
+        assert_eq!(source(&item), Some("2d{sides}".to_string()));
+        assert_eq!(expression_from_item(&item).unwrap(), expr);
+    }
+}
@@ -1,2 +1,24 @@
+pub mod caching_environment;
 pub mod dynamodb_environment;
+pub mod file_environment;
 pub mod hash_map_environment;
+pub mod layered_environment;
+pub mod sqlite_environment;
+
+use crate::types::Expression;
+
+// One `name => value` line per variable, sorted by name. Variables saved with
+// source text are shown as they were written.
+pub(crate) fn format_variables<'a>(
+    variables: impl IntoIterator<Item = (&'a String, &'a Expression, Option<&'a String>)>,
+) -> String {
+    let mut lines: Vec<String> = variables
+        .into_iter()
+        .map(|(name, expr, source)| match source {
+            Some(source) => format!("{} => {}", name, source),
+            None => format!("{} => {}", name, expr),
+        })
+        .collect();
+    lines.sort();
+    lines.join("\n")
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use serde_json::Value;
+
+use crate::error::RollerError;
+use crate::roll_log::LoggedRoll;
+use crate::types::{Context, Environment, Expression, SharedEnvironment, VariableVersion};
+
+const DEFAULT_SCOPE_CAPACITY: usize = 256;
+const DEFAULT_MAX_SCOPES: usize = 1024;
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheMetrics {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl Display for CacheMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "hits: {}, misses: {}", self.hits(), self.misses())
+    }
+}
+
+struct CacheEntry {
+    value: Option<Expression>,
+    expires_at: Instant,
+}
+
+type ScopeCache = LruCache<String, CacheEntry>;
+
+// Read-through cache in front of another environment. Each scope keeps its own
+// LRU of recently read variables, including ones that weren't found, and entries
+// expire after a TTL so writes made elsewhere are eventually picked up. Writes go
+// through to the inner environment before updating the cache.
+#[derive(Clone)]
+pub struct CachingEnvironment<E: Environment> {
+    inner: E,
+    scopes: Arc<Mutex<LruCache<String, ScopeCache>>>,
+    scope_capacity: NonZeroUsize,
+    ttl: Duration,
+    metrics: Arc<CacheMetrics>,
+}
+
+impl<E: Environment> CachingEnvironment<E> {
+    pub fn new(inner: E) -> Self {
+        CachingEnvironment::with_limits(inner, DEFAULT_SCOPE_CAPACITY, DEFAULT_TTL)
+    }
+
+    pub fn with_limits(inner: E, scope_capacity: usize, ttl: Duration) -> Self {
+        CachingEnvironment {
+            inner,
+            scopes: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(DEFAULT_MAX_SCOPES).unwrap(),
+            ))),
+            scope_capacity: NonZeroUsize::new(scope_capacity).unwrap_or(NonZeroUsize::MIN),
+            ttl,
+            metrics: Arc::new(CacheMetrics::default()),
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<CacheMetrics> {
+        self.metrics.clone()
+    }
+
+    pub fn invalidate<C: Context>(&self, ctx: C, var_name: &str) {
+        if let Some(scope) = self.scopes.lock().unwrap().get_mut(&ctx.user_context_key()) {
+            scope.pop(var_name);
+        }
+    }
+
+    pub fn invalidate_scope<C: Context>(&self, ctx: C) {
+        self.scopes.lock().unwrap().pop(&ctx.user_context_key());
+    }
+
+    pub fn invalidate_all(&self) {
+        self.scopes.lock().unwrap().clear();
+    }
+
+    fn cached(&self, scope: &str, var_name: &str) -> Option<Option<Expression>> {
+        let mut scopes = self.scopes.lock().unwrap();
+        let scope_cache = scopes.get_mut(scope)?;
+        match scope_cache.get(var_name) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                scope_cache.pop(var_name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn store(&self, scope: String, var_name: &str, value: Option<Expression>) {
+        let mut scopes = self.scopes.lock().unwrap();
+        let scope_cache = scopes.get_or_insert_mut(scope, || LruCache::new(self.scope_capacity));
+        scope_cache.put(
+            var_name.to_string(),
+            CacheEntry {
+                value,
+                expires_at: Instant::now() + self.ttl,
+            },
+        );
+    }
+}
+
+// Clones share the cache as well as the inner environment's storage.
+impl<E: SharedEnvironment> SharedEnvironment for CachingEnvironment<E> {}
+
+impl<E: Environment + Send + Sync> Environment for CachingEnvironment<E> {
+    async fn get<C: Context + Send>(&self, ctx: C, var_name: &str) -> Option<Expression> {
+        self.try_get(ctx, var_name).await.ok().flatten()
+    }
+
+    // Failed reads aren't cached, so the next lookup tries again.
+    async fn try_get<C: Context + Send>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Option<Expression>, RollerError> {
+        let scope = ctx.user_context_key();
+        if let Some(value) = self.cached(&scope, var_name) {
+            self.metrics.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+
+        self.metrics.misses.fetch_add(1, Ordering::Relaxed);
+        let value = self.inner.try_get(ctx, var_name).await?;
+        self.store(scope, var_name, value.clone());
+        Ok(value)
+    }
+
+    async fn set<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        result: &Expression,
+    ) -> Result<(), RollerError> {
+        let scope = ctx.user_context_key();
+        self.inner.set(ctx, var_name, result).await?;
+        self.store(scope, var_name, Some(result.clone()));
+        Ok(())
+    }
+
+    async fn set_with_source<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        result: &Expression,
+        source: &str,
+    ) -> Result<(), RollerError> {
+        let scope = ctx.user_context_key();
+        self.inner
+            .set_with_source(ctx, var_name, result, source)
+            .await?;
+        self.store(scope, var_name, Some(result.clone()));
+        Ok(())
+    }
+
+    async fn source<C: Context + Send>(&self, ctx: C, var_name: &str) -> Option<String> {
+        self.inner.source(ctx, var_name).await
+    }
+
+    async fn try_source<C: Context + Send>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Option<String>, RollerError> {
+        self.inner.try_source(ctx, var_name).await
+    }
+
+    async fn print<C: Context + Send>(&self, ctx: C) -> String {
+        self.inner.print(ctx).await
+    }
+
+    async fn history<C: Context + Send>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Vec<VariableVersion>, RollerError> {
+        self.inner.history(ctx, var_name).await
+    }
+
+    async fn state<C: Context + Send>(
+        &self,
+        ctx: C,
+        name: &str,
+    ) -> Result<Option<Value>, RollerError> {
+        self.inner.state(ctx, name).await
+    }
+
+    async fn set_state<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        name: &str,
+        value: &Value,
+    ) -> Result<(), RollerError> {
+        self.inner.set_state(ctx, name, value).await
+    }
+
+    async fn versioned_state<C: Context + Send>(
+        &self,
+        ctx: C,
+        name: &str,
+    ) -> Result<(Option<Value>, u64), RollerError> {
+        self.inner.versioned_state(ctx, name).await
+    }
+
+    async fn set_state_version<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        name: &str,
+        value: &Value,
+        expected_version: u64,
+    ) -> Result<(), RollerError> {
+        self.inner
+            .set_state_version(ctx, name, value, expected_version)
+            .await
+    }
+
+    async fn log_roll<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        roll: &LoggedRoll,
+    ) -> Result<(), RollerError> {
+        self.inner.log_roll(ctx, roll).await
+    }
+
+    async fn rolls<C: Context + Send>(
+        &self,
+        ctx: C,
+        since: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<LoggedRoll>, RollerError> {
+        self.inner.rolls(ctx, since, limit).await
+    }
+
+    async fn version<C: Context + Send>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Option<u64>, RollerError> {
+        self.inner.version(ctx, var_name).await
+    }
+
+    async fn set_version<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        value: &Expression,
+        source: Option<&str>,
+        expected_version: u64,
+    ) -> Result<(), RollerError> {
+        let scope = ctx.user_context_key();
+        self.inner
+            .set_version(ctx, var_name, value, source, expected_version)
+            .await?;
+        self.store(scope, var_name, Some(value.clone()));
+        Ok(())
+    }
+
+    async fn move_scope<F: Context + Send, T: Context + Send>(
+        &mut self,
+        from: F,
+        to: T,
+    ) -> Result<u64, RollerError> {
+        let scopes = [from.user_context_key(), to.user_context_key()];
+        let moved = self.inner.move_scope(from, to).await;
+        for scope in &scopes {
+            self.scopes.lock().unwrap().pop(scope);
+        }
+        moved
+    }
+
+    async fn set_pending_roll(
+        &mut self,
+        message_id: &str,
+        statement: &str,
+    ) -> Result<(), RollerError> {
+        self.inner.set_pending_roll(message_id, statement).await
+    }
+
+    async fn pending_roll(&self, message_id: &str) -> Result<Option<String>, RollerError> {
+        self.inner.pending_roll(message_id).await
+    }
+
+    async fn closure<C: Context + Send>(
+        &self,
+        ctx: C,
+    ) -> Result<HashMap<String, Expression>, RollerError> {
+        self.inner.closure(ctx).await
+    }
+
+    async fn closure_with_sources<C: Context + Copy + Send>(
+        &self,
+        ctx: C,
+    ) -> Result<HashMap<String, (Expression, Option<String>)>, RollerError> {
+        self.inner.closure_with_sources(ctx).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environments::hash_map_environment::HashMapEnvironment;
+    use crate::repl::REPLContext;
+
+    struct TestCtx;
+
+    impl Context for &TestCtx {
+        fn user_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", "test", "test_user")
+        }
+
+        fn global_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", "test", "global")
+        }
+
+        fn scope_context(&self, scope: &str) -> REPLContext {
+            REPLContext::new(scope.to_string(), "test_user".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_through() {
+        let ctx = &TestCtx;
+        let mut inner = HashMapEnvironment::new();
+        inner.set(ctx, "a", &Expression::Integer(1)).await.unwrap();
+        let mut env = CachingEnvironment::new(inner);
+
+        assert_eq!(env.get(ctx, "a").await, Some(Expression::Integer(1)));
+        assert_eq!(env.get(ctx, "a").await, Some(Expression::Integer(1)));
+        assert_eq!(env.get(ctx, "missing").await, None);
+        assert_eq!(env.get(ctx, "missing").await, None);
+        assert_eq!((env.metrics().hits(), env.metrics().misses()), (2, 2));
+
+        env.set(ctx, "a", &Expression::Integer(2)).await.unwrap();
+        assert_eq!(env.get(ctx, "a").await, Some(Expression::Integer(2)));
+        assert_eq!(env.inner.get(ctx, "a").await, Some(Expression::Integer(2)));
+        assert_eq!(env.metrics().hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_eviction_and_invalidation() {
+        let ctx = &TestCtx;
+        let mut inner = HashMapEnvironment::new();
+        inner.set(ctx, "a", &Expression::Integer(1)).await.unwrap();
+        inner.set(ctx, "b", &Expression::Integer(2)).await.unwrap();
+        let env = CachingEnvironment::with_limits(inner, 1, Duration::from_secs(60));
+
+        env.get(ctx, "a").await;
+        env.get(ctx, "b").await;
+        // "a" was evicted to make room for "b".
+        env.get(ctx, "a").await;
+        assert_eq!(env.metrics().misses(), 3);
+
+        env.invalidate(ctx, "a");
+        env.get(ctx, "a").await;
+        assert_eq!(env.metrics().misses(), 4);
+
+        env.invalidate_scope(ctx);
+        env.get(ctx, "a").await;
+        assert_eq!(env.metrics().misses(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_expiry() {
+        let ctx = &TestCtx;
+        let mut inner = HashMapEnvironment::new();
+        inner.set(ctx, "a", &Expression::Integer(1)).await.unwrap();
+        let env = CachingEnvironment::with_limits(inner, 16, Duration::ZERO);
+
+        env.get(ctx, "a").await;
+        env.get(ctx, "a").await;
+        assert_eq!((env.metrics().hits(), env.metrics().misses()), (0, 2));
+    }
+}
@@ -1,5 +1,9 @@
 use crate::dynamodb::DDBClient;
-use crate::types::{Context, Environment, Expression};
+use crate::environments::format_variables;
+use crate::error::RollerError;
+use crate::roll_log::LoggedRoll;
+use crate::types::{Context, Environment, Expression, SharedEnvironment, VariableVersion};
+use serde_json::Value;
 use std::collections::HashMap;
 
 #[derive(Clone)]
@@ -13,37 +17,226 @@ impl DynamoDBEnvironment {
     }
 }
 
+impl SharedEnvironment for DynamoDBEnvironment {}
+
 impl Environment for DynamoDBEnvironment {
-    async fn get<C: Context>(&self, ctx: C, var_name: &str) -> Option<Expression> {
+    async fn get<C: Context + Send>(&self, ctx: C, var_name: &str) -> Option<Expression> {
+        self.try_get(ctx, var_name).await.ok().flatten()
+    }
+
+    async fn try_get<C: Context>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Option<Expression>, RollerError> {
         self.client
             .get_expression(&ctx.user_context_key(), &format!("var_name:{}", var_name))
             .await
-            .ok()
     }
 
-    async fn set<C: Context>(&mut self, ctx: C, var_name: &str, result: &Expression) {
+    async fn set<C: Context>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        result: &Expression,
+    ) -> Result<(), RollerError> {
+        self.client
+            .set_expression(
+                &ctx.user_context_key(),
+                &format!("var_name:{}", var_name),
+                result,
+                None,
+            )
+            .await
+            .map(|_| ())
+    }
+
+    async fn set_with_source<C: Context>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        result: &Expression,
+        source: &str,
+    ) -> Result<(), RollerError> {
         self.client
             .set_expression(
                 &ctx.user_context_key(),
                 &format!("var_name:{}", var_name),
                 result,
+                Some(source),
             )
             .await
+            .map(|_| ())
+    }
+
+    async fn source<C: Context + Send>(&self, ctx: C, var_name: &str) -> Option<String> {
+        self.try_source(ctx, var_name).await.ok().flatten()
+    }
+
+    async fn try_source<C: Context>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Option<String>, RollerError> {
+        self.client
+            .get_source(&ctx.user_context_key(), &format!("var_name:{}", var_name))
+            .await
     }
 
     async fn print<C: Context>(&self, ctx: C) -> String {
-        format!("dynamo-env:{}", ctx.user_context_key())
+        match self
+            .client
+            .get_all_with_sources_in_scope(&ctx.user_context_key(), "var_name:")
+            .await
+        {
+            Ok(variables) => format_variables(
+                variables
+                    .iter()
+                    .map(|(name, (expr, source))| (name, expr, source.as_ref())),
+            ),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    async fn version<C: Context>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Option<u64>, RollerError> {
+        self.client
+            .get_version(&ctx.user_context_key(), &format!("var_name:{}", var_name))
+            .await
+            .map(Some)
+    }
+
+    async fn set_version<C: Context>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        value: &Expression,
+        source: Option<&str>,
+        expected_version: u64,
+    ) -> Result<(), RollerError> {
+        self.client
+            .put_expression_version(
+                &ctx.user_context_key(),
+                &format!("var_name:{}", var_name),
+                value,
+                source,
+                expected_version,
+            )
+            .await
+            .map(|_| ())
+    }
+
+    async fn closure<C: Context>(
+        &self,
+        ctx: C,
+    ) -> Result<HashMap<String, Expression>, RollerError> {
+        self.client
+            .get_all_in_scope(&ctx.user_context_key(), "var_name:")
+            .await
+    }
+
+    async fn closure_with_sources<C: Context + Copy + Send>(
+        &self,
+        ctx: C,
+    ) -> Result<HashMap<String, (Expression, Option<String>)>, RollerError> {
+        self.client
+            .get_all_with_sources_in_scope(&ctx.user_context_key(), "var_name:")
+            .await
+    }
+
+    async fn history<C: Context>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Vec<VariableVersion>, RollerError> {
+        self.client
+            .get_history(&ctx.user_context_key(), &format!("var_name:{}", var_name))
+            .await
+    }
+
+    async fn state<C: Context>(&self, ctx: C, name: &str) -> Result<Option<Value>, RollerError> {
+        self.client.get_state(&ctx.user_context_key(), name).await
+    }
+
+    async fn set_state<C: Context>(
+        &mut self,
+        ctx: C,
+        name: &str,
+        value: &Value,
+    ) -> Result<(), RollerError> {
+        self.client
+            .put_state(&ctx.user_context_key(), name, value)
+            .await
+    }
+
+    async fn versioned_state<C: Context>(
+        &self,
+        ctx: C,
+        name: &str,
+    ) -> Result<(Option<Value>, u64), RollerError> {
+        self.client
+            .get_state_version(&ctx.user_context_key(), name)
+            .await
+    }
+
+    async fn set_state_version<C: Context>(
+        &mut self,
+        ctx: C,
+        name: &str,
+        value: &Value,
+        expected_version: u64,
+    ) -> Result<(), RollerError> {
+        self.client
+            .put_state_version(&ctx.user_context_key(), name, value, expected_version)
+            .await
+    }
+
+    async fn log_roll<C: Context>(&mut self, ctx: C, roll: &LoggedRoll) -> Result<(), RollerError> {
+        self.client.put_roll(&ctx.user_context_key(), roll).await
+    }
+
+    async fn rolls<C: Context>(
+        &self,
+        ctx: C,
+        since: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<LoggedRoll>, RollerError> {
+        self.client
+            .get_rolls(&ctx.user_context_key(), since, limit)
+            .await
+    }
+
+    async fn move_scope<F: Context, T: Context>(
+        &mut self,
+        from: F,
+        to: T,
+    ) -> Result<u64, RollerError> {
+        self.client
+            .move_partition(&from.user_context_key(), &to.user_context_key())
+            .await
+    }
+
+    async fn set_pending_roll(
+        &mut self,
+        message_id: &str,
+        statement: &str,
+    ) -> Result<(), RollerError> {
+        self.client.put_pending_roll(message_id, statement).await
     }
 
-    async fn closure<C: Context>(&self, ctx: C) -> Result<HashMap<String, Expression>, ()> {
-        self.client.get_all_in_scope(&ctx.user_context_key()).await
+    async fn pending_roll(&self, message_id: &str) -> Result<Option<String>, RollerError> {
+        self.client.get_pending_roll(message_id).await
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::dynamodb::{make_client, DDBClient};
+    use crate::dynamodb::{make_client, DDBClient, LOCALSTACK_ENDPOINT};
+    use crate::repl::REPLContext;
 
     use super::*;
     use aws_sdk_dynamodb::types::{
@@ -61,10 +254,14 @@ mod tests {
         fn global_context_key(&self) -> String {
             format!("scope:{}#scope_type:user#user:{}", "test", "global")
         }
+
+        fn scope_context(&self, scope: &str) -> REPLContext {
+            REPLContext::new(scope.to_string(), "test_user".to_string())
+        }
     }
 
     #[allow(clippy::result_large_err)]
-    async fn make_env(client: &DDBClient) -> Result<DynamoDBEnvironment, Error> {
+    async fn make_env(client: &DDBClient, table_name: &str) -> Result<DynamoDBEnvironment, Error> {
         let pk = AttributeDefinition::builder()
             .attribute_name("pk")
             .attribute_type(ScalarAttributeType::S)
@@ -88,14 +285,14 @@ mod tests {
         let _ = client
             .client
             .delete_table()
-            .table_name("dice-roller-test")
+            .table_name(table_name)
             .send()
             .await;
 
         let _ = client
             .client
             .create_table()
-            .table_name("dice-roller-test")
+            .table_name(table_name)
             .key_schema(pks)
             .key_schema(sks)
             .attribute_definitions(pk)
@@ -110,15 +307,126 @@ mod tests {
     #[tokio::test]
     async fn test_save_read_dynamo() {
         let client = DDBClient::new(
-            make_client(true).await.expect("failed to create client"),
+            make_client(Some(LOCALSTACK_ENDPOINT))
+                .await
+                .expect("failed to create client"),
             "dice-roller-test".to_string(),
         );
-        let mut env = make_env(&client).await.expect("failed to create env");
+        let mut env = make_env(&client, "dice-roller-test")
+            .await
+            .expect("failed to create env");
         let ctx = &TestCtx;
-        env.set(ctx, "test_value", &Expression::Integer(1)).await;
+        env.set(ctx, "test_value", &Expression::Integer(1))
+            .await
+            .unwrap();
         assert_eq!(
             env.get(ctx, "test_value").await.unwrap(),
             Expression::Integer(1)
         )
     }
+
+    #[tokio::test]
+    async fn test_closure_paginates_dynamo() {
+        let client = DDBClient::new(
+            make_client(Some(LOCALSTACK_ENDPOINT))
+                .await
+                .expect("failed to create client"),
+            "dice-roller-test-pagination".to_string(),
+        );
+        let mut env = make_env(&client, "dice-roller-test-pagination")
+            .await
+            .expect("failed to create env");
+        let ctx = &TestCtx;
+
+        // ~300KB per item, so the scope is several times Query's 1MB page size.
+        let expr = Expression::DiceRollTemplate {
+            args: vec!["a".repeat(1000); 300],
+            expressions: vec![Expression::Integer(1)],
+        };
+        for i in 0..12 {
+            env.set(ctx, &format!("large_value_{}", i), &expr)
+                .await
+                .unwrap();
+        }
+
+        let closure = env.closure(ctx).await.unwrap();
+        assert_eq!(closure.len(), 12);
+        assert_eq!(closure.get("large_value_11").unwrap(), &expr);
+    }
+
+    #[tokio::test]
+    async fn test_history_dynamo() {
+        let client = DDBClient::new(
+            make_client(Some(LOCALSTACK_ENDPOINT))
+                .await
+                .expect("failed to create client"),
+            "dice-roller-test-history".to_string(),
+        );
+        let mut env = make_env(&client, "dice-roller-test-history")
+            .await
+            .expect("failed to create env");
+        let ctx = &TestCtx;
+
+        for i in 1..=12 {
+            env.set(ctx, "test_value", &Expression::Integer(i))
+                .await
+                .unwrap();
+        }
+        // A variable whose name extends test_value's isn't part of its history.
+        env.set(ctx, "test_value_2", &Expression::Integer(0))
+            .await
+            .unwrap();
+
+        let history = env.history(ctx, "test_value").await.unwrap();
+        let versions: Vec<u64> = history.iter().map(|version| version.version).collect();
+        assert_eq!(versions, (3..=12).rev().collect::<Vec<u64>>());
+        assert_eq!(history[0].expression, Expression::Integer(12));
+        assert_eq!(env.closure(ctx).await.unwrap().len(), 2);
+
+        // A writer that read version 11 loses to the write of version 12.
+        let conflict = client
+            .put_expression_version(
+                &ctx.user_context_key(),
+                "var_name:test_value",
+                &Expression::Integer(0),
+                None,
+                11,
+            )
+            .await;
+        assert!(matches!(conflict, Err(RollerError::ConflictError(_))));
+        assert_eq!(
+            env.get(ctx, "test_value").await.unwrap(),
+            Expression::Integer(12)
+        );
+        assert_eq!(env.version(ctx, "test_value").await.unwrap(), Some(12));
+
+        // State is numbered the same way: of two writes based on version 1, the
+        // second is turned down.
+        env.set_state(ctx, "gms", &serde_json::json!([1]))
+            .await
+            .unwrap();
+        let (_, version) = env.versioned_state(ctx, "gms").await.unwrap();
+        assert_eq!(version, 1);
+        env.set_state_version(ctx, "gms", &serde_json::json!([1, 2]), version)
+            .await
+            .unwrap();
+        let stale = env
+            .set_state_version(ctx, "gms", &serde_json::json!([]), version)
+            .await;
+        assert!(matches!(stale, Err(RollerError::ConflictError(_))));
+        assert_eq!(
+            env.versioned_state(ctx, "gms").await.unwrap(),
+            (Some(serde_json::json!([1, 2])), 2)
+        );
+
+        env.set_with_source(ctx, "test_value", &Expression::Integer(13), "13")
+            .await
+            .unwrap();
+        assert_eq!(env.source(ctx, "test_value").await, Some("13".to_string()));
+        assert_eq!(
+            env.history(ctx, "test_value").await.unwrap()[0].source,
+            Some("13".to_string())
+        );
+        assert!(env.print(ctx).await.contains("test_value => 13"));
+    }
 }
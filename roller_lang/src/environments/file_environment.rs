@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::environments::hash_map_environment::{HashMapEnvironment, Variable};
+use crate::error::RollerError;
+use crate::roll_log::LoggedRoll;
+use crate::types::{Context, Environment, Expression};
+
+const DATA_DIR_NAME: &str = "dice-roller";
+const ENVIRONMENT_FILE_NAME: &str = "environment.json";
+// The roll log sits beside the environment file, e.g. environment.rolls.jsonl.
+const ROLL_LOG_EXTENSION: &str = "rolls.jsonl";
+
+#[derive(Clone)]
+pub struct FileEnvironment {
+    path: PathBuf,
+    // Variables and state, exactly as saved in the file at path.
+    env: HashMapEnvironment,
+    // The roll log, which is appended to its own file a roll at a time rather
+    // than rewritten with everything else.
+    rolls: HashMapEnvironment,
+}
+
+// A line of the roll log.
+#[derive(Serialize, Deserialize)]
+struct LogEntry {
+    scope: String,
+    roll: LoggedRoll,
+}
+
+impl FileEnvironment {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut env: HashMapEnvironment = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMapEnvironment::new(),
+            Err(err) => return Err(err),
+        };
+
+        // Environment files used to hold the roll log too.
+        let mut rolls = HashMapEnvironment::new();
+        let legacy = env.take_rolls();
+        for (scope, scope_rolls) in &legacy {
+            for roll in scope_rolls {
+                rolls.log_roll_in(scope, roll.clone());
+            }
+        }
+        let mut entries = 0;
+        match fs::read_to_string(roll_log_path(&path)) {
+            // A line cut short by a crash mid-append is skipped.
+            Ok(contents) => {
+                for entry in contents
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<LogEntry>(line).ok())
+                {
+                    rolls.log_roll_in(&entry.scope, entry.roll);
+                    entries += 1;
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let environment = FileEnvironment { path, env, rolls };
+        // The log only grows while it's open, so it's rewritten with just the
+        // rolls still kept when it's opened.
+        let kept: usize = environment
+            .rolls
+            .logged_rolls()
+            .values()
+            .map(Vec::len)
+            .sum();
+        if !legacy.is_empty() || entries > kept {
+            environment.save_roll_log()?;
+        }
+        if !legacy.is_empty() {
+            environment.save()?;
+        }
+        Ok(environment)
+    }
+
+    pub fn with_default_path() -> io::Result<Self> {
+        FileEnvironment::open(default_path()?)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        write_file(&self.path, serde_json::to_string(&self.env)?.as_bytes())
+    }
+
+    fn save_roll_log(&self) -> io::Result<()> {
+        let mut contents = String::new();
+        for (scope, rolls) in self.rolls.logged_rolls() {
+            for roll in rolls {
+                contents.push_str(&log_line(scope, roll)?);
+            }
+        }
+        write_file(&roll_log_path(&self.path), contents.as_bytes())
+    }
+
+    // Saves a change already made to env, calling undo to take it back if it
+    // can't be saved, so what's in memory is always what's on disk.
+    fn commit(
+        &mut self,
+        what: &str,
+        undo: impl FnOnce(&mut HashMapEnvironment),
+    ) -> Result<(), RollerError> {
+        match self.save() {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                undo(&mut self.env);
+                Err(RollerError::StorageError(format!(
+                    "failed to save {} to {}: {}",
+                    what,
+                    self.path.display(),
+                    err
+                )))
+            }
+        }
+    }
+
+    // Saves variables in one scope with a single write, or none at all if
+    // they're already saved as they are.
+    fn put_variables(
+        &mut self,
+        scope: &str,
+        variables: Vec<(&str, Variable)>,
+        what: &str,
+    ) -> Result<(), RollerError> {
+        let changed: Vec<_> = variables
+            .into_iter()
+            .filter(|(name, variable)| self.env.variable(scope, name).as_ref() != Some(variable))
+            .collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        let previous: Vec<_> = changed
+            .iter()
+            .map(|(name, _)| (*name, self.env.variable(scope, name)))
+            .collect();
+        for (name, variable) in changed {
+            self.env.put_variable(scope, name, Some(variable));
+        }
+        self.commit(what, |env| {
+            for (name, variable) in previous {
+                env.put_variable(scope, name, variable);
+            }
+        })
+    }
+}
+
+// Writes to a sibling temporary file and renames it over the original, so a
+// crash mid-write never leaves a truncated file behind.
+fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+fn roll_log_path(path: &Path) -> PathBuf {
+    path.with_extension(ROLL_LOG_EXTENSION)
+}
+
+fn log_line(scope: &str, roll: &LoggedRoll) -> io::Result<String> {
+    let entry = LogEntry {
+        scope: scope.to_string(),
+        roll: roll.clone(),
+    };
+    Ok(format!("{}\n", serde_json::to_string(&entry)?))
+}
+
+// $XDG_DATA_HOME/dice-roller/environment.json.
+pub fn default_path() -> io::Result<PathBuf> {
+    Ok(data_dir()?.join(ENVIRONMENT_FILE_NAME))
+}
+
+// $XDG_DATA_HOME/dice-roller, falling back to the platform's data directory
+// when XDG_DATA_HOME isn't set.
+pub fn data_dir() -> io::Result<PathBuf> {
+    match dirs::data_dir() {
+        Some(dir) => Ok(dir.join(DATA_DIR_NAME)),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "could not determine a data directory",
+        )),
+    }
+}
+
+impl Environment for FileEnvironment {
+    async fn get<C: Context + Send>(&self, ctx: C, var_name: &str) -> Option<Expression> {
+        self.env.get(ctx, var_name).await
+    }
+
+    async fn set<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        result: &Expression,
+    ) -> Result<(), RollerError> {
+        let variable = Variable {
+            expression: result.clone(),
+            source: None,
+        };
+        self.put_variables(
+            &ctx.user_context_key(),
+            vec![(var_name, variable)],
+            var_name,
+        )
+    }
+
+    async fn set_with_source<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        result: &Expression,
+        source: &str,
+    ) -> Result<(), RollerError> {
+        let variable = Variable {
+            expression: result.clone(),
+            source: Some(source.to_string()),
+        };
+        self.put_variables(
+            &ctx.user_context_key(),
+            vec![(var_name, variable)],
+            var_name,
+        )
+    }
+
+    async fn set_all<C: Context + Copy + Send>(
+        &mut self,
+        ctx: C,
+        variables: &[(String, Expression, Option<String>)],
+    ) -> Result<(), RollerError> {
+        let variables = variables
+            .iter()
+            .map(|(name, expression, source)| {
+                let variable = Variable {
+                    expression: expression.clone(),
+                    source: source.clone(),
+                };
+                (name.as_str(), variable)
+            })
+            .collect();
+        self.put_variables(&ctx.user_context_key(), variables, "variables")
+    }
+
+    async fn source<C: Context + Send>(&self, ctx: C, var_name: &str) -> Option<String> {
+        self.env.source(ctx, var_name).await
+    }
+
+    async fn print<C: Context + Send>(&self, ctx: C) -> String {
+        self.env.print(ctx).await
+    }
+
+    async fn closure<C: Context + Send>(
+        &self,
+        ctx: C,
+    ) -> Result<HashMap<String, Expression>, RollerError> {
+        self.env.closure(ctx).await
+    }
+
+    async fn closure_with_sources<C: Context + Copy + Send>(
+        &self,
+        ctx: C,
+    ) -> Result<HashMap<String, (Expression, Option<String>)>, RollerError> {
+        self.env.closure_with_sources(ctx).await
+    }
+
+    async fn move_scope<F: Context + Send, T: Context + Send>(
+        &mut self,
+        from: F,
+        to: T,
+    ) -> Result<u64, RollerError> {
+        // Moving a scope is rare enough to keep a copy to undo it with.
+        let previous = self.env.clone();
+        let moved = self.env.move_scope(from, to).await?;
+        if moved > 0 {
+            self.commit("variables", |env| *env = previous)?;
+        }
+        Ok(moved)
+    }
+
+    async fn state<C: Context + Send>(
+        &self,
+        ctx: C,
+        name: &str,
+    ) -> Result<Option<Value>, RollerError> {
+        self.env.state(ctx, name).await
+    }
+
+    async fn set_state<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        name: &str,
+        value: &Value,
+    ) -> Result<(), RollerError> {
+        let scope = ctx.user_context_key();
+        let previous = self.env.state_value(&scope, name);
+        if previous.as_ref() == Some(value) {
+            return Ok(());
+        }
+        self.env.put_state(&scope, name, Some(value.clone()));
+        self.commit(name, |env| env.put_state(&scope, name, previous))
+    }
+
+    async fn log_roll<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        roll: &LoggedRoll,
+    ) -> Result<(), RollerError> {
+        let scope = ctx.user_context_key();
+        let log_path = roll_log_path(&self.path);
+        let append = || -> io::Result<()> {
+            if let Some(parent) = log_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&log_path)?
+                .write_all(log_line(&scope, roll)?.as_bytes())
+        };
+        append().map_err(|err| {
+            RollerError::StorageError(format!(
+                "failed to log roll to {}: {}",
+                log_path.display(),
+                err
+            ))
+        })?;
+        self.rolls.log_roll_in(&scope, roll.clone());
+        Ok(())
+    }
+
+    async fn rolls<C: Context + Send>(
+        &self,
+        ctx: C,
+        since: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<LoggedRoll>, RollerError> {
+        self.rolls.rolls(ctx, since, limit).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repl::REPLContext;
+    use crate::types::RollResult;
+
+    struct TestCtx;
+
+    impl Context for &TestCtx {
+        fn user_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", "test", "test_user")
+        }
+
+        fn global_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", "test", "global")
+        }
+
+        fn scope_context(&self, scope: &str) -> REPLContext {
+            REPLContext::new(scope.to_string(), "test_user".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_read_file() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("nested").join("environment.json");
+        let ctx = &TestCtx;
+
+        let mut env = FileEnvironment::open(&path).expect("failed to open env");
+        assert_eq!(env.get(ctx, "test_value").await, None);
+        env.set(ctx, "test_value", &Expression::Integer(1))
+            .await
+            .unwrap();
+
+        let reopened = FileEnvironment::open(&path).expect("failed to reopen env");
+        assert_eq!(
+            reopened.get(ctx, "test_value").await.unwrap(),
+            Expression::Integer(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_read_state_file() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("environment.json");
+        let ctx = &TestCtx;
+
+        let mut env = FileEnvironment::open(&path).expect("failed to open env");
+        env.set(ctx, "test_value", &Expression::Integer(1))
+            .await
+            .unwrap();
+        env.set_state(ctx, "gms", &serde_json::json!([1, 2]))
+            .await
+            .unwrap();
+
+        let reopened = FileEnvironment::open(&path).expect("failed to reopen env");
+        assert_eq!(
+            reopened.state(ctx, "gms").await.unwrap(),
+            Some(serde_json::json!([1, 2]))
+        );
+        assert_eq!(
+            reopened.get(ctx, "test_value").await,
+            Some(Expression::Integer(1))
+        );
+
+        // Files written before state was saved hold only variables.
+        fs::write(
+            &path,
+            r#"{"scope": {"a": {"expression_type": "integer", "expression": 1}}}"#,
+        )
+        .unwrap();
+        let legacy = FileEnvironment::open(&path).expect("failed to open legacy env");
+        assert_eq!(legacy.state(ctx, "gms").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_save_failure_file() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("environment.json");
+        // A directory in the way of the temporary file makes every save fail.
+        fs::create_dir(dir.path().join("environment.json.tmp")).unwrap();
+        let ctx = &TestCtx;
+
+        let mut env = FileEnvironment::open(&path).expect("failed to open env");
+        assert!(env
+            .set(ctx, "test_value", &Expression::Integer(1))
+            .await
+            .is_err());
+        assert_eq!(env.get(ctx, "test_value").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_skip_unchanged_and_batch_writes() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("environment.json");
+        let ctx = &TestCtx;
+
+        let mut env = FileEnvironment::open(&path).expect("failed to open env");
+        env.set(ctx, "a", &Expression::Integer(1)).await.unwrap();
+        env.set_state(ctx, "gms", &serde_json::json!([1]))
+            .await
+            .unwrap();
+        fs::create_dir(dir.path().join("environment.json.tmp")).unwrap();
+
+        // Nothing changes, so nothing is written.
+        env.set(ctx, "a", &Expression::Integer(1)).await.unwrap();
+        env.set_state(ctx, "gms", &serde_json::json!([1]))
+            .await
+            .unwrap();
+
+        // A batch is one write, undone as a whole when it fails.
+        let variables = vec![
+            ("a".to_string(), Expression::Integer(2), None),
+            (
+                "b".to_string(),
+                Expression::Integer(3),
+                Some("3".to_string()),
+            ),
+        ];
+        assert!(env.set_all(ctx, &variables).await.is_err());
+        assert_eq!(env.get(ctx, "a").await, Some(Expression::Integer(1)));
+        assert_eq!(env.get(ctx, "b").await, None);
+        assert!(env
+            .set_state(ctx, "gms", &serde_json::json!([2]))
+            .await
+            .is_err());
+        assert_eq!(
+            env.state(ctx, "gms").await.unwrap(),
+            Some(serde_json::json!([1]))
+        );
+
+        fs::remove_dir(dir.path().join("environment.json.tmp")).unwrap();
+        env.set_all(ctx, &variables).await.unwrap();
+        let reopened = FileEnvironment::open(&path).expect("failed to reopen env");
+        assert_eq!(reopened.get(ctx, "a").await, Some(Expression::Integer(2)));
+        assert_eq!(reopened.source(ctx, "b").await, Some("3".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_roll_log_file() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("environment.json");
+        let log_path = dir.path().join("environment.rolls.jsonl");
+        let ctx = &TestCtx;
+        let roll = |total| {
+            let result = RollResult {
+                expression: "1d20".to_string(),
+                dice: vec![],
+                total,
+            };
+            LoggedRoll::new(ctx, &result, None)
+        };
+        let totals = |env: &FileEnvironment| {
+            let rolls = env.rolls.logged_rolls().values().flatten();
+            rolls.map(|roll| roll.total).collect::<Vec<_>>()
+        };
+
+        let mut env = FileEnvironment::open(&path).expect("failed to open env");
+        env.set(ctx, "a", &Expression::Integer(1)).await.unwrap();
+        for total in 0..=500 {
+            env.log_roll(ctx, &roll(total)).await.unwrap();
+        }
+        // Logging only appends to the log; the environment isn't rewritten.
+        assert!(!fs::read_to_string(&path).unwrap().contains("1d20"));
+        assert_eq!(fs::read_to_string(&log_path).unwrap().lines().count(), 501);
+
+        // Reopening drops the rolls no longer kept from the log.
+        let reopened = FileEnvironment::open(&path).expect("failed to reopen env");
+        assert_eq!(totals(&reopened), (1..=500).collect::<Vec<_>>());
+        assert_eq!(fs::read_to_string(&log_path).unwrap().lines().count(), 500);
+
+        // Rolls saved in the environment file itself move to the log.
+        fs::remove_file(&log_path).unwrap();
+        let mut legacy = HashMapEnvironment::new();
+        legacy.log_roll(ctx, &roll(7)).await.unwrap();
+        fs::write(&path, serde_json::to_string(&legacy).unwrap()).unwrap();
+        let migrated = FileEnvironment::open(&path).expect("failed to open legacy env");
+        assert_eq!(totals(&migrated), vec![7]);
+        assert!(!fs::read_to_string(&path).unwrap().contains("1d20"));
+        assert_eq!(fs::read_to_string(&log_path).unwrap().lines().count(), 1);
+    }
+}
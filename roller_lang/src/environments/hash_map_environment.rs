@@ -1,11 +1,76 @@
 use core::fmt;
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt::Display,
+};
 
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::environments::format_variables;
+use crate::error::RollerError;
+use crate::roll_log::LoggedRoll;
 use crate::types::{Context, Environment, Expression};
 
-#[derive(Clone)]
+// Older rolls are dropped once a scope has logged this many, since nothing
+// expires them here.
+const MAX_LOGGED_ROLLS: usize = 500;
+
+// A saved value and the source text it was written as. The expression's own
+// fields are flattened in, so environments saved before source text was kept
+// still load.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Variable {
+    #[serde(flatten)]
+    pub(crate) expression: Expression,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) source: Option<String>,
+}
+
+type Scopes<V> = HashMap<String, HashMap<String, V>>;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "SavedEnvironment")]
 pub struct HashMapEnvironment {
-    env: HashMap<String, HashMap<String, Expression>>,
+    #[serde(rename = "variables")]
+    env: Scopes<Variable>,
+    state: Scopes<Value>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    rolls: HashMap<String, Vec<LoggedRoll>>,
+}
+
+// Environments saved before state was kept hold nothing but variables.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedEnvironment {
+    WithState {
+        variables: Scopes<Variable>,
+        #[serde(default)]
+        state: Scopes<Value>,
+        #[serde(default)]
+        rolls: HashMap<String, Vec<LoggedRoll>>,
+    },
+    Variables(Scopes<Variable>),
+}
+
+impl From<SavedEnvironment> for HashMapEnvironment {
+    fn from(saved: SavedEnvironment) -> Self {
+        match saved {
+            SavedEnvironment::WithState {
+                variables,
+                state,
+                rolls,
+            } => HashMapEnvironment {
+                env: variables,
+                state,
+                rolls,
+            },
+            SavedEnvironment::Variables(env) => HashMapEnvironment {
+                env,
+                ..HashMapEnvironment::new()
+            },
+        }
+    }
 }
 
 impl Default for HashMapEnvironment {
@@ -19,54 +84,355 @@ impl HashMapEnvironment {
         ctx: C,
         values: HashMap<String, Expression>,
     ) -> Self {
+        let values = values
+            .into_iter()
+            .map(|(name, expression)| {
+                (
+                    name,
+                    Variable {
+                        expression,
+                        source: None,
+                    },
+                )
+            })
+            .collect();
         let mut env = HashMap::new();
         env.insert(ctx.user_context_key(), values);
-        HashMapEnvironment { env }
+        HashMapEnvironment {
+            env,
+            ..HashMapEnvironment::new()
+        }
     }
 
     pub fn new() -> Self {
         HashMapEnvironment {
             env: HashMap::new(),
+            state: HashMap::new(),
+            rolls: HashMap::new(),
+        }
+    }
+
+    fn insert<C: Context>(&mut self, ctx: C, var_name: &str, variable: Variable) {
+        self.env
+            .entry(ctx.user_context_key())
+            .or_default()
+            .insert(var_name.to_string(), variable);
+    }
+
+    // The variable saved under name in the scope with the given key.
+    pub(crate) fn variable(&self, scope: &str, name: &str) -> Option<Variable> {
+        self.env.get(scope)?.get(name).cloned()
+    }
+
+    // Saves a variable in the scope with the given key, or removes it given
+    // None, e.g. to undo saving it.
+    pub(crate) fn put_variable(&mut self, scope: &str, name: &str, variable: Option<Variable>) {
+        put_entry(&mut self.env, scope, name, variable);
+    }
+
+    // The state saved under name in the scope with the given key.
+    pub(crate) fn state_value(&self, scope: &str, name: &str) -> Option<Value> {
+        self.state.get(scope)?.get(name).cloned()
+    }
+
+    // Saves state in the scope with the given key, or removes it given None.
+    pub(crate) fn put_state(&mut self, scope: &str, name: &str, value: Option<Value>) {
+        put_entry(&mut self.state, scope, name, value);
+    }
+
+    // Logs a roll under a scope's key, dropping the oldest past the limit.
+    pub(crate) fn log_roll_in(&mut self, scope: &str, roll: LoggedRoll) {
+        let rolls = self.rolls.entry(scope.to_string()).or_default();
+        rolls.push(roll);
+        if rolls.len() > MAX_LOGGED_ROLLS {
+            rolls.drain(..rolls.len() - MAX_LOGGED_ROLLS);
+        }
+    }
+
+    // Every logged roll, by scope.
+    pub(crate) fn logged_rolls(&self) -> &HashMap<String, Vec<LoggedRoll>> {
+        &self.rolls
+    }
+
+    // Removes and returns every logged roll, by scope.
+    pub(crate) fn take_rolls(&mut self) -> HashMap<String, Vec<LoggedRoll>> {
+        std::mem::take(&mut self.rolls)
+    }
+}
+
+fn put_entry<V>(scopes: &mut Scopes<V>, scope: &str, name: &str, value: Option<V>) {
+    match value {
+        Some(value) => {
+            scopes
+                .entry(scope.to_string())
+                .or_default()
+                .insert(name.to_string(), value);
+        }
+        None => {
+            if let Some(entries) = scopes.get_mut(scope) {
+                entries.remove(name);
+            }
         }
     }
 }
 
+// Moves the entries under one scope to another, keeping any the target already
+// has, and returns how many were moved.
+fn move_entries<V>(scopes: &mut Scopes<V>, from: &str, to: &str) -> u64 {
+    if from == to {
+        return 0;
+    }
+    let Some(entries) = scopes.remove(from) else {
+        return 0;
+    };
+    let target = scopes.entry(to.to_string()).or_default();
+    let mut moved = 0;
+    for (name, value) in entries {
+        if let Entry::Vacant(entry) = target.entry(name) {
+            entry.insert(value);
+            moved += 1;
+        }
+    }
+    moved
+}
+
+fn format_values(values: &HashMap<String, Variable>) -> String {
+    format_variables(
+        values
+            .iter()
+            .map(|(name, variable)| (name, &variable.expression, variable.source.as_ref())),
+    )
+}
+
 impl Environment for HashMapEnvironment {
     async fn get<C: Context>(&self, ctx: C, var_name: &str) -> Option<Expression> {
         Some(
             self.env
-                .get(&ctx.user_context_key())
-                .unwrap()
+                .get(&ctx.user_context_key())?
                 .get(var_name)?
+                .expression
                 .clone(),
         )
     }
 
-    async fn set<C: Context>(&mut self, ctx: C, var_name: &str, result: &Expression) {
-        if let Some(user_map) = self.env.get_mut(&ctx.user_context_key()) {
-            user_map.insert(var_name.to_string(), result.clone());
-        } else {
-            let mut new_user_map = HashMap::new();
-            new_user_map.insert(var_name.to_string(), result.clone());
-            self.env
-                .insert(ctx.user_context_key().to_string(), new_user_map);
-        }
+    async fn set<C: Context>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        result: &Expression,
+    ) -> Result<(), RollerError> {
+        let variable = Variable {
+            expression: result.clone(),
+            source: None,
+        };
+        self.insert(ctx, var_name, variable);
+        Ok(())
+    }
+
+    async fn set_with_source<C: Context>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        result: &Expression,
+        source: &str,
+    ) -> Result<(), RollerError> {
+        let variable = Variable {
+            expression: result.clone(),
+            source: Some(source.to_string()),
+        };
+        self.insert(ctx, var_name, variable);
+        Ok(())
+    }
+
+    async fn source<C: Context>(&self, ctx: C, var_name: &str) -> Option<String> {
+        self.env
+            .get(&ctx.user_context_key())?
+            .get(var_name)?
+            .source
+            .clone()
     }
 
     async fn print<C: Context>(&self, ctx: C) -> String {
-        format!("{:?}", self.env.get(&ctx.user_context_key()).unwrap())
+        match self.env.get(&ctx.user_context_key()) {
+            Some(user_map) => format_values(user_map),
+            None => String::new(),
+        }
     }
 
-    async fn closure<C: Context>(&self, ctx: C) -> Result<HashMap<String, Expression>, ()> {
+    async fn closure<C: Context>(
+        &self,
+        ctx: C,
+    ) -> Result<HashMap<String, Expression>, RollerError> {
         match self.env.get(&ctx.user_context_key()) {
-            Some(map) => Ok(map.clone()),
+            Some(map) => Ok(map
+                .iter()
+                .map(|(name, variable)| (name.clone(), variable.expression.clone()))
+                .collect()),
             None => Ok(HashMap::new()),
         }
     }
+
+    async fn closure_with_sources<C: Context + Copy>(
+        &self,
+        ctx: C,
+    ) -> Result<HashMap<String, (Expression, Option<String>)>, RollerError> {
+        match self.env.get(&ctx.user_context_key()) {
+            Some(map) => Ok(map
+                .iter()
+                .map(|(name, variable)| {
+                    let variable = variable.clone();
+                    (name.clone(), (variable.expression, variable.source))
+                })
+                .collect()),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    async fn state<C: Context>(&self, ctx: C, name: &str) -> Result<Option<Value>, RollerError> {
+        Ok(self
+            .state
+            .get(&ctx.user_context_key())
+            .and_then(|state| state.get(name))
+            .cloned())
+    }
+
+    async fn set_state<C: Context>(
+        &mut self,
+        ctx: C,
+        name: &str,
+        value: &Value,
+    ) -> Result<(), RollerError> {
+        self.state
+            .entry(ctx.user_context_key())
+            .or_default()
+            .insert(name.to_string(), value.clone());
+        Ok(())
+    }
+
+    async fn move_scope<F: Context, T: Context>(
+        &mut self,
+        from: F,
+        to: T,
+    ) -> Result<u64, RollerError> {
+        let (from, to) = (from.user_context_key(), to.user_context_key());
+        Ok(move_entries(&mut self.env, &from, &to) + move_entries(&mut self.state, &from, &to))
+    }
+
+    async fn log_roll<C: Context>(&mut self, ctx: C, roll: &LoggedRoll) -> Result<(), RollerError> {
+        self.log_roll_in(&ctx.user_context_key(), roll.clone());
+        Ok(())
+    }
+
+    async fn rolls<C: Context>(
+        &self,
+        ctx: C,
+        since: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<LoggedRoll>, RollerError> {
+        let Some(rolls) = self.rolls.get(&ctx.user_context_key()) else {
+            return Ok(Vec::new());
+        };
+        let rolls: Vec<_> = rolls
+            .iter()
+            .filter(|roll| roll.timestamp >= since)
+            .cloned()
+            .collect();
+        let skip = limit.map_or(0, |limit| rolls.len().saturating_sub(limit));
+        Ok(rolls.into_iter().skip(skip).collect())
+    }
 }
 
 impl Display for HashMapEnvironment {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self.env)
+        let mut scopes: Vec<_> = self.env.iter().collect();
+        scopes.sort_by_key(|(scope, _)| *scope);
+        for (scope, values) in scopes {
+            writeln!(f, "{}:", scope)?;
+            writeln!(f, "{}", format_values(values))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repl::REPLContext;
+
+    struct TestCtx;
+
+    impl Context for &TestCtx {
+        fn user_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", "test", "test_user")
+        }
+
+        fn global_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", "test", "global")
+        }
+
+        fn scope_context(&self, scope: &str) -> REPLContext {
+            REPLContext::new(scope.to_string(), "test_user".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_source_hash_map() {
+        let ctx = &TestCtx;
+        let template = Expression::DiceRollTemplate {
+            args: vec!["m".to_string()],
+            expressions: vec![Expression::Variable("m".to_string())],
+        };
+        let mut env = HashMapEnvironment::new();
+        env.set_with_source(ctx, "attack", &template, "(m) => ({m})")
+            .await
+            .unwrap();
+        env.set(ctx, "bonus", &Expression::Integer(2))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            env.source(ctx, "attack").await,
+            Some("(m) => ({m})".to_string())
+        );
+        assert_eq!(env.print(ctx).await, "attack => (m) => ({m})\nbonus => 2");
+
+        // Environments saved before source text was kept load without it.
+        let legacy: HashMapEnvironment = serde_json::from_str(
+            r#"{"scope": {"a": {"expression_type": "integer", "expression": 1}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            legacy.env["scope"]["a"],
+            Variable {
+                expression: Expression::Integer(1),
+                source: None,
+            }
+        );
+
+        env.set(ctx, "attack", &template).await.unwrap();
+        assert_eq!(env.source(ctx, "attack").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_move_scope() {
+        let by_name = &REPLContext::new("channel".to_string(), "ann".to_string());
+        let by_id = &REPLContext::new("channel".to_string(), "1234".to_string());
+        let mut env = HashMapEnvironment::new();
+        env.set(by_name, "str", &Expression::Integer(3))
+            .await
+            .unwrap();
+        env.set(by_name, "dex", &Expression::Integer(2))
+            .await
+            .unwrap();
+        env.set(by_id, "dex", &Expression::Integer(4))
+            .await
+            .unwrap();
+
+        // Values already set under the new scope win.
+        assert_eq!(env.move_scope(by_name, by_id).await.unwrap(), 1);
+        assert_eq!(env.get(by_id, "str").await, Some(Expression::Integer(3)));
+        assert_eq!(env.get(by_id, "dex").await, Some(Expression::Integer(4)));
+        assert_eq!(env.get(by_name, "str").await, None);
+        assert_eq!(env.move_scope(by_name, by_id).await.unwrap(), 0);
     }
 }
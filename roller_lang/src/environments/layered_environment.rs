@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+
+use crate::error::RollerError;
+use crate::roll_log::LoggedRoll;
+use crate::types::{Context, Environment, Expression, VariableVersion};
+
+type Cache = Arc<Mutex<HashMap<(String, String), Option<Expression>>>>;
+
+// An environment of template argument bindings layered over a parent
+// environment. Lookups that miss the bindings fall through to the parent and
+// are cached, so a template body only fetches the variables it actually uses.
+pub struct LayeredEnvironment<'a, E: Environment> {
+    parent: &'a E,
+    bindings: HashMap<String, Expression>,
+    cache: Cache,
+}
+
+impl<'a, E: Environment> LayeredEnvironment<'a, E> {
+    pub fn new(parent: &'a E) -> Self {
+        LayeredEnvironment {
+            parent,
+            bindings: HashMap::new(),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Adds a layer of bindings on top of this one. The new layer shares this
+    // layer's parent and lookup cache.
+    pub fn layer(&self, bindings: HashMap<String, Expression>) -> Self {
+        let mut layered = self.bindings.clone();
+        layered.extend(bindings);
+        LayeredEnvironment {
+            parent: self.parent,
+            bindings: layered,
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl<'a, E: Environment + Sync> Environment for LayeredEnvironment<'a, E> {
+    async fn get<C: Context + Send>(&self, ctx: C, var_name: &str) -> Option<Expression> {
+        self.try_get(ctx, var_name).await.ok().flatten()
+    }
+
+    async fn try_get<C: Context + Send>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Option<Expression>, RollerError> {
+        if let Some(value) = self.bindings.get(var_name) {
+            return Ok(Some(value.clone()));
+        }
+
+        let key = (ctx.user_context_key(), var_name.to_string());
+        let cached = self.cache.lock().unwrap().get(&key).cloned();
+        match cached {
+            Some(value) => Ok(value),
+            None => {
+                let home = ctx.home_context();
+                let mut value = self.parent.try_get(ctx, var_name).await?;
+                if let (None, Some(home)) = (&value, home) {
+                    value = self.parent.try_get(&home, var_name).await?;
+                }
+                self.cache.lock().unwrap().insert(key, value.clone());
+                Ok(value)
+            }
+        }
+    }
+
+    async fn set<C: Context + Send>(
+        &mut self,
+        _ctx: C,
+        var_name: &str,
+        result: &Expression,
+    ) -> Result<(), RollerError> {
+        self.bindings.insert(var_name.to_string(), result.clone());
+        Ok(())
+    }
+
+    async fn print<C: Context + Send>(&self, ctx: C) -> String {
+        self.parent.print(ctx).await
+    }
+
+    async fn history<C: Context + Send>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Vec<VariableVersion>, RollerError> {
+        self.parent.history(ctx, var_name).await
+    }
+
+    async fn state<C: Context + Send>(
+        &self,
+        ctx: C,
+        name: &str,
+    ) -> Result<Option<Value>, RollerError> {
+        self.parent.state(ctx, name).await
+    }
+
+    async fn versioned_state<C: Context + Send>(
+        &self,
+        ctx: C,
+        name: &str,
+    ) -> Result<(Option<Value>, u64), RollerError> {
+        self.parent.versioned_state(ctx, name).await
+    }
+
+    async fn rolls<C: Context + Send>(
+        &self,
+        ctx: C,
+        since: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<LoggedRoll>, RollerError> {
+        self.parent.rolls(ctx, since, limit).await
+    }
+
+    async fn closure<C: Context + Send>(
+        &self,
+        ctx: C,
+    ) -> Result<HashMap<String, Expression>, RollerError> {
+        let mut closure = self.parent.closure(ctx).await?;
+        closure.extend(self.bindings.clone());
+        Ok(closure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environments::hash_map_environment::HashMapEnvironment;
+    use crate::repl::REPLContext;
+
+    struct TestCtx;
+
+    impl Context for &TestCtx {
+        fn user_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", "test", "test_user")
+        }
+
+        fn global_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", "test", "global")
+        }
+
+        fn scope_context(&self, scope: &str) -> REPLContext {
+            REPLContext::new(scope.to_string(), "test_user".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_layered_lookup() {
+        let ctx = &TestCtx;
+        let mut parent = HashMapEnvironment::new();
+        parent.set(ctx, "a", &Expression::Integer(1)).await.unwrap();
+        parent.set(ctx, "b", &Expression::Integer(2)).await.unwrap();
+
+        let env = LayeredEnvironment::new(&parent);
+        let outer = env.layer(HashMap::from([("b".to_string(), Expression::Integer(3))]));
+        let inner = outer.layer(HashMap::from([("c".to_string(), Expression::Integer(4))]));
+
+        assert_eq!(inner.get(ctx, "a").await, Some(Expression::Integer(1)));
+        assert_eq!(inner.get(ctx, "b").await, Some(Expression::Integer(3)));
+        assert_eq!(inner.get(ctx, "c").await, Some(Expression::Integer(4)));
+        assert_eq!(outer.get(ctx, "c").await, None);
+        assert_eq!(env.get(ctx, "b").await, Some(Expression::Integer(2)));
+        assert_eq!(env.cache.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_home_scope_lookup() {
+        let ctx = &REPLContext::new("channel".to_string(), "user".to_string()).with_home_scope();
+        let home = &ctx.scope_context("home");
+        let mut parent = HashMapEnvironment::new();
+        parent
+            .set(home, "a", &Expression::Integer(1))
+            .await
+            .unwrap();
+        parent
+            .set(home, "b", &Expression::Integer(2))
+            .await
+            .unwrap();
+        parent.set(ctx, "b", &Expression::Integer(3)).await.unwrap();
+
+        let env = LayeredEnvironment::new(&parent);
+        assert_eq!(env.get(ctx, "a").await, Some(Expression::Integer(1)));
+        assert_eq!(env.get(ctx, "b").await, Some(Expression::Integer(3)));
+
+        let without_home = &REPLContext::new("channel".to_string(), "user".to_string());
+        let env = LayeredEnvironment::new(&parent);
+        assert_eq!(env.get(without_home, "a").await, None);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::environments::format_variables;
+use crate::error::RollerError;
+use crate::roll_log::LoggedRoll;
+use crate::sqlite::SqliteClient;
+use crate::types::{Context, Environment, Expression, SharedEnvironment, VariableVersion};
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Clone)]
+pub struct SqliteEnvironment {
+    client: SqliteClient,
+}
+
+impl SqliteEnvironment {
+    pub fn new(client: SqliteClient) -> Self {
+        SqliteEnvironment { client }
+    }
+
+    // rusqlite blocks, so queries run on tokio's blocking pool instead of
+    // holding up a runtime worker while they wait for the connection or disk.
+    async fn blocking<T, F>(&self, query: F) -> Result<T, rusqlite::Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&SqliteClient) -> Result<T, rusqlite::Error> + Send + 'static,
+    {
+        let client = self.client.clone();
+        match tokio::task::spawn_blocking(move || query(&client)).await {
+            Ok(result) => result,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+}
+
+impl SharedEnvironment for SqliteEnvironment {}
+
+impl Environment for SqliteEnvironment {
+    async fn get<C: Context + Send>(&self, ctx: C, var_name: &str) -> Option<Expression> {
+        self.try_get(ctx, var_name).await.ok().flatten()
+    }
+
+    async fn try_get<C: Context>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Option<Expression>, RollerError> {
+        let (scope, name) = (ctx.user_context_key(), var_name.to_string());
+        self.blocking(move |client| client.get_expression(&scope, &name))
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!("failed to read {}: {}", var_name, err))
+            })
+    }
+
+    async fn set<C: Context>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        result: &Expression,
+    ) -> Result<(), RollerError> {
+        let (scope, name, expr) = (ctx.user_context_key(), var_name.to_string(), result.clone());
+        self.blocking(move |client| client.set_expression(&scope, &name, &expr, None))
+            .await
+            .map(|_| ())
+            .map_err(|err| {
+                RollerError::StorageError(format!("failed to save {}: {}", var_name, err))
+            })
+    }
+
+    async fn set_with_source<C: Context>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        result: &Expression,
+        source: &str,
+    ) -> Result<(), RollerError> {
+        let (scope, name, expr) = (ctx.user_context_key(), var_name.to_string(), result.clone());
+        let source = source.to_string();
+        self.blocking(move |client| client.set_expression(&scope, &name, &expr, Some(&source)))
+            .await
+            .map(|_| ())
+            .map_err(|err| {
+                RollerError::StorageError(format!("failed to save {}: {}", var_name, err))
+            })
+    }
+
+    async fn source<C: Context + Send>(&self, ctx: C, var_name: &str) -> Option<String> {
+        self.try_source(ctx, var_name).await.ok().flatten()
+    }
+
+    async fn try_source<C: Context>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Option<String>, RollerError> {
+        let (scope, name) = (ctx.user_context_key(), var_name.to_string());
+        self.blocking(move |client| client.get_source(&scope, &name))
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!("failed to read {}: {}", var_name, err))
+            })
+    }
+
+    async fn print<C: Context>(&self, ctx: C) -> String {
+        let scope = ctx.user_context_key();
+        match self
+            .blocking(move |client| client.get_all_with_sources_in_scope(&scope))
+            .await
+        {
+            Ok(variables) => format_variables(
+                variables
+                    .iter()
+                    .map(|(name, (expr, source))| (name, expr, source.as_ref())),
+            ),
+            Err(err) => format!("failed to list variables: {}", err),
+        }
+    }
+
+    async fn history<C: Context>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Vec<VariableVersion>, RollerError> {
+        let (scope, name) = (ctx.user_context_key(), var_name.to_string());
+        self.blocking(move |client| client.get_history(&scope, &name))
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!("failed to read {}'s history: {}", var_name, err))
+            })
+    }
+
+    async fn version<C: Context>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> Result<Option<u64>, RollerError> {
+        let (scope, name) = (ctx.user_context_key(), var_name.to_string());
+        self.blocking(move |client| client.get_version(&scope, &name))
+            .await
+            .map(Some)
+            .map_err(|err| {
+                RollerError::StorageError(format!("failed to read {}: {}", var_name, err))
+            })
+    }
+
+    async fn set_version<C: Context>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        value: &Expression,
+        source: Option<&str>,
+        expected_version: u64,
+    ) -> Result<(), RollerError> {
+        let (scope, name, expr) = (ctx.user_context_key(), var_name.to_string(), value.clone());
+        let source = source.map(str::to_string);
+        let saved = self
+            .blocking(move |client| {
+                client.set_expression_version(
+                    &scope,
+                    &name,
+                    &expr,
+                    source.as_deref(),
+                    expected_version,
+                )
+            })
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!("failed to save {}: {}", var_name, err))
+            })?;
+        match saved {
+            Some(_) => Ok(()),
+            None => Err(RollerError::ConflictError(format!(
+                "{} was changed by someone else",
+                var_name
+            ))),
+        }
+    }
+
+    async fn closure<C: Context>(
+        &self,
+        ctx: C,
+    ) -> Result<HashMap<String, Expression>, RollerError> {
+        let scope = ctx.user_context_key();
+        self.blocking(move |client| client.get_all_in_scope(&scope))
+            .await
+            .map_err(|err| RollerError::StorageError(format!("failed to read variables: {}", err)))
+    }
+
+    async fn closure_with_sources<C: Context + Copy + Send>(
+        &self,
+        ctx: C,
+    ) -> Result<HashMap<String, (Expression, Option<String>)>, RollerError> {
+        let scope = ctx.user_context_key();
+        self.blocking(move |client| client.get_all_with_sources_in_scope(&scope))
+            .await
+            .map_err(|err| RollerError::StorageError(format!("failed to read variables: {}", err)))
+    }
+
+    async fn state<C: Context>(&self, ctx: C, name: &str) -> Result<Option<Value>, RollerError> {
+        let (scope, state_name) = (ctx.user_context_key(), name.to_string());
+        self.blocking(move |client| client.get_state(&scope, &state_name))
+            .await
+            .map_err(|err| RollerError::StorageError(format!("failed to read {}: {}", name, err)))
+    }
+
+    async fn versioned_state<C: Context>(
+        &self,
+        ctx: C,
+        name: &str,
+    ) -> Result<(Option<Value>, u64), RollerError> {
+        let (scope, state_name) = (ctx.user_context_key(), name.to_string());
+        self.blocking(move |client| client.get_state_version(&scope, &state_name))
+            .await
+            .map_err(|err| RollerError::StorageError(format!("failed to read {}: {}", name, err)))
+    }
+
+    async fn set_state_version<C: Context>(
+        &mut self,
+        ctx: C,
+        name: &str,
+        value: &Value,
+        expected_version: u64,
+    ) -> Result<(), RollerError> {
+        let (scope, state_name, value) = (ctx.user_context_key(), name.to_string(), value.clone());
+        let saved = self
+            .blocking(move |client| {
+                client.set_state_version(&scope, &state_name, &value, expected_version)
+            })
+            .await
+            .map_err(|err| {
+                RollerError::StorageError(format!("failed to save {}: {}", name, err))
+            })?;
+        if saved {
+            Ok(())
+        } else {
+            Err(RollerError::ConflictError(format!(
+                "{} was changed by someone else",
+                name
+            )))
+        }
+    }
+
+    async fn log_roll<C: Context>(&mut self, ctx: C, roll: &LoggedRoll) -> Result<(), RollerError> {
+        let (scope, roll) = (ctx.user_context_key(), roll.clone());
+        self.blocking(move |client| client.log_roll(&scope, &roll))
+            .await
+            .map_err(|err| RollerError::StorageError(format!("failed to log roll: {}", err)))
+    }
+
+    async fn rolls<C: Context>(
+        &self,
+        ctx: C,
+        since: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<LoggedRoll>, RollerError> {
+        let scope = ctx.user_context_key();
+        self.blocking(move |client| client.get_rolls(&scope, since, limit))
+            .await
+            .map_err(|err| RollerError::StorageError(format!("failed to read roll log: {}", err)))
+    }
+
+    async fn move_scope<F: Context, T: Context>(
+        &mut self,
+        from: F,
+        to: T,
+    ) -> Result<u64, RollerError> {
+        let (from, to) = (from.user_context_key(), to.user_context_key());
+        self.blocking(move |client| client.move_scope(&from, &to))
+            .await
+            .map_err(|err| RollerError::StorageError(format!("failed to move variables: {}", err)))
+    }
+
+    async fn set_state<C: Context>(
+        &mut self,
+        ctx: C,
+        name: &str,
+        value: &Value,
+    ) -> Result<(), RollerError> {
+        let (scope, state_name, value) = (ctx.user_context_key(), name.to_string(), value.clone());
+        self.blocking(move |client| client.set_state(&scope, &state_name, &value))
+            .await
+            .map_err(|err| RollerError::StorageError(format!("failed to save {}: {}", name, err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deck::Deck;
+    use crate::eval::EvalVisitor;
+    use crate::parser::StatementParser;
+    use crate::repl::REPLContext;
+    use crate::types::{Parser, Visitor};
+    use rand::rngs::mock::StepRng;
+
+    struct TestCtx;
+
+    impl Context for &TestCtx {
+        fn user_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", "test", "test_user")
+        }
+
+        fn global_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", "test", "global")
+        }
+
+        fn scope_context(&self, scope: &str) -> REPLContext {
+            REPLContext::new(scope.to_string(), "test_user".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_read_sqlite() {
+        let mut env = SqliteEnvironment::new(
+            SqliteClient::open_in_memory().expect("failed to create client"),
+        );
+        let ctx = &TestCtx;
+        env.set(ctx, "test_value", &Expression::Integer(1))
+            .await
+            .unwrap();
+        env.set(ctx, "test_value", &Expression::Integer(2))
+            .await
+            .unwrap();
+        assert_eq!(
+            env.get(ctx, "test_value").await.unwrap(),
+            Expression::Integer(2)
+        );
+        assert_eq!(env.get(ctx, "missing").await, None);
+        assert_eq!(
+            env.closure(ctx).await.unwrap(),
+            HashMap::from([("test_value".to_string(), Expression::Integer(2))])
+        );
+
+        env.set_with_source(ctx, "attack", &Expression::Integer(3), "1 + 2")
+            .await
+            .unwrap();
+        assert_eq!(env.source(ctx, "attack").await, Some("1 + 2".to_string()));
+        assert_eq!(env.print(ctx).await, "attack => 1 + 2\ntest_value => 2");
+        let history = env.history(ctx, "test_value").await.unwrap();
+        assert_eq!(
+            history
+                .iter()
+                .map(|version| (version.version, version.expression.clone()))
+                .collect::<Vec<_>>(),
+            vec![(2, Expression::Integer(2)), (1, Expression::Integer(1))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_failure_sqlite() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("dice-roller.sqlite3");
+        let mut env =
+            SqliteEnvironment::new(SqliteClient::open(&path).expect("failed to create client"));
+        let ctx = &TestCtx;
+        env.set(ctx, "bonus", &Expression::Integer(1))
+            .await
+            .unwrap();
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute("UPDATE variables SET expression = '{'", [])
+            .unwrap();
+
+        // A value that can't be read is an error, not a missing variable.
+        assert!(env.try_get(ctx, "bonus").await.is_err());
+        assert_eq!(env.try_get(ctx, "missing").await.unwrap(), None);
+        assert!(env.closure(ctx).await.is_err());
+        let err = EvalVisitor::new(&mut StepRng::new(0, 1), &mut env, ctx)
+            .visit_statement(&StatementParser.parse("!roll {bonus}").unwrap())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RollerError::StorageError(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_stale_writes_sqlite() {
+        let mut env = SqliteEnvironment::new(
+            SqliteClient::open_in_memory().expect("failed to create client"),
+        );
+        let ctx = &TestCtx;
+        let mut rng = StepRng::new(0, 1);
+        let mut run = async |env: &mut SqliteEnvironment, input: &str| {
+            EvalVisitor::new(&mut rng, env, ctx)
+                .visit_statement(&StatementParser.parse(input).unwrap())
+                .await
+                .map(|output| output.to_string())
+        };
+
+        run(&mut env, "!set bonus 1").await.unwrap();
+        let edit = run(&mut env, "!edit bonus").await.unwrap();
+        assert_eq!(edit, "!set@1 bonus 1");
+        // Someone else saves bonus while it's being edited, so the edit is
+        // turned down rather than undoing their change.
+        run(&mut env, "!set bonus 2").await.unwrap();
+        let err = run(&mut env, "!set@1 bonus 3").await.unwrap_err();
+        assert!(matches!(err, RollerError::EvalError(_)), "{}", err);
+        assert_eq!(env.get(ctx, "bonus").await.unwrap(), Expression::Integer(2));
+        assert_eq!(
+            run(&mut env, "!edit bonus").await.unwrap(),
+            "!set@2 bonus 2"
+        );
+        run(&mut env, "!set@2 bonus 3").await.unwrap();
+
+        // Of two saves of the same deck, the one based on the older read loses.
+        run(&mut env, "!deck new cards standard").await.unwrap();
+        let mut first = Deck::load(&env, ctx, "cards").await.unwrap();
+        let mut second = Deck::load(&env, ctx, "cards").await.unwrap();
+        first.draw(1).unwrap();
+        first.save(&mut env, ctx, "cards").await.unwrap();
+        second.draw(2).unwrap();
+        let err = second.save(&mut env, ctx, "cards").await.unwrap_err();
+        assert!(matches!(err, RollerError::ConflictError(_)), "{}", err);
+        assert_eq!(Deck::load(&env, ctx, "cards").await.unwrap().drawn.len(), 1);
+    }
+}
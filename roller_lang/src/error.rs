@@ -4,13 +4,20 @@ use std::{error::Error, fmt};
 pub enum RollerError {
     EvalError(String),
     ParserError(String),
+    StorageError(String),
+    // A conditional write lost to someone else's, so what it was based on is out
+    // of date.
+    ConflictError(String),
     OtherError,
 }
 
 impl fmt::Display for RollerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            RollerError::EvalError(msg) | RollerError::ParserError(msg) => write!(f, "{}", msg),
+            RollerError::EvalError(msg)
+            | RollerError::ParserError(msg)
+            | RollerError::StorageError(msg)
+            | RollerError::ConflictError(msg) => write!(f, "{}", msg),
             RollerError::OtherError => write!(f, "an unknown error"),
         }
     }
@@ -1,15 +1,36 @@
 use rand::distributions::Uniform;
 use rand::Rng;
 use rust_i18n::t;
-use std::convert::{TryFrom, TryInto};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 
 use crate::{
     call_stack::{Control, ControlStack},
-    environments::hash_map_environment::HashMapEnvironment,
+    character::{Characters, FieldKind, CHAR_PREFIX},
+    config::Config,
+    deck::{cards, Deck},
+    dynamodb::backoff,
+    environments::layered_environment::LayeredEnvironment,
     error::RollerError,
-    types::{Context, Environment, Expression, Op, Statement, Visitor},
+    export::{export, Format},
+    initiative::{Combatant, Initiative},
+    parser::parse_expression,
+    repl::{REPLContext, HOME_SCOPE},
+    roll_log::{LogFormat, LoggedRoll, RollLog, DEFAULT_LOG_NAME},
+    state,
+    stats::Stats,
+    tables::{Part, Table, TABLE_PREFIX},
+    types::{
+        Context, DieRoll, Environment, Expression, Global, LuckOf, Op, Output, RollResult,
+        ScopeRef, Statement, Visitor,
+    },
 };
 
+const GMS_STATE: &str = "gms";
+// How many times a statement is run before giving up on saving its state when
+// others keep saving it first.
+const MAX_STATE_ATTEMPTS: u32 = 10;
+
 impl TryFrom<Expression> for i64 {
     type Error = RollerError;
 
@@ -28,7 +49,8 @@ impl TryFrom<Expression> for usize {
 
     fn try_from(value: Expression) -> Result<usize, Self::Error> {
         match value {
-            Expression::Integer(value) => Ok(usize::try_from(value).unwrap()),
+            Expression::Integer(value) => usize::try_from(value)
+                .map_err(|_| RollerError::EvalError(format!("expected 0 or more, got {}", value))),
             _ => Err(RollerError::EvalError(
                 "can't convert non-integer exprsession to usize".to_string(),
             )),
@@ -36,14 +58,43 @@ impl TryFrom<Expression> for usize {
     }
 }
 
+// Rolls count dice, as long as they fit in the server's limits given the
+// rolled dice already rolled for the same statement.
 fn handle_roll(
     rng: &mut impl Rng,
     count: Expression,
     sides: Expression,
-) -> Result<i64, RollerError> {
-    let die = Uniform::new_inclusive(1, i64::try_from(sides)?);
+    config: &Config,
+    rolled: usize,
+) -> Result<DieRoll, RollerError> {
+    let sides = i64::try_from(sides)?;
+    if sides < 1 {
+        return Err(RollerError::EvalError(format!(
+            "dice need at least 1 side, not {}",
+            sides
+        )));
+    }
+    if sides > config.max_sides {
+        return Err(RollerError::EvalError(format!(
+            "dice can't have more than {} sides",
+            config.max_sides
+        )));
+    }
+    let count = i64::try_from(count)?;
+    let count = usize::try_from(count)
+        .map_err(|_| RollerError::EvalError(format!("can't roll {} dice", count)))?;
+    if rolled + count > config.max_dice {
+        return Err(RollerError::EvalError(format!(
+            "can't roll more than {} dice at once",
+            config.max_dice
+        )));
+    }
+    let die = Uniform::new_inclusive(1, sides);
 
-    Ok(rng.sample_iter(&die).take(count.try_into()?).sum())
+    Ok(DieRoll {
+        sides,
+        results: rng.sample_iter(&die).take(count).collect(),
+    })
 }
 
 fn handle_op(left: Expression, right: Expression, op: Op) -> Result<i64, RollerError> {
@@ -53,148 +104,686 @@ fn handle_op(left: Expression, right: Expression, op: Op) -> Result<i64, RollerE
     }
 }
 
-pub struct EvalVisitor<'a, T: Rng + ?Sized, E: Environment, C: Context> {
-    rng: &'a mut T,
-    env: &'a mut E,
+// Rolls on the table called name, returning the roll and its entry with the
+// dice and tables it refers to filled in. Each roll on a table is kept as a die
+// in dice, so it counts towards the server's limit like any other.
+async fn roll_table<T: Rng, E: Environment + Sync, C: Context + Copy + Send>(
+    rng: &mut T,
+    env: &LayeredEnvironment<'_, E>,
     ctx: C,
-}
+    dice: &mut Vec<DieRoll>,
+    name: &str,
+) -> Result<(i64, String), RollerError> {
+    let table = Table::load(env, ctx, name).await?;
+    let size = Expression::Integer(table.size());
+    let rolled = dice.iter().map(|die| die.results.len()).sum();
+    let roll = handle_roll(rng, Expression::Integer(1), size, &ctx.config(), rolled)?;
+    let result = roll.results.iter().sum();
+    dice.push(roll);
 
-impl<'a, T: Rng, E: Environment, C: Context> EvalVisitor<'a, T, E, C> {
-    pub fn new(rng: &'a mut T, env: &'a mut E, ctx: C) -> Self {
-        EvalVisitor { rng, env, ctx }
+    let entry = table
+        .entry(result)
+        .ok_or_else(|| RollerError::EvalError(format!("{} has no entry for {}", name, result)))?;
+    let mut text = String::new();
+    for part in entry.parts() {
+        match part {
+            Part::Text(part) => text.push_str(part),
+            Part::Roll(expr) => {
+                let expr = parse_expression(expr)?;
+                let total = Box::pin(evaluate(rng, env, ctx, dice, &expr)).await?;
+                text.push_str(&i64::try_from(total)?.to_string());
+            }
+            Part::Table(table) => {
+                let (_, rolled) = Box::pin(roll_table(rng, env, ctx, dice, table)).await?;
+                text.push_str(&rolled);
+            }
+        }
     }
+    Ok((result, text))
 }
 
-impl<'a, T: Rng, E: Environment + Clone, C: Context + Copy + Send>
-    Visitor<Result<String, RollerError>, Result<Expression, RollerError>>
-    for EvalVisitor<'a, T, E, C>
-{
-    async fn visit_expression(&mut self, expr: &Expression) -> Result<Expression, RollerError> {
-        let mut stack = ControlStack::new(expr.clone());
-
-        while stack.size_call() > 0 {
-            match stack.peek_call()? {
-                Expression::Term(left_expr, right_expr, _)
-                | Expression::DiceRoll {
-                    count: left_expr,
-                    sides: right_expr,
-                } => match stack.push_to_call_stack(&[*left_expr, *right_expr]) {
+// Template calls evaluate their body against a new layer of the same
+// LayeredEnvironment, binding the template's arguments over the caller's scope.
+// Every die rolled along the way is appended to dice.
+async fn evaluate<T: Rng, E: Environment + Sync, C: Context + Copy + Send>(
+    rng: &mut T,
+    env: &LayeredEnvironment<'_, E>,
+    ctx: C,
+    dice: &mut Vec<DieRoll>,
+    expr: &Expression,
+) -> Result<Expression, RollerError> {
+    let config = ctx.config();
+    let mut stack = ControlStack::new(expr.clone());
+
+    while stack.size_call() > 0 {
+        match stack.peek_call()? {
+            Expression::Term(left_expr, right_expr, _)
+            | Expression::DiceRoll {
+                count: left_expr,
+                sides: right_expr,
+            } => match stack.push_to_call_stack(&[*left_expr, *right_expr]) {
+                Control::Wait => continue,
+                Control::Continue => (),
+            },
+            Expression::DiceRollTemplateCall {
+                template_expression,
+                args,
+            } => {
+                let mut calls = vec![*template_expression];
+                for arg in args {
+                    calls.push(arg)
+                }
+                match stack.push_to_call_stack(calls.as_slice()) {
                     Control::Wait => continue,
                     Control::Continue => (),
-                },
-                Expression::DiceRollTemplateCall {
-                    template_expression,
-                    args,
-                } => {
-                    let mut calls = vec![*template_expression];
-                    for arg in args {
-                        calls.push(arg)
-                    }
-                    match stack.push_to_call_stack(calls.as_slice()) {
-                        Control::Wait => continue,
-                        Control::Continue => (),
-                    }
                 }
-                _ => (),
             }
+            _ => (),
+        }
+
+        match stack.pop_call()? {
+            expr @ Expression::Integer(_) => {
+                stack.push_return(expr.clone());
+            }
+            Expression::Term(_, _, op) => {
+                let left = stack.pop_return()?;
+                let right = stack.pop_return()?;
+                stack.push_return(Expression::Integer(handle_op(left, right, op.clone())?));
+            }
+            Expression::DiceRoll { count: _, sides: _ } => {
+                let count = stack.pop_return()?;
+                let sides = stack.pop_return()?;
 
-            match stack.pop_call()? {
-                expr @ Expression::Integer(_) => {
-                    stack.push_return(expr.clone());
+                let rolled = dice.iter().map(|die| die.results.len()).sum();
+                let roll = handle_roll(rng, count, sides, &config, rolled)?;
+                stack.push_return(Expression::Integer(roll.results.iter().sum()));
+                dice.push(roll);
+            }
+            // Fields of the character being played. Templates are called like
+            // any other; everything else is worked out on each use.
+            Expression::Variable(variable_name) if variable_name.starts_with(CHAR_PREFIX) => {
+                let field = &variable_name[CHAR_PREFIX.len()..];
+                match Characters::load(env, ctx).await?.active()?.field(field)? {
+                    template @ Expression::DiceRollTemplate { .. } => stack.push_return(template),
+                    expr => {
+                        stack.push_return(Box::pin(evaluate(rng, env, ctx, dice, &expr)).await?)
+                    }
+                }
+            }
+            // Tables rolled in an expression have to land on a dice
+            // expression, which is rolled in turn.
+            Expression::Variable(variable_name) if variable_name.starts_with(TABLE_PREFIX) => {
+                let name = &variable_name[TABLE_PREFIX.len()..];
+                let (_, text) = Box::pin(roll_table(rng, env, ctx, dice, name)).await?;
+                let expr = parse_expression(text.trim()).map_err(|_| {
+                    RollerError::EvalError(format!(
+                        "{} rolled {}, which can't be added up",
+                        name, text
+                    ))
+                })?;
+                stack.push_return(Box::pin(evaluate(rng, env, ctx, dice, &expr)).await?)
+            }
+            Expression::Variable(variable_name) => match env.try_get(ctx, &variable_name).await? {
+                Some(env_expr) => {
+                    stack.push_return(env_expr);
                 }
-                Expression::Term(_, _, op) => {
-                    let left = stack.pop_return()?;
-                    let right = stack.pop_return()?;
-                    stack.push_return(Expression::Integer(handle_op(left, right, op.clone())?));
+                None => {
+                    return Err(RollerError::EvalError(format!(
+                        "failed to lookup variable {}",
+                        variable_name
+                    )))
                 }
-                Expression::DiceRoll { count: _, sides: _ } => {
-                    let count = stack.pop_return()?;
-                    let sides = stack.pop_return()?;
+            },
+            expr @ Expression::DiceRollTemplate {
+                args: _,
+                expressions: _,
+            } => {
+                stack.push_return(expr.clone());
+            }
+            Expression::DiceRollTemplateCall {
+                template_expression: _,
+                args: _,
+            } => match stack.pop_return() {
+                Ok(Expression::DiceRollTemplate {
+                    args: arg_names,
+                    expressions,
+                }) => {
+                    let mut bindings = HashMap::new();
+                    for arg_name in arg_names {
+                        bindings.insert(arg_name, stack.pop_return()?);
+                    }
 
-                    stack.push_return(Expression::Integer(handle_roll(self.rng, count, sides)?));
-                }
-                Expression::Variable(variable_name) => {
-                    match self.env.get(self.ctx, &variable_name).await {
-                        Some(env_expr) => {
-                            stack.push_return(env_expr);
+                    match expressions.as_slice() {
+                        [expr] => {
+                            stack.push_return(
+                                Box::pin(evaluate(rng, &env.layer(bindings), ctx, dice, expr))
+                                    .await?,
+                            );
+                        }
+                        [] => {
+                            return Err(RollerError::EvalError(
+                                "missing body for dice roll template".to_string(),
+                            ))
                         }
-                        None => {
-                            return Err(RollerError::EvalError(format!(
-                                "failed to lookup variable {}",
-                                variable_name
-                            )))
+                        _ => {
+                            return Err(RollerError::EvalError(
+                                "a dice roll template's body must be one expression".to_string(),
+                            ))
                         }
                     }
                 }
-                expr @ Expression::DiceRollTemplate {
-                    args: _,
-                    expressions: _,
-                } => {
-                    stack.push_return(expr.clone());
-                }
-                Expression::DiceRollTemplateCall {
-                    template_expression: _,
-                    args: _,
-                } => match stack.pop_return() {
-                    Ok(Expression::DiceRollTemplate {
-                        args: arg_names,
-                        expressions,
-                    }) => {
-                        let closure = self.env.closure(self.ctx).await?;
-                        let mut new_env =
-                            HashMapEnvironment::from_context_and_initial_values(self.ctx, closure);
-
-                        for arg_name in arg_names {
-                            let arg = stack.pop_return()?;
-                            new_env.set(self.ctx, &arg_name, &arg).await
-                        }
+                _ => return Err(RollerError::EvalError("not callable".to_string())),
+            },
+        }
+    }
 
-                        // For now just support one expression in a template
-                        match expressions.last() {
-                            Some(expr) => {
-                                stack.push_return(
-                                    Box::pin(
-                                        EvalVisitor::new(self.rng, &mut new_env, self.ctx)
-                                            .visit_expression(expr),
-                                    )
-                                    .await?
-                                    .clone(),
-                                );
-                            }
-                            None => {
-                                return Err(RollerError::EvalError(
-                                    "missing body for dice roll template".to_string(),
-                                ))
-                            }
-                        }
-                    }
-                    _ => return Err(RollerError::EvalError("not callable".to_string())),
-                },
-            }
+    match stack.pop_return() {
+        Ok(expr) => Ok(expr),
+        Err(_) => Err(RollerError::EvalError(
+            "evaluation did not produce a result".to_string(),
+        )),
+    }
+}
+
+pub struct EvalVisitor<'a, T: Rng + ?Sized, E: Environment, C: Context> {
+    rng: &'a mut T,
+    env: &'a mut E,
+    ctx: C,
+    // The seed rng started from, if the caller seeded it, for the roll log.
+    seed: Option<u64>,
+    // Whether rolls go in the roll log, or are left for the caller to log.
+    log_rolls: bool,
+}
+
+// The context for a scope named in a command, or None for the current one.
+fn scope_context<C: Context>(ctx: C, scope: &ScopeRef) -> Option<REPLContext> {
+    match scope {
+        ScopeRef::Current => None,
+        ScopeRef::Home => Some(ctx.scope_context(HOME_SCOPE)),
+        ScopeRef::Channel(channel) => Some(ctx.scope_context(channel)),
+    }
+}
+
+impl<'a, T: Rng, E: Environment, C: Context> EvalVisitor<'a, T, E, C> {
+    pub fn new(rng: &'a mut T, env: &'a mut E, ctx: C) -> Self {
+        EvalVisitor {
+            rng,
+            env,
+            ctx,
+            seed: None,
+            log_rolls: true,
         }
+    }
 
-        match stack.pop_return() {
-            Ok(expr) => Ok(expr),
-            Err(_) => Err(RollerError::EvalError(
-                "evaluation did not produce a result".to_string(),
-            )),
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    // Leaves rolls out of the roll log, e.g. when only one of several will be
+    // kept.
+    pub fn without_log(mut self) -> Self {
+        self.log_rolls = false;
+        self
+    }
+}
+
+impl<'a, T: Rng, E: Environment + Sync, C: Context + Copy + Send> EvalVisitor<'a, T, E, C> {
+    async fn roll(&mut self, expr: &Expression) -> Result<RollResult, RollerError> {
+        let mut dice = vec![];
+        let total = evaluate(
+            self.rng,
+            &LayeredEnvironment::new(self.env),
+            self.ctx,
+            &mut dice,
+            expr,
+        )
+        .await?;
+        Ok(RollResult {
+            expression: expr.to_string(),
+            dice,
+            total: i64::try_from(total)?,
+        })
+    }
+
+    // The user ids of the GMs of the current scope, kept in its global scope,
+    // and the version the list was saved as.
+    async fn gms(&self) -> Result<(Vec<u64>, u64), RollerError> {
+        let (gms, version) = state::load(self.env, Global(self.ctx), GMS_STATE, "GM list").await?;
+        Ok((gms.unwrap_or_default(), version))
+    }
+
+    async fn set_field(
+        &mut self,
+        field: &str,
+        kind: FieldKind,
+        expr: &Expression,
+        source: &str,
+    ) -> Result<String, RollerError> {
+        let mut characters = Characters::load(self.env, self.ctx).await?;
+        characters.active_mut()?.set(field, kind, expr, source)?;
+        characters.save(self.env, self.ctx).await?;
+        Ok(format!("char.{} => {}", field, source))
+    }
+
+    // Adds combatant to the channel's initiative order, reporting their roll.
+    async fn add_combatant(&mut self, combatant: Combatant) -> Result<String, RollerError> {
+        let mut initiative = Initiative::load(self.env, self.ctx).await?;
+        let report = format!(
+            "{} rolled {} for initiative",
+            combatant.name, combatant.initiative
+        );
+        initiative.add(combatant);
+        initiative.save(self.env, self.ctx).await?;
+        Ok(report)
+    }
+
+    // Only server admins and the scope's GMs may change who its GMs are.
+    fn check_can_manage_gms(&self, gms: &[u64]) -> Result<(), RollerError> {
+        let is_gm = self
+            .ctx
+            .user_id()
+            .parse::<u64>()
+            .is_ok_and(|id| gms.contains(&id));
+        if self.ctx.is_admin() || is_gm {
+            Ok(())
+        } else {
+            Err(RollerError::EvalError(
+                "only server admins and GMs can change the GMs".to_string(),
+            ))
         }
     }
 
-    async fn visit_statement(&mut self, stmt: &Statement) -> Result<String, RollerError> {
-        match stmt {
-            Statement::Help => Ok(t!("help-general").to_string()),
-            Statement::PrintEnv => Ok(self.env.print(self.ctx).await.to_string()),
-            Statement::Roll(ref expr) => Ok(format!(
-                "{}",
-                i64::try_from(self.visit_expression(expr).await?)?
+    async fn set_gms(&mut self, gms: &[u64], version: u64) -> Result<(), RollerError> {
+        state::save(
+            self.env,
+            Global(self.ctx),
+            GMS_STATE,
+            "GM list",
+            &gms,
+            Some(version),
+        )
+        .await
+    }
+
+    async fn eval_statement(&mut self, stmt: &Statement) -> Result<Output, RollerError> {
+        let text = match stmt {
+            Statement::Roll(ref expr) => {
+                let roll = self.roll(expr).await?;
+                if self.log_rolls {
+                    LoggedRoll::new(self.ctx, &roll, self.seed)
+                        .log(self.env, self.ctx)
+                        .await?;
+                }
+                return Ok(Output::Roll(roll));
+            }
+            Statement::GmRoll(ref expr) => {
+                let roll = self.roll(expr).await?;
+                return Ok(Output::SecretRoll(roll, self.gms().await?.0));
+            }
+            Statement::Whisper(user, ref expr) => {
+                let roll = self.roll(expr).await?;
+                return Ok(Output::SecretRoll(roll, vec![*user]));
+            }
+            Statement::AddGm(user) => {
+                let (mut gms, version) = self.gms().await?;
+                self.check_can_manage_gms(&gms)?;
+                if !gms.contains(user) {
+                    gms.push(*user);
+                    self.set_gms(&gms, version).await?;
+                }
+                Ok(format!("<@{}> is a GM here", user))
+            }
+            Statement::RemoveGm(user) => {
+                let (mut gms, version) = self.gms().await?;
+                self.check_can_manage_gms(&gms)?;
+                gms.retain(|gm| gm != user);
+                self.set_gms(&gms, version).await?;
+                Ok(format!("<@{}> isn't a GM here", user))
+            }
+            Statement::ListGms => {
+                let (gms, _) = self.gms().await?;
+                if gms.is_empty() {
+                    Ok("there are no GMs here".to_string())
+                } else {
+                    Ok(gms
+                        .iter()
+                        .map(|gm| format!("<@{}>", gm))
+                        .collect::<Vec<_>>()
+                        .join(", "))
+                }
+            }
+            Statement::InitRoll(ref expr) => {
+                let roll = self.roll(expr).await?;
+                let combatant = Combatant::player(self.ctx.user_id(), roll.total);
+                self.add_combatant(combatant).await
+            }
+            Statement::InitAdd(name, ref expr) => {
+                let roll = self.roll(expr).await?;
+                let combatant = Combatant::npc(name.clone(), roll.total);
+                self.add_combatant(combatant).await
+            }
+            Statement::InitNext => {
+                let mut initiative = Initiative::load(self.env, self.ctx).await?;
+                let name = initiative.next_turn()?.name.clone();
+                initiative.save(self.env, self.ctx).await?;
+                Ok(format!(
+                    "round {}: {}, it's your turn",
+                    initiative.round, name
+                ))
+            }
+            Statement::InitClear => {
+                Initiative::default().save(self.env, self.ctx).await?;
+                Ok("initiative cleared".to_string())
+            }
+            Statement::InitList => Ok(Initiative::load(self.env, self.ctx).await?.to_string()),
+            Statement::CharCreate(name) => {
+                let mut characters = Characters::load(self.env, self.ctx).await?;
+                characters.create(name)?;
+                characters.save(self.env, self.ctx).await?;
+                Ok(format!("created {}, who you're now playing", name))
+            }
+            Statement::CharUse(name) => {
+                let mut characters = Characters::load(self.env, self.ctx).await?;
+                characters.play(name)?;
+                characters.save(self.env, self.ctx).await?;
+                Ok(format!("you're now playing {}", name))
+            }
+            Statement::CharShow(name) => Characters::load(self.env, self.ctx)
+                .await?
+                .show(name.as_ref()),
+            Statement::CharSet(field, expr, source) => {
+                self.set_field(field, FieldKind::of(expr), expr, source)
+                    .await
+            }
+            Statement::CharSkill(field, expr, source) => {
+                self.set_field(field, FieldKind::Skill, expr, source).await
+            }
+            Statement::TableSet(name, definition) => {
+                let table = Table::parse(definition)?;
+                table.save(self.env, self.ctx, name).await?;
+                Ok(format!(
+                    "saved {} with {} entries, rolled with a d{}",
+                    name,
+                    table.entries.len(),
+                    table.size()
+                ))
+            }
+            Statement::TableRoll(name) => {
+                let (roll, text) = roll_table(
+                    self.rng,
+                    &LayeredEnvironment::new(self.env),
+                    self.ctx,
+                    &mut vec![],
+                    name,
+                )
+                .await?;
+                Ok(format!("{} ({}): {}", name, roll, text))
+            }
+            Statement::TableShow(name) => {
+                Ok(Table::load(self.env, self.ctx, name).await?.to_string())
+            }
+            Statement::DeckNew(name, kind) => {
+                let deck = Deck::new(kind, self.rng);
+                deck.save(self.env, self.ctx, name).await?;
+                Ok(format!(
+                    "shuffled a new deck of {} called {}",
+                    cards(deck.size()),
+                    name
+                ))
+            }
+            Statement::DeckDraw(name, count) => {
+                let mut deck = Deck::load(self.env, self.ctx, name).await?;
+                let drawn = deck.draw(*count)?.join(", ");
+                deck.save(self.env, self.ctx, name).await?;
+                Ok(format!("drew {} ({} left)", drawn, deck.pile.len()))
+            }
+            Statement::DeckPeek(name, count) => {
+                let deck = Deck::load(self.env, self.ctx, name).await?;
+                let next = deck.peek(*count);
+                if next.is_empty() {
+                    Ok(format!("there are no cards left to draw from {}", name))
+                } else {
+                    let next: Vec<&str> = next.into_iter().map(String::as_str).collect();
+                    Ok(format!("next in {}: {}", name, next.join(", ")))
+                }
+            }
+            Statement::DeckDiscard(name) => {
+                let mut deck = Deck::load(self.env, self.ctx, name).await?;
+                let discarded = deck.discard();
+                deck.save(self.env, self.ctx, name).await?;
+                Ok(format!("discarded {}", cards(discarded)))
+            }
+            Statement::DeckShuffle(name) => {
+                let mut deck = Deck::load(self.env, self.ctx, name).await?;
+                deck.shuffle(self.rng);
+                deck.save(self.env, self.ctx, name).await?;
+                Ok(format!(
+                    "shuffled the {} left to draw from {}",
+                    cards(deck.pile.len()),
+                    name
+                ))
+            }
+            Statement::DeckReshuffle(name) => {
+                let mut deck = Deck::load(self.env, self.ctx, name).await?;
+                deck.reshuffle(self.rng);
+                deck.save(self.env, self.ctx, name).await?;
+                Ok(format!(
+                    "shuffled all {} back into {}",
+                    cards(deck.size()),
+                    name
+                ))
+            }
+            Statement::DeckShow(name) => Ok(format!(
+                "{}: {}",
+                name,
+                Deck::load(self.env, self.ctx, name).await?
             )),
-            Statement::SetValue(variable, ref expr) => {
+            Statement::LogLast(count, None) => Ok(RollLog::load_last(self.env, self.ctx, *count)
+                .await?
+                .last(*count, None)),
+            Statement::LogLast(count, Some(user)) => Ok(RollLog::load(self.env, self.ctx, None)
+                .await?
+                .last(*count, Some(&user.to_string()))),
+            Statement::LogExport(name, period) => {
+                let format = LogFormat::from_name(name.as_deref().unwrap_or(DEFAULT_LOG_NAME))?;
+                Ok(RollLog::load(self.env, self.ctx, *period)
+                    .await?
+                    .render(format))
+            }
+            Statement::Luck(of, period) => {
+                let user = match of {
+                    LuckOf::Me => Some(self.ctx.user_id()),
+                    LuckOf::User(user) => Some(user.to_string()),
+                    LuckOf::Channel => None,
+                };
+                let log = RollLog::load(self.env, self.ctx, *period).await?;
+                let rolls = log.by(user.as_deref());
+                let whose = match &user {
+                    Some(user) => format!("<@{}>'s rolls here", user),
+                    None => "rolls here".to_string(),
+                };
+                let when = period
+                    .map(|period| format!(" in the last {}", period))
+                    .unwrap_or_default();
+                // Says how much of the log the stats come from, since older
+                // rolls expire from it.
+                Ok(format!(
+                    "{}{}, out of {} logged: {}",
+                    whose,
+                    when,
+                    log.rolls.len(),
+                    Stats::new(rolls)
+                ))
+            }
+            Statement::TableImport(_) => Err(RollerError::EvalError(
+                "!table import needs a file or attachment to read tables from".to_string(),
+            )),
+            Statement::Help(topic) => {
+                let config = self.ctx.config();
+                let page = match topic {
+                    Some(topic) => format!("help-{}", topic.name()),
+                    None => "help-general".to_string(),
+                };
+                Ok(t!(&page, locale = &config.locale, prefix = &config.prefix).to_string())
+            }
+            Statement::Config(None) => Ok(Config::load(self.env, self.ctx).await?.to_string()),
+            Statement::Config(Some((key, value))) => {
+                if !self.ctx.is_admin() {
+                    return Err(RollerError::EvalError(
+                        "only server admins can change settings".to_string(),
+                    ));
+                }
+                let mut config = Config::load(self.env, self.ctx).await?;
+                config.set(key, value)?;
+                config.save(self.env, self.ctx).await?;
+                Ok(format!("{} is now {}", key, value))
+            }
+            Statement::PrintEnv => Ok(self.env.print(self.ctx).await.to_string()),
+            Statement::SetValue(variable, ref expr, source, version) => {
                 let value = self.visit_expression(expr).await?;
-                let return_string = format!("{:?} => {:?}", variable, value);
-                self.env.set(self.ctx, variable, &value).await;
+                let return_string = format!("{} => {}", variable, value);
+                // Only a template literal is saved as written; anything else is
+                // saved as the value it evaluated to.
+                let source = matches!(**expr, Expression::DiceRollTemplate { .. })
+                    .then_some(source.as_str());
+                match (version, source) {
+                    (Some(version), source) => self
+                        .env
+                        .set_version(self.ctx, variable, &value, source, *version)
+                        .await
+                        .map_err(|err| match err {
+                            // Running it again would only be turned down again.
+                            RollerError::ConflictError(_) => RollerError::EvalError(format!(
+                                "{} has been changed since v{}, edit it again",
+                                variable, version
+                            )),
+                            err => err,
+                        })?,
+                    (None, Some(source)) => {
+                        self.env
+                            .set_with_source(self.ctx, variable, &value, source)
+                            .await?
+                    }
+                    (None, None) => self.env.set(self.ctx, variable, &value).await?,
+                }
+                Ok(return_string)
+            }
+            Statement::Export(name) => {
+                let format = match name {
+                    Some(name) => Format::from_name(name)?,
+                    None => Format::Yaml,
+                };
+                export(&*self.env, self.ctx).await?.render(format)
+            }
+            Statement::Import(..) => Err(RollerError::EvalError(
+                "!import needs a file or attachment to read variables from".to_string(),
+            )),
+            Statement::CopyVars(from, to) => {
+                let copied = match (scope_context(self.ctx, from), scope_context(self.ctx, to)) {
+                    (None, None) => {
+                        return Err(RollerError::EvalError(
+                            "variables can't be copied to the scope they're in".to_string(),
+                        ))
+                    }
+                    (None, Some(to)) => self.env.copy_vars(self.ctx, &to).await?,
+                    (Some(from), None) => self.env.copy_vars(&from, self.ctx).await?,
+                    (Some(from), Some(to)) => self.env.copy_vars(&from, &to).await?,
+                };
+                if copied.is_empty() {
+                    Ok("no variables to copy".to_string())
+                } else {
+                    Ok(format!("copied {}", copied.join(", ")))
+                }
+            }
+            Statement::Edit(variable) => {
+                let source = match self.env.try_source(self.ctx, variable).await? {
+                    Some(source) => source,
+                    None => self
+                        .env
+                        .try_get(self.ctx, variable)
+                        .await?
+                        .ok_or_else(|| RollerError::EvalError(format!("{} is not set", variable)))?
+                        .to_string(),
+                };
+                // Saving the edit turns it down if the variable changes meanwhile.
+                let prefix = self.ctx.config().prefix;
+                match self.env.version(self.ctx, variable).await? {
+                    Some(version) => {
+                        Ok(format!("{}set@{} {} {}", prefix, version, variable, source))
+                    }
+                    None => Ok(format!("{}set {} {}", prefix, variable, source)),
+                }
+            }
+            Statement::History(variable) => {
+                let history = self.env.history(self.ctx, variable).await?;
+                if history.is_empty() {
+                    Ok(format!("{} has no history", variable))
+                } else {
+                    Ok(history
+                        .iter()
+                        .map(|version| format!("v{}: {}", version.version, version.expression))
+                        .collect::<Vec<_>>()
+                        .join("\n"))
+                }
+            }
+            Statement::Revert(variable, version) => {
+                let previous = self
+                    .env
+                    .history(self.ctx, variable)
+                    .await?
+                    .into_iter()
+                    .find(|previous| previous.version == *version)
+                    .ok_or_else(|| {
+                        RollerError::EvalError(format!("{} has no version {}", variable, version))
+                    })?;
+                let return_string = format!("{} => {}", variable, previous.expression);
+                match previous.source {
+                    Some(source) => {
+                        self.env
+                            .set_with_source(self.ctx, variable, &previous.expression, &source)
+                            .await?
+                    }
+                    None => {
+                        self.env
+                            .set(self.ctx, variable, &previous.expression)
+                            .await?
+                    }
+                }
                 Ok(return_string)
             }
+        };
+        text.map(Output::Text)
+    }
+}
+
+impl<'a, T: Rng, E: Environment + Sync, C: Context + Copy + Send>
+    Visitor<Result<Output, RollerError>, Result<Expression, RollerError>>
+    for EvalVisitor<'a, T, E, C>
+{
+    async fn visit_expression(&mut self, expr: &Expression) -> Result<Expression, RollerError> {
+        evaluate(
+            self.rng,
+            &LayeredEnvironment::new(self.env),
+            self.ctx,
+            &mut vec![],
+            expr,
+        )
+        .await
+    }
+
+    async fn visit_statement(&mut self, stmt: &Statement) -> Result<Output, RollerError> {
+        // State is only saved if nobody else has saved it since it was read, so a
+        // statement that loses that race is run again on what they saved.
+        let mut attempt = 1;
+        loop {
+            match self.eval_statement(stmt).await {
+                Err(RollerError::ConflictError(_)) if attempt < MAX_STATE_ATTEMPTS => {
+                    tokio::time::sleep(backoff(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
         }
     }
 }
@@ -202,7 +791,11 @@ impl<'a, T: Rng, E: Environment + Clone, C: Context + Copy + Send>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::environments::file_environment::FileEnvironment;
     use crate::environments::hash_map_environment::HashMapEnvironment;
+    use crate::parser::StatementParser;
+    use crate::repl::REPLContext;
+    use crate::types::{HelpTopic, Parser};
     use rand::rngs::mock::StepRng;
 
     struct TestCtx;
@@ -215,6 +808,10 @@ mod tests {
         fn global_context_key(&self) -> String {
             format!("scope:{}#scope_type:user#user:{}", "test", "global")
         }
+
+        fn scope_context(&self, scope: &str) -> REPLContext {
+            REPLContext::new(scope.to_string(), "test_user".to_string())
+        }
     }
 
     #[tokio::test]
@@ -263,7 +860,14 @@ mod tests {
                 )))))
                 .await
                 .unwrap(),
-            "2"
+            Output::Roll(RollResult {
+                expression: "1d6 + 1".to_string(),
+                dice: vec![DieRoll {
+                    sides: 6,
+                    results: vec![1]
+                }],
+                total: 2
+            })
         );
         assert_eq!(
             visitor
@@ -276,19 +880,35 @@ mod tests {
                     Op::Add
                 )))))
                 .await
-                .unwrap(),
+                .unwrap()
+                .to_string(),
             "2"
         );
         assert_eq!(
             visitor
                 .visit_expression(&Box::new(Expression::DiceRoll {
-                    count: Box::new(Expression::Integer(1231239)),
-                    sides: Box::new(Expression::Integer(410123123))
+                    count: Box::new(Expression::Integer(1000)),
+                    sides: Box::new(Expression::Integer(410123))
                 }))
                 .await
                 .unwrap(),
-            Expression::Integer(1231239)
+            Expression::Integer(1000)
         );
+        // Beyond the default limits.
+        assert!(visitor
+            .visit_expression(&Box::new(Expression::DiceRoll {
+                count: Box::new(Expression::Integer(1231239)),
+                sides: Box::new(Expression::Integer(6))
+            }))
+            .await
+            .is_err());
+        assert!(visitor
+            .visit_expression(&Box::new(Expression::DiceRoll {
+                count: Box::new(Expression::Integer(1)),
+                sides: Box::new(Expression::Integer(410123123))
+            }))
+            .await
+            .is_err());
         assert_eq!(
             visitor
                 .visit_expression(&Box::new(Expression::DiceRollTemplateCall {
@@ -346,4 +966,621 @@ mod tests {
             Expression::Integer(-4),
         );
     }
+
+    #[tokio::test]
+    async fn test_eval_dice_bounds() {
+        let mut rng = StepRng::new(0, 1);
+        let mut env = HashMapEnvironment::new();
+        let ctx = &TestCtx {};
+        env.set(ctx, "n", &Expression::Integer(-1)).await.unwrap();
+        let mut visitor = EvalVisitor::new(&mut rng, &mut env, ctx);
+        assert_eq!(
+            visitor
+                .visit_statement(&StatementParser.parse("!roll {n}d6").unwrap())
+                .await
+                .unwrap_err()
+                .to_string(),
+            "can't roll -1 dice"
+        );
+        assert_eq!(
+            visitor
+                .visit_statement(&StatementParser.parse("!roll 1d{n}").unwrap())
+                .await
+                .unwrap_err()
+                .to_string(),
+            "dice need at least 1 side, not -1"
+        );
+        assert_eq!(
+            visitor
+                .visit_statement(&StatementParser.parse("!roll 1d0").unwrap())
+                .await
+                .unwrap_err()
+                .to_string(),
+            "dice need at least 1 side, not 0"
+        );
+        // No dice at all roll nothing.
+        assert_eq!(
+            visitor
+                .visit_statement(&StatementParser.parse("!roll 0d6").unwrap())
+                .await
+                .unwrap()
+                .to_string(),
+            "0"
+        );
+        assert!(usize::try_from(Expression::Integer(-1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_eval_template_scope() {
+        let mut rng = StepRng::new(0, 1);
+        let mut env = HashMapEnvironment::new();
+        let ctx = &TestCtx {};
+        env.set(ctx, "bonus", &Expression::Integer(5))
+            .await
+            .unwrap();
+        env.set(
+            ctx,
+            "add_bonus",
+            &Expression::DiceRollTemplate {
+                args: vec!["x".to_string()],
+                expressions: vec![Expression::Term(
+                    Box::new(Expression::Variable("x".to_string())),
+                    Box::new(Expression::Variable("bonus".to_string())),
+                    Op::Add,
+                )],
+            },
+        )
+        .await
+        .unwrap();
+
+        let mut visitor = EvalVisitor::new(&mut rng, &mut env, ctx);
+        // The outer template's argument is visible to the nested call, and
+        // both fall through to the saved bonus.
+        assert_eq!(
+            visitor
+                .visit_expression(&Expression::DiceRollTemplateCall {
+                    template_expression: Box::new(Expression::DiceRollTemplate {
+                        args: vec!["y".to_string()],
+                        expressions: vec![Expression::DiceRollTemplateCall {
+                            template_expression: Box::new(Expression::Variable(
+                                "add_bonus".to_string()
+                            )),
+                            args: vec![Expression::Variable("y".to_string())],
+                        }],
+                    }),
+                    args: vec![Expression::Integer(2)],
+                })
+                .await
+                .unwrap(),
+            Expression::Integer(7),
+        );
+        assert!(visitor
+            .visit_expression(&Expression::Variable("x".to_string()))
+            .await
+            .is_err());
+        // Only one expression is ever evaluated, so a body with more is refused
+        // rather than having the rest ignored.
+        assert_eq!(
+            visitor
+                .visit_expression(&Expression::DiceRollTemplateCall {
+                    template_expression: Box::new(Expression::DiceRollTemplate {
+                        args: vec![],
+                        expressions: vec![Expression::Integer(1), Expression::Integer(2)],
+                    }),
+                    args: vec![],
+                })
+                .await
+                .unwrap_err()
+                .to_string(),
+            "a dice roll template's body must be one expression"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_eval_history_unsupported() {
+        let mut rng = StepRng::new(0, 1);
+        let mut env = HashMapEnvironment::new();
+        let mut visitor = EvalVisitor::new(&mut rng, &mut env, &TestCtx {});
+        assert!(visitor
+            .visit_statement(&Statement::History("a".to_string()))
+            .await
+            .is_err());
+        assert!(visitor
+            .visit_statement(&Statement::Revert("a".to_string(), 1))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_eval_edit() {
+        let mut rng = StepRng::new(0, 1);
+        let mut env = HashMapEnvironment::new();
+        let mut visitor = EvalVisitor::new(&mut rng, &mut env, &TestCtx {});
+        let parser = StatementParser;
+
+        for input in ["!set attack (m) =>  (1d20 + {m})", "!set bonus 1d4 + 1"] {
+            visitor
+                .visit_statement(&parser.parse(input).unwrap())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            visitor
+                .visit_statement(&Statement::Edit("attack".to_string()))
+                .await
+                .unwrap()
+                .to_string(),
+            "!set attack (m) =>  (1d20 + {m})"
+        );
+        // Rolled values are edited as the value they were saved as.
+        assert_eq!(
+            visitor
+                .visit_statement(&Statement::Edit("bonus".to_string()))
+                .await
+                .unwrap()
+                .to_string(),
+            "!set bonus 2"
+        );
+        assert!(visitor
+            .visit_statement(&Statement::Edit("missing".to_string()))
+            .await
+            .is_err());
+
+        // Output to run again, and help, use the server's prefix.
+        let mut config = Config::default();
+        config.set("prefix", "?").unwrap();
+        let ctx = &REPLContext::new("table".to_string(), "1".to_string()).with_config(config);
+        env.set(ctx, "bonus", &Expression::Integer(2))
+            .await
+            .unwrap();
+        let mut visitor = EvalVisitor::new(&mut rng, &mut env, ctx);
+        assert_eq!(
+            visitor
+                .visit_statement(&Statement::Edit("bonus".to_string()))
+                .await
+                .unwrap()
+                .to_string(),
+            "?set bonus 2"
+        );
+        let help = visitor
+            .visit_statement(&Statement::Help(None))
+            .await
+            .unwrap()
+            .to_string();
+        assert!(help.contains("?roll [dice-expression]"), "{}", help);
+        assert!(!help.contains('!'), "{}", help);
+    }
+
+    // Discord won't send a message over 2,000 characters.
+    #[tokio::test]
+    async fn test_eval_help_pages() {
+        let mut env = HashMapEnvironment::new();
+        let ctx = &REPLContext::new("table".to_string(), "1".to_string());
+        let overview = run(&mut env, ctx, "!help").await;
+        for topic in HelpTopic::ALL {
+            let input = format!("!help {}", topic.name());
+            assert!(overview.contains(&input), "{} isn't listed", input);
+            let page = run(&mut env, ctx, &input).await;
+            assert!(page.contains(':'), "{} has no page: {}", input, page);
+            assert!(page.chars().count() <= 2000, "{} is too long", input);
+        }
+        assert!(overview.chars().count() <= 2000);
+    }
+
+    #[tokio::test]
+    async fn test_eval_copy_vars() {
+        let mut rng = StepRng::new(0, 1);
+        let mut env = HashMapEnvironment::new();
+        let other = &(&TestCtx).scope_context("other");
+        env.set_with_source(other, "attack", &Expression::Integer(1), "1")
+            .await
+            .unwrap();
+        env.set(other, "bonus", &Expression::Integer(2))
+            .await
+            .unwrap();
+        let mut visitor = EvalVisitor::new(&mut rng, &mut env, &TestCtx {});
+
+        assert_eq!(
+            visitor
+                .visit_statement(&StatementParser.parse("!copy-vars from:#other").unwrap())
+                .await
+                .unwrap()
+                .to_string(),
+            "copied attack, bonus"
+        );
+        assert_eq!(
+            visitor
+                .visit_statement(&StatementParser.parse("!copy-vars from:#empty").unwrap())
+                .await
+                .unwrap()
+                .to_string(),
+            "no variables to copy"
+        );
+        visitor
+            .visit_statement(&StatementParser.parse("!copy-vars to:home").unwrap())
+            .await
+            .unwrap();
+
+        let home = &(&TestCtx).scope_context(HOME_SCOPE);
+        assert_eq!(
+            env.get(&TestCtx, "bonus").await,
+            Some(Expression::Integer(2))
+        );
+        assert_eq!(env.source(home, "attack").await, Some("1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_eval_gm_roll() {
+        let mut rng = StepRng::new(0, 1);
+        let mut env = HashMapEnvironment::new();
+        let admin = &REPLContext::new("table".to_string(), "9".to_string()).as_admin();
+        let mut visitor = EvalVisitor::new(&mut rng, &mut env, admin);
+        let parse = |input: &str| StatementParser.parse(input).unwrap();
+
+        for input in ["!gm add <@1>", "!gm add <@2>", "!gm add <@1>"] {
+            visitor.visit_statement(&parse(input)).await.unwrap();
+        }
+        visitor
+            .visit_statement(&parse("!gm remove <@2>"))
+            .await
+            .unwrap();
+        assert_eq!(
+            visitor
+                .visit_statement(&parse("!gm list"))
+                .await
+                .unwrap()
+                .to_string(),
+            "<@1>"
+        );
+        match visitor
+            .visit_statement(&parse("!gmroll 1d20"))
+            .await
+            .unwrap()
+        {
+            Output::SecretRoll(roll, recipients) => {
+                assert_eq!(roll.total, 1);
+                assert_eq!(recipients, vec![1]);
+            }
+            output => panic!("expected a secret roll, got {:?}", output),
+        }
+        assert!(matches!(
+            visitor
+                .visit_statement(&parse("!whisper <@7> 2"))
+                .await
+                .unwrap(),
+            Output::SecretRoll(_, recipients) if recipients == vec![7]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_eval_gm_permissions() {
+        let mut env = HashMapEnvironment::new();
+        let admin = &REPLContext::new("table".to_string(), "1".to_string()).as_admin();
+        let gm = &REPLContext::new("table".to_string(), "2".to_string());
+        let player = &REPLContext::new("table".to_string(), "3".to_string());
+        assert_eq!(
+            try_run(&mut env, player, "!gm add <@3>")
+                .await
+                .unwrap_err()
+                .to_string(),
+            "only server admins and GMs can change the GMs"
+        );
+        try_run(&mut env, admin, "!gm add <@2>").await.unwrap();
+        try_run(&mut env, gm, "!gm add <@4>").await.unwrap();
+        assert!(try_run(&mut env, player, "!gm remove <@2>").await.is_err());
+        try_run(&mut env, gm, "!gm remove <@2>").await.unwrap();
+        assert_eq!(run(&mut env, player, "!gm list").await, "<@4>");
+    }
+
+    async fn run(env: &mut HashMapEnvironment, ctx: &REPLContext, input: &str) -> String {
+        try_run(env, ctx, input).await.unwrap()
+    }
+
+    async fn try_run(
+        env: &mut HashMapEnvironment,
+        ctx: &REPLContext,
+        input: &str,
+    ) -> Result<String, RollerError> {
+        EvalVisitor::new(&mut StepRng::new(0, 1), env, ctx)
+            .visit_statement(&StatementParser.parse(input).unwrap())
+            .await
+            .map(|output| output.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_eval_initiative() {
+        let mut env = HashMapEnvironment::new();
+        let ann = &REPLContext::new("table".to_string(), "1".to_string());
+        let gm = &REPLContext::new("table".to_string(), "2".to_string());
+
+        run(&mut env, ann, "!set dex 3").await;
+        assert_eq!(
+            run(&mut env, ann, "!init roll 1d20+{dex}").await,
+            "<@1> rolled 4 for initiative"
+        );
+        assert_eq!(
+            run(&mut env, gm, "!init add Goblin 1d20+5").await,
+            "Goblin rolled 6 for initiative"
+        );
+        assert_eq!(
+            run(&mut env, gm, "!init next").await,
+            "round 1: Goblin, it's your turn"
+        );
+        assert_eq!(
+            run(&mut env, gm, "!init next").await,
+            "round 1: <@1>, it's your turn"
+        );
+        assert_eq!(
+            run(&mut env, ann, "!init list").await,
+            "round 1\n   6 Goblin\n▶ 4 <@1>"
+        );
+        run(&mut env, gm, "!init clear").await;
+        assert_eq!(
+            run(&mut env, ann, "!init").await,
+            "no one has rolled initiative"
+        );
+        assert_eq!(
+            try_run(&mut env, gm, "!init next")
+                .await
+                .unwrap_err()
+                .to_string(),
+            "no one has rolled initiative"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_eval_character() {
+        let mut env = HashMapEnvironment::new();
+        let ctx = &REPLContext::new("table".to_string(), "1".to_string());
+
+        for input in ["!char show", "!char set str 16", "!roll {char.str}"] {
+            assert_eq!(
+                try_run(&mut env, ctx, input).await.unwrap_err().to_string(),
+                "make a character with !char create first",
+                "{}",
+                input
+            );
+        }
+
+        run(&mut env, ctx, "!char create Thorn").await;
+        for input in [
+            "!char set str 16",
+            "!char set str_mod {char.str} - 10",
+            "!char set attack (m) => (1d20 + {char.str_mod} + {m})",
+            "!char skill athletics {char.str_mod} + 2",
+        ] {
+            run(&mut env, ctx, input).await;
+        }
+        assert_eq!(run(&mut env, ctx, "!roll {char.athletics}").await, "8");
+        assert_eq!(run(&mut env, ctx, "!roll {char.attack}(1)").await, "8");
+
+        // Derived values follow the attributes they come from.
+        run(&mut env, ctx, "!char set str 12").await;
+        assert_eq!(run(&mut env, ctx, "!roll {char.attack}(1)").await, "4");
+
+        run(&mut env, ctx, "!char create Wren").await;
+        assert!(EvalVisitor::new(&mut StepRng::new(0, 1), &mut env, ctx)
+            .visit_statement(&StatementParser.parse("!roll {char.str}").unwrap())
+            .await
+            .is_err());
+        run(&mut env, ctx, "!char use Thorn").await;
+        assert_eq!(run(&mut env, ctx, "!roll {char.str}").await, "12");
+    }
+
+    #[tokio::test]
+    async fn test_eval_config() {
+        let mut rng = StepRng::new(0, 1);
+        let mut env = HashMapEnvironment::new();
+        let parse = |input: &str| StatementParser.parse(input).unwrap();
+        let player = &REPLContext::new("table".to_string(), "1".to_string());
+        let admin = &player.clone().as_admin();
+
+        assert!(EvalVisitor::new(&mut rng, &mut env, player)
+            .visit_statement(&parse("!config max-dice 3"))
+            .await
+            .is_err());
+        EvalVisitor::new(&mut rng, &mut env, admin)
+            .visit_statement(&parse("!config max-dice 3"))
+            .await
+            .unwrap();
+        let config = Config::load(&env, player).await.unwrap();
+        assert_eq!(config.max_dice, 3);
+
+        let player = &player.clone().with_config(config);
+        let mut visitor = EvalVisitor::new(&mut rng, &mut env, player);
+        visitor
+            .visit_statement(&parse("!roll 2d6 + 1d6"))
+            .await
+            .unwrap();
+        assert_eq!(
+            visitor
+                .visit_statement(&parse("!roll 2d6 + 2d6"))
+                .await
+                .unwrap_err()
+                .to_string(),
+            "can't roll more than 3 dice at once"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_eval_log() {
+        let mut env = HashMapEnvironment::new();
+        let ann = &REPLContext::new("table".to_string(), "1".to_string());
+        let bo = &REPLContext::new("table".to_string(), "2".to_string());
+
+        assert_eq!(
+            run(&mut env, ann, "!luck").await,
+            "<@1>'s rolls here, out of 0 logged: no rolls"
+        );
+        assert_eq!(
+            run(&mut env, ann, "!luck channel last 1h").await,
+            "rolls here in the last 1h, out of 0 logged: no rolls"
+        );
+
+        run(&mut env, ann, "!roll 2d6 + 1").await;
+        run(&mut env, bo, "!roll 1d20").await;
+        run(&mut env, bo, "!gmroll 1d20").await;
+        let log = run(&mut env, ann, "!log last 5").await;
+        assert_eq!(log.lines().count(), 2, "secret rolls aren't logged");
+        assert!(log.ends_with("<@2> 1d20 = 1 d20 [1]"), "{}", log);
+        assert!(run(&mut env, ann, "!log <@1>")
+            .await
+            .ends_with("<@1> 2d6 + 1 = 3 d6 [1, 1]"));
+
+        let csv = run(&mut env, ann, "!log export rolls.csv").await;
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with(",1,table,2d6 + 1,\"d6 [1, 1]\",3,"));
+        let recent = run(&mut env, ann, "!log export rolls.md last 1h").await;
+        assert_eq!(recent.lines().count(), 4, "{}", recent);
+
+        assert_eq!(
+            run(&mut env, ann, "!luck").await,
+            "<@1>'s rolls here, out of 2 logged: 1 roll of 2 dice, adding up to 2 against 7.0 expected\n\
+             no d20s rolled"
+        );
+        assert!(run(&mut env, ann, "!luck channel last 1h")
+            .await
+            .starts_with("rolls here in the last 1h, out of 2 logged: 2 rolls of 3 dice"));
+        assert_eq!(
+            run(&mut env, ann, "!luck <@3>").await,
+            "<@3>'s rolls here, out of 2 logged: no rolls"
+        );
+        assert!(run(&mut env, ann, "!luck <@2>")
+            .await
+            .contains("1 d20 averaging 1.00 against 10.50 expected"));
+
+        // Rolls run through the REPL record the seed they were rolled from.
+        let mut repl = crate::repl::REPL::default();
+        repl.exec(ann, "!roll 1d20").await.unwrap();
+        let csv = repl.exec(ann, "!log export rolls.csv").await.unwrap();
+        assert!(!csv.lines().nth(1).unwrap().ends_with(','), "{}", csv);
+
+        // A roll that can't be logged fails like any other write, rather than
+        // going missing from the log.
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = FileEnvironment::open(dir.path().join("environment.json")).unwrap();
+        // A directory in the way of the roll log makes logging fail.
+        std::fs::create_dir(dir.path().join("environment.rolls.jsonl")).unwrap();
+        let output = EvalVisitor::new(&mut StepRng::new(0, 1), &mut env, ann)
+            .visit_statement(&StatementParser.parse("!roll 1d20").unwrap())
+            .await;
+        assert!(matches!(output, Err(RollerError::StorageError(_))));
+        assert!(env.rolls(Global(ann), 0, None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_eval_deck() {
+        let mut env = HashMapEnvironment::new();
+        let ctx = &REPLContext::new("table".to_string(), "1".to_string());
+        let gm = &REPLContext::new("table".to_string(), "2".to_string());
+
+        assert_eq!(
+            run(&mut env, ctx, "!deck new init standard").await,
+            "shuffled a new deck of 54 cards called init"
+        );
+        let next = run(&mut env, gm, "!deck peek init 2").await;
+        let drawn = run(&mut env, gm, "!deck draw init 2").await;
+        assert_eq!(
+            next.replace("next in init: ", "drew "),
+            drawn.replace(" (52 left)", "")
+        );
+        assert_eq!(
+            run(&mut env, ctx, "!deck discard init").await,
+            "discarded 2 cards"
+        );
+        assert_eq!(
+            run(&mut env, ctx, "!deck show init").await,
+            "init: 52 cards to draw, 2 discarded"
+        );
+        assert_eq!(
+            run(&mut env, ctx, "!deck reshuffle init").await,
+            "shuffled all 54 cards back into init"
+        );
+
+        run(&mut env, gm, "!deck draw init 54").await;
+        assert_eq!(
+            try_run(&mut env, gm, "!deck draw init")
+                .await
+                .unwrap_err()
+                .to_string(),
+            "there are only 0 cards left to draw, reshuffle to put the rest back"
+        );
+        assert!(run(&mut env, ctx, "!deck show init")
+            .await
+            .starts_with("init: 0 cards to draw, 0 discarded\n"));
+
+        // Decks belong to the channel they were made in.
+        let other_channel = &REPLContext::new("other".to_string(), "1".to_string());
+        assert!(
+            EvalVisitor::new(&mut StepRng::new(0, 1), &mut env, other_channel)
+                .visit_statement(&StatementParser.parse("!deck draw init").unwrap())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_eval_table() {
+        let mut env = HashMapEnvironment::new();
+        let ctx =
+            &REPLContext::new("table".to_string(), "1".to_string()).with_guild("9".to_string());
+        let other_channel =
+            &REPLContext::new("other".to_string(), "2".to_string()).with_guild("9".to_string());
+
+        assert_eq!(
+            run(&mut env, ctx, "!table set gem 1-2: a ruby, 3: a pearl").await,
+            "saved gem with 2 entries, rolled with a d3"
+        );
+        run(
+            &mut env,
+            ctx,
+            "!table set loot 1: [[2d6 + 1]] gold and {table.gem}, 2: nothing",
+        )
+        .await;
+        run(&mut env, ctx, "!table set bonus 1: 1d4 + 2\n2: 5").await;
+
+        // Tables belong to the server, so every channel can roll on them.
+        assert_eq!(
+            run(&mut env, other_channel, "!table roll loot").await,
+            "loot (1): 3 gold and a ruby"
+        );
+        assert_eq!(run(&mut env, ctx, "!roll {table.bonus} + 1").await, "4");
+        assert_eq!(
+            run(&mut env, ctx, "!table show bonus").await,
+            "1: 1d4 + 2\n2: 5"
+        );
+
+        // loot rolls on gem, so gem can't roll on loot, nor on a table that
+        // rolls on loot.
+        run(&mut env, ctx, "!table set hoard 1: {table.loot}").await;
+        assert_eq!(
+            try_run(&mut env, ctx, "!table set gem 1: {table.hoard}")
+                .await
+                .unwrap_err()
+                .to_string(),
+            "gem can't roll on itself"
+        );
+        assert_eq!(
+            run(&mut env, ctx, "!table roll gem").await,
+            "gem (1): a ruby"
+        );
+
+        for input in [
+            "!table set gem 1: {table.loot}",
+            "!table roll missing",
+            "!roll {table.gem}",
+        ] {
+            assert!(
+                EvalVisitor::new(&mut StepRng::new(0, 1), &mut env, ctx)
+                    .visit_statement(&StatementParser.parse(input).unwrap())
+                    .await
+                    .is_err(),
+                "{}",
+                input
+            );
+        }
+    }
 }
@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::RollerError;
+use crate::parser::{is_variable_name, parse_expression};
+use crate::schema::{self, CURRENT_VERSION};
+use crate::types::{Context, Environment, Expression, OnConflict};
+
+pub const DEFAULT_EXPORT_NAME: &str = "variables.yaml";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Yaml,
+}
+
+impl Format {
+    // Picks the format from a file or attachment name's extension.
+    pub fn from_name(name: &str) -> Result<Self, RollerError> {
+        let extension = name.rsplit_once('.').map(|(_, extension)| extension);
+        match extension
+            .map(|extension| extension.to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("json") => Ok(Format::Json),
+            Some("yaml") | Some("yml") => Ok(Format::Yaml),
+            _ => Err(RollerError::EvalError(format!(
+                "{} should end in .json, .yaml or .yml",
+                name
+            ))),
+        }
+    }
+}
+
+// A portable copy of every variable in a scope. Expressions are kept in their
+// stored layout, tagged with the schema version they were written at, so
+// documents exported by older versions can still be imported.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Document {
+    pub schema_version: u32,
+    pub variables: BTreeMap<String, ExportedVariable>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportedVariable {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub expression: Value,
+}
+
+impl Document {
+    pub fn parse(input: &str, format: Format) -> Result<Self, RollerError> {
+        let document = match format {
+            Format::Json => serde_json::from_str(input).map_err(|err| err.to_string()),
+            Format::Yaml => serde_yaml::from_str(input).map_err(|err| err.to_string()),
+        };
+        document.map_err(|err| RollerError::EvalError(format!("invalid document: {}", err)))
+    }
+
+    pub fn render(&self, format: Format) -> Result<String, RollerError> {
+        let rendered = match format {
+            Format::Json => serde_json::to_string_pretty(self).map_err(|err| err.to_string()),
+            Format::Yaml => serde_yaml::to_string(self).map_err(|err| err.to_string()),
+        };
+        rendered.map_err(|err| RollerError::EvalError(format!("failed to export: {}", err)))
+    }
+
+    // Checks every variable up front, so a bad document changes nothing.
+    fn validate(&self) -> Result<Vec<(String, Expression, Option<String>)>, RollerError> {
+        let mut variables = vec![];
+        for (name, variable) in &self.variables {
+            if !is_variable_name(name) {
+                return Err(RollerError::EvalError(format!(
+                    "{} isn't a valid variable name",
+                    name
+                )));
+            }
+            let parsed = match &variable.source {
+                Some(source) => Some(parse_expression(source).map_err(|_| {
+                    RollerError::EvalError(format!("the source of {} doesn't parse", name))
+                })?),
+                None => None,
+            };
+            let expression = schema::upgrade(
+                variable.expression.clone(),
+                self.schema_version,
+                variable.source.as_deref(),
+            )
+            .map_err(|err| RollerError::EvalError(format!("{}: {}", name, err)))?;
+            // The source is what !edit shows, so it has to say what's saved.
+            if parsed.is_some_and(|parsed| parsed != expression) {
+                return Err(RollerError::EvalError(format!(
+                    "the source of {} doesn't match its expression",
+                    name
+                )));
+            }
+            variables.push((name.clone(), expression, variable.source.clone()));
+        }
+        Ok(variables)
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    pub skipped: Vec<String>,
+    pub renamed: Vec<(String, String)>,
+}
+
+impl Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "imported {} variables", self.imported.len())?;
+        if !self.skipped.is_empty() {
+            write!(f, ", skipped {}", self.skipped.join(", "))?;
+        }
+        for (from, to) in &self.renamed {
+            write!(f, ", saved {} as {}", from, to)?;
+        }
+        Ok(())
+    }
+}
+
+pub async fn export<E: Environment, C: Context + Copy + Send>(
+    env: &E,
+    ctx: C,
+) -> Result<Document, RollerError> {
+    let mut variables = BTreeMap::new();
+    for (name, (expression, source)) in env.closure_with_sources(ctx).await? {
+        let expression = serde_json::to_value(&expression)
+            .map_err(|err| RollerError::EvalError(format!("failed to export {}: {}", name, err)))?;
+        variables.insert(name, ExportedVariable { source, expression });
+    }
+    Ok(Document {
+        schema_version: CURRENT_VERSION,
+        variables,
+    })
+}
+
+pub async fn import<E: Environment, C: Context + Copy + Send>(
+    env: &mut E,
+    ctx: C,
+    document: &Document,
+    on_conflict: OnConflict,
+) -> Result<ImportReport, RollerError> {
+    let variables = document.validate()?;
+    let mut taken: HashSet<String> = env.closure(ctx).await?.into_keys().collect();
+
+    let mut report = ImportReport::default();
+    let mut imported = Vec::new();
+    for (name, expression, source) in variables {
+        let target = match on_conflict {
+            _ if !taken.contains(&name) => name.clone(),
+            OnConflict::Skip => {
+                report.skipped.push(name);
+                continue;
+            }
+            OnConflict::Overwrite => name.clone(),
+            OnConflict::Rename => {
+                let renamed = (2..)
+                    .map(|n| format!("{}-{}", name, n))
+                    .find(|candidate| !taken.contains(candidate))
+                    .unwrap();
+                report.renamed.push((name.clone(), renamed.clone()));
+                renamed
+            }
+        };
+
+        taken.insert(target.clone());
+        imported.push((target, expression, source));
+        report.imported.push(name);
+    }
+    env.set_all(ctx, &imported).await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environments::hash_map_environment::HashMapEnvironment;
+    use crate::repl::REPLContext;
+
+    struct TestCtx(&'static str);
+
+    impl Context for &TestCtx {
+        fn user_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", self.0, "test_user")
+        }
+
+        fn global_context_key(&self) -> String {
+            format!("scope:{}#scope_type:user#user:{}", self.0, "global")
+        }
+
+        fn scope_context(&self, scope: &str) -> REPLContext {
+            REPLContext::new(scope.to_string(), "test_user".to_string())
+        }
+    }
+
+    fn template() -> Expression {
+        Expression::DiceRollTemplate {
+            args: vec!["m".to_string()],
+            expressions: vec![Expression::Variable("m".to_string())],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let (from, to) = (&TestCtx("from"), &TestCtx("to"));
+        let mut env = HashMapEnvironment::new();
+        env.set_with_source(from, "attack", &template(), "(m) => ({m})")
+            .await
+            .unwrap();
+        env.set(from, "bonus", &Expression::Integer(2))
+            .await
+            .unwrap();
+        env.set(to, "bonus", &Expression::Integer(5)).await.unwrap();
+
+        for format in [Format::Json, Format::Yaml] {
+            let exported = export(&env, from).await.unwrap().render(format).unwrap();
+            let document = Document::parse(&exported, format).unwrap();
+            assert_eq!(document, export(&env, from).await.unwrap());
+        }
+
+        let document = export(&env, from).await.unwrap();
+        let report = import(&mut env, to, &document, OnConflict::Skip)
+            .await
+            .unwrap();
+        assert_eq!(report.skipped, vec!["bonus".to_string()]);
+        assert_eq!(env.get(to, "bonus").await, Some(Expression::Integer(5)));
+        assert_eq!(
+            env.source(to, "attack").await,
+            Some("(m) => ({m})".to_string())
+        );
+
+        let report = import(&mut env, to, &document, OnConflict::Rename)
+            .await
+            .unwrap();
+        assert_eq!(
+            report.renamed,
+            vec![
+                ("attack".to_string(), "attack-2".to_string()),
+                ("bonus".to_string(), "bonus-2".to_string())
+            ]
+        );
+        assert_eq!(env.get(to, "bonus-2").await, Some(Expression::Integer(2)));
+
+        import(&mut env, to, &document, OnConflict::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!(env.get(to, "bonus").await, Some(Expression::Integer(2)));
+    }
+
+    #[tokio::test]
+    async fn test_import_validates() {
+        let ctx = &TestCtx("test");
+        let mut env = HashMapEnvironment::new();
+        let invalid = [
+            r#"{"schema_version": 1, "variables": {"a b": {"expression": {"expression_type": "integer", "expression": 1}}}}"#,
+            r#"{"schema_version": 1, "variables": {"a": {"expression": {"expression_type": "unknown"}}}}"#,
+            r#"{"schema_version": 1, "variables": {"a": {"source": "1d", "expression": {"expression_type": "integer", "expression": 1}}}}"#,
+            r#"{"schema_version": 1, "variables": {"a": {"source": "2", "expression": {"expression_type": "integer", "expression": 1}}}}"#,
+        ];
+        for input in invalid {
+            let document = Document::parse(input, Format::Json).unwrap();
+            assert!(import(&mut env, ctx, &document, OnConflict::Overwrite)
+                .await
+                .is_err());
+        }
+        assert!(env.closure(ctx).await.unwrap().is_empty());
+        assert!(Format::from_name("vars.txt").is_err());
+        assert_eq!(Format::from_name("vars.YML").unwrap(), Format::Yaml);
+    }
+}
@@ -0,0 +1,172 @@
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::RollerError;
+use crate::state;
+use crate::types::{Context, Environment, Global};
+
+const INITIATIVE_STATE: &str = "initiative";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Combatant {
+    pub name: String,
+    // The player's user id, or None for NPCs added by name.
+    pub user: Option<String>,
+    pub initiative: i64,
+}
+
+impl Combatant {
+    // Players are mentioned so they're pinged when it's their turn.
+    pub fn player(user: String, initiative: i64) -> Self {
+        Combatant {
+            name: format!("<@{}>", user),
+            user: Some(user),
+            initiative,
+        }
+    }
+
+    pub fn npc(name: String, initiative: i64) -> Self {
+        Combatant {
+            name,
+            user: None,
+            initiative,
+        }
+    }
+
+    fn is(&self, other: &Combatant) -> bool {
+        match (&self.user, &other.user) {
+            (Some(user), Some(other)) => user == other,
+            (None, None) => self.name == other.name,
+            _ => false,
+        }
+    }
+}
+
+// A channel's turn order, highest initiative first. Ties go in the order the
+// combatants were added. turn is None until the first `!init next`.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Initiative {
+    pub combatants: Vec<Combatant>,
+    pub turn: Option<usize>,
+    pub round: u32,
+    // The version the order was loaded at, if it was loaded.
+    #[serde(skip)]
+    pub version: Option<u64>,
+}
+
+impl Initiative {
+    pub async fn load<E: Environment + Sync, C: Context + Send>(
+        env: &E,
+        ctx: C,
+    ) -> Result<Self, RollerError> {
+        let (initiative, version) =
+            state::load(env, Global(ctx), INITIATIVE_STATE, "initiative order").await?;
+        Ok(Initiative {
+            version: Some(version),
+            ..initiative.unwrap_or_default()
+        })
+    }
+
+    pub async fn save<E: Environment, C: Context + Send>(
+        &self,
+        env: &mut E,
+        ctx: C,
+    ) -> Result<(), RollerError> {
+        state::save(
+            env,
+            Global(ctx),
+            INITIATIVE_STATE,
+            "initiative order",
+            self,
+            self.version,
+        )
+        .await
+    }
+
+    pub fn active(&self) -> Option<&Combatant> {
+        self.turn.and_then(|turn| self.combatants.get(turn))
+    }
+
+    // Adds combatant in initiative order, replacing them if they've already
+    // rolled. Whoever's turn it is keeps it.
+    pub fn add(&mut self, combatant: Combatant) {
+        let active = self.active().cloned();
+        self.combatants.retain(|other| !other.is(&combatant));
+        let position = self
+            .combatants
+            .iter()
+            .position(|other| other.initiative < combatant.initiative)
+            .unwrap_or(self.combatants.len());
+        self.combatants.insert(position, combatant);
+        if let Some(active) = active {
+            self.turn = self.combatants.iter().position(|other| other.is(&active));
+        }
+    }
+
+    // Moves on to the next combatant, starting a new round after the last one.
+    pub fn next_turn(&mut self) -> Result<&Combatant, RollerError> {
+        if self.combatants.is_empty() {
+            return Err(RollerError::EvalError(
+                "no one has rolled initiative".to_string(),
+            ));
+        }
+        let turn = match self.turn {
+            Some(turn) if turn + 1 < self.combatants.len() => turn + 1,
+            _ => {
+                self.round += 1;
+                0
+            }
+        };
+        self.turn = Some(turn);
+        Ok(&self.combatants[turn])
+    }
+}
+
+impl Display for Initiative {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.combatants.is_empty() {
+            return write!(f, "no one has rolled initiative");
+        }
+        if self.turn.is_some() {
+            writeln!(f, "round {}", self.round)?;
+        }
+        for (i, combatant) in self.combatants.iter().enumerate() {
+            let marker = if self.turn == Some(i) { "▶" } else { "  " };
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{} {} {}", marker, combatant.initiative, combatant.name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turn_order() {
+        let mut initiative = Initiative::default();
+        initiative.add(Combatant::player("1".to_string(), 12));
+        initiative.add(Combatant::npc("Goblin".to_string(), 15));
+        initiative.add(Combatant::npc("Wolf".to_string(), 12));
+        assert_eq!(initiative.next_turn().unwrap().name, "Goblin");
+        assert_eq!(initiative.next_turn().unwrap().name, "<@1>");
+
+        // Rerolling moves the player without taking their turn away.
+        initiative.add(Combatant::player("1".to_string(), 20));
+        assert_eq!(initiative.active().unwrap().name, "<@1>");
+        assert_eq!(
+            initiative.to_string(),
+            "round 1\n▶ 20 <@1>\n   15 Goblin\n   12 Wolf"
+        );
+
+        assert_eq!(initiative.next_turn().unwrap().name, "Goblin");
+        assert_eq!(initiative.next_turn().unwrap().name, "Wolf");
+        assert_eq!(initiative.next_turn().unwrap().name, "<@1>");
+        assert_eq!(initiative.round, 2);
+        assert!(Initiative::default().next_turn().is_err());
+    }
+}
@@ -1,13 +1,26 @@
+pub mod character;
+pub mod config;
+pub mod deck;
 pub mod discord;
 pub mod dynamodb;
+pub mod environments;
 pub mod error;
+pub mod export;
+pub mod initiative;
 pub mod readline;
+pub mod render;
 pub mod repl;
+pub mod roll_log;
+pub mod schema;
+pub mod sqlite;
+pub mod state;
+pub mod stats;
+pub mod tables;
+pub mod types;
 
 mod call_stack;
-mod environments;
 mod eval;
 mod parser;
-mod types;
+mod printer;
 
 rust_i18n::i18n!("../locales");
@@ -1,33 +1,83 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while, take_while1},
-    character::complete::{char, space0, space1},
-    combinator::{map_res, opt},
+    bytes::complete::{is_not, tag, take_while, take_while1},
+    character::complete::{char, digit1, multispace1, not_line_ending, space0, space1},
+    combinator::{all_consuming, consumed, eof, map, map_res, opt, recognize, rest, value, verify},
     error::ErrorKind,
     multi::{many0, separated_list0},
-    sequence::{delimited, preceded, tuple},
+    sequence::{delimited, preceded, separated_pair, terminated, tuple},
     Err::Error,
     IResult,
 };
 
 use crate::{
     error::RollerError,
-    types::{Expression, Op, Parser, Statement},
+    types::{
+        DeckKind, Expression, HelpTopic, LuckOf, OnConflict, Op, Parser, Period, ScopeRef,
+        Statement, TimeUnit,
+    },
 };
 
 // Parser Grammer
 //
-// Statement <- Roll | SetValue | Help
-// SetValue <- (Variable, Expression)
+// Statement <- Roll | SetValue | Edit | Export | Import | CopyVars | History | Revert | GmRoll
+//              | Whisper | AddGm | RemoveGm | ListGms | Config | InitRoll | InitAdd | InitNext
+//              | InitClear | InitList | CharCreate | CharUse | CharShow | CharSet | CharSkill
+//              | TableSet | TableRoll | TableShow | TableImport | DeckNew | DeckDraw | DeckPeek
+//              | DeckDiscard | DeckShuffle | DeckReshuffle | DeckShow | LogLast | LogExport | Luck
+//              | Help
+// SetValue <- (@Version)?, (Variable, Expression)
+// Edit <- Variable
+// Export <- FileName?
+// Import <- FileName?, OnConflict?
+// CopyVars <- (from:ScopeRef)?, (to:ScopeRef)?
+// ScopeRef <- home | <#[0-9]+> | #Variable
+// History <- Variable
+// Revert <- (Variable, Version)
 // Roll <- Expression
-// Help <- ()
+// GmRoll <- Expression
+// Whisper <- (Mention, Expression)
+// AddGm <- Mention
+// RemoveGm <- Mention
+// ListGms <- ()
+// Mention <- <@!?[0-9]+>
+// Config <- (Setting, Value)?
+// InitRoll <- Expression
+// InitAdd <- (Name, Expression)
+// InitNext | InitClear | InitList <- ()
+// CharCreate | CharUse <- Name
+// CharShow <- Name?
+// CharSet | CharSkill <- (Name, Expression)
+// TableSet <- (Name, Entries)
+// TableRoll | TableShow <- Name
+// TableImport <- FileName?
+// Entries <- (Range: Text), ... | (Range: Text)\n ...
+// Range <- [0-9]+ | [0-9]+-[0-9]+
+// DeckNew <- (Name, DeckKind)
+// DeckKind <- standard | tarot | (Card, ...)
+// DeckDraw | DeckPeek <- (Name, Count?)
+// DeckDiscard | DeckShuffle | DeckReshuffle | DeckShow <- Name
+// LogLast <- Count?, Mention?
+// LogExport <- FileName?, (last Period)?
+// Luck <- (channel | Mention)?, Period?
+// Period <- [0-9]+(m | h | d | w)
+// Name <- [A-z0-9_-]+
+// Help <- HelpTopic?
 //
-// Expression <- Term | DiceRollTemplate | DiceRoll | Integer | Variable
-// Term <- (DiceRoll | Integer | Variable) | (DiceRoll | Integer | Variable), Op
-// DiceRollTemplate <- (...Variable, => ,...Expression)
-// DiceRoll <- (Integer | Null), Integer
-// Integer <- [0-9]+
-// Variable <- {[A-z][A-z0-9-]+}
+// Expression <- DiceRollTemplateCall | DiceRollTemplate | Term
+// Term <- Operand | Term, Op, Operand
+//         Spaces around Op are optional, e.g. 1d20+2 or 1d20 + 2
+// Operand <- DiceRoll | Integer | Variable | Group
+// Group <- (Expression)
+// DiceRollTemplate <- (...Variable, => ,Expression)
+// DiceRollTemplateCall <- (DiceRollTemplate | Variable | Group), (...Expression)
+// DiceRoll <- (Natural | Variable | Group | Null), (Natural | Variable | Group)
+// Integer <- -?[0-9]+
+// Natural <- [0-9]+
+// Variable <- {[A-z][A-z0-9-]+} | {char.Name} | {table.Name}
+
+// How many rolls `!log` shows when it isn't given a count.
+const DEFAULT_LOG_COUNT: usize = 20;
 
 fn from_decimal(input: &str) -> Result<i64, std::num::ParseIntError> {
     input.parse::<i64>()
@@ -66,7 +116,10 @@ fn variable_ref(input: &str) -> IResult<&str, Expression> {
 }
 
 fn integer(input: &str) -> IResult<&str, Expression> {
-    let (input, number) = map_res(take_while(is_digit), from_decimal)(input)?;
+    let (input, number) = map_res(
+        recognize(tuple((opt(char('-')), take_while(is_digit)))),
+        from_decimal,
+    )(input)?;
 
     Ok((input, Expression::Integer(number)))
 }
@@ -88,8 +141,8 @@ fn term(input: &str) -> IResult<&str, Expression> {
     let (input, (expr1, exprs)) = tuple((
         sub_expression,
         many0(tuple((
-            preceded(space1, operation),
-            preceded(space1, sub_expression),
+            preceded(space0, operation),
+            preceded(space0, sub_expression),
         ))),
     ))(input)?;
 
@@ -103,12 +156,23 @@ fn parse_term(left_expr: Expression, next: (Op, Expression)) -> Expression {
 }
 
 fn dice_roll(input: &str) -> IResult<&str, Expression> {
+    let start = input;
     let (input, (count, _, sides)) = tuple((
-        opt(alt((variable_ref, integer))),
+        opt(alt((variable_ref, integer, group))),
         char('d'),
-        alt((variable_ref, integer)),
+        alt((variable_ref, integer, group)),
     ))(input)?;
 
+    // Dice can't have a negative count or sides. It's a failure rather than an
+    // error, so `-1d6` isn't read as -1 followed by some other text.
+    let negative = |expr: &Expression| matches!(expr, Expression::Integer(n) if *n < 0);
+    if count.as_ref().is_some_and(negative) || negative(&sides) {
+        return Err(nom::Err::Failure(nom::error::Error {
+            input: start,
+            code: ErrorKind::Verify,
+        }));
+    }
+
     if let Some(n) = count {
         Ok((
             input,
@@ -137,20 +201,20 @@ fn expression_list(input: &str) -> IResult<&str, Vec<Expression>> {
 }
 
 fn dice_roll_template(input: &str) -> IResult<&str, Expression> {
-    let (input, (arg_list, _, expressions)) =
+    let (input, (arg_list, _, body)) =
         tuple((arg_list, tag("=>"), delimited(lparen, expression, rparen)))(input)?;
     Ok((
         input,
         Expression::DiceRollTemplate {
             args: arg_list.iter().map(|v| v.to_string()).collect(),
-            expressions: vec![expressions],
+            expressions: vec![body],
         },
     ))
 }
 
 fn dice_roll_template_call(input: &str) -> IResult<&str, Expression> {
     let (input, (template_expression, args)) = tuple((
-        alt((dice_roll_template, variable_ref)),
+        alt((dice_roll_template, variable_ref, group)),
         delimited(char('('), expression_list, char(')')),
     ))(input)?;
 
@@ -163,8 +227,13 @@ fn dice_roll_template_call(input: &str) -> IResult<&str, Expression> {
     ))
 }
 
+// Parentheses only group; they don't appear in the parsed expression.
+fn group(input: &str) -> IResult<&str, Expression> {
+    delimited(lparen, expression, rparen)(input)
+}
+
 fn sub_expression(input: &str) -> IResult<&str, Expression> {
-    alt((dice_roll, integer, variable_ref))(input)
+    alt((dice_roll, integer, variable_ref, group))(input)
 }
 
 fn expression(input: &str) -> IResult<&str, Expression> {
@@ -185,36 +254,516 @@ fn print_env(input: &str) -> IResult<&str, Statement> {
 }
 
 fn set_value(input: &str) -> IResult<&str, Statement> {
-    let (input, (var_name, expr)) = preceded(
+    let (input, (version, var_name, (source, expr))) = preceded(
         tag("set"),
-        tuple((preceded(space1, variable), preceded(space1, expression))),
+        tuple((
+            opt(preceded(
+                char('@'),
+                map_res(digit1, |v: &str| v.parse::<u64>()),
+            )),
+            preceded(space1, variable),
+            preceded(space1, consumed(expression)),
+        )),
     )(input)?;
 
     Ok((
         input,
-        Statement::SetValue(var_name.to_string(), Box::new(expr)),
+        Statement::SetValue(
+            var_name.to_string(),
+            Box::new(expr),
+            source.trim_end().to_string(),
+            version,
+        ),
+    ))
+}
+
+fn edit(input: &str) -> IResult<&str, Statement> {
+    let (input, var_name) = preceded(tag("edit"), preceded(space1, variable))(input)?;
+
+    Ok((input, Statement::Edit(var_name.to_string())))
+}
+
+fn file_name(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| !c.is_whitespace())(input)
+}
+
+fn on_conflict(input: &str) -> IResult<&str, OnConflict> {
+    alt((
+        value(OnConflict::Skip, tag("skip")),
+        value(OnConflict::Overwrite, tag("overwrite")),
+        value(OnConflict::Rename, tag("rename")),
+    ))(input)
+}
+
+fn export(input: &str) -> IResult<&str, Statement> {
+    let (input, name) = preceded(tag("export"), opt(preceded(space1, file_name)))(input)?;
+
+    Ok((input, Statement::Export(name.map(|name| name.to_string()))))
+}
+
+// A lone word after `!import` is read as the conflict option when it is one, so
+// `!import rename` renames rather than reading a file called "rename".
+fn import(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = tag("import")(input)?;
+    if let Ok((input, on_conflict)) =
+        preceded(space1, terminated(on_conflict, tuple((space0, eof))))(input)
+    {
+        return Ok((input, Statement::Import(None, on_conflict)));
+    }
+
+    let (input, (name, on_conflict)) = tuple((
+        opt(preceded(space1, file_name)),
+        opt(preceded(space1, on_conflict)),
+    ))(input)?;
+
+    Ok((
+        input,
+        Statement::Import(
+            name.map(|name| name.to_string()),
+            on_conflict.unwrap_or(OnConflict::Skip),
+        ),
+    ))
+}
+
+// Either the home scope or a channel, given as a Discord mention or by name.
+fn scope_ref(input: &str) -> IResult<&str, ScopeRef> {
+    alt((
+        value(ScopeRef::Home, tag("home")),
+        map(delimited(tag("<#"), digit1, char('>')), |id: &str| {
+            ScopeRef::Channel(id.to_string())
+        }),
+        map(preceded(char('#'), variable), |name: &str| {
+            ScopeRef::Channel(name.to_string())
+        }),
+    ))(input)
+}
+
+fn copy_vars(input: &str) -> IResult<&str, Statement> {
+    let (rest, (from, to)) = preceded(
+        tag("copy-vars"),
+        tuple((
+            opt(preceded(space1, preceded(tag("from:"), scope_ref))),
+            opt(preceded(space1, preceded(tag("to:"), scope_ref))),
+        )),
+    )(input)?;
+    if from.is_none() && to.is_none() {
+        return Err(Error(nom::error::Error::new(input, ErrorKind::Tag)));
+    }
+
+    Ok((
+        rest,
+        Statement::CopyVars(
+            from.unwrap_or(ScopeRef::Current),
+            to.unwrap_or(ScopeRef::Current),
+        ),
     ))
 }
 
+fn history(input: &str) -> IResult<&str, Statement> {
+    let (input, var_name) = preceded(tag("history"), preceded(space1, variable))(input)?;
+
+    Ok((input, Statement::History(var_name.to_string())))
+}
+
+fn revert(input: &str) -> IResult<&str, Statement> {
+    let (input, (var_name, version)) = preceded(
+        tag("revert"),
+        tuple((
+            preceded(space1, variable),
+            preceded(space1, map_res(digit1, |v: &str| v.parse::<u64>())),
+        )),
+    )(input)?;
+
+    Ok((input, Statement::Revert(var_name.to_string(), version)))
+}
+
 fn roll(input: &str) -> IResult<&str, Statement> {
     let (input, expr) = preceded(tag("roll"), preceded(space1, expression))(input)?;
 
     Ok((input, Statement::Roll(Box::new(expr))))
 }
 
+// A Discord user mention, e.g. <@123> or <@!123>, as the user's id.
+fn mention(input: &str) -> IResult<&str, u64> {
+    delimited(
+        tag("<@"),
+        preceded(
+            opt(char('!')),
+            map_res(digit1, |id: &str| id.parse::<u64>()),
+        ),
+        char('>'),
+    )(input)
+}
+
+fn gm_roll(input: &str) -> IResult<&str, Statement> {
+    let (input, expr) = preceded(tag("gmroll"), preceded(space1, expression))(input)?;
+
+    Ok((input, Statement::GmRoll(Box::new(expr))))
+}
+
+fn whisper(input: &str) -> IResult<&str, Statement> {
+    let (input, (user, expr)) = preceded(
+        tag("whisper"),
+        tuple((preceded(space1, mention), preceded(space1, expression))),
+    )(input)?;
+
+    Ok((input, Statement::Whisper(user, Box::new(expr))))
+}
+
+fn gm(input: &str) -> IResult<&str, Statement> {
+    preceded(
+        tag("gm"),
+        preceded(
+            space1,
+            alt((
+                map(
+                    preceded(tag("add"), preceded(space1, mention)),
+                    Statement::AddGm,
+                ),
+                map(
+                    preceded(tag("remove"), preceded(space1, mention)),
+                    Statement::RemoveGm,
+                ),
+                value(Statement::ListGms, tag("list")),
+            )),
+        ),
+    )(input)
+}
+
+fn config(input: &str) -> IResult<&str, Statement> {
+    let (input, setting) = preceded(
+        tag("config"),
+        opt(preceded(
+            space1,
+            separated_pair(is_not(" \t\r\n"), space1, not_line_ending),
+        )),
+    )(input)?;
+
+    Ok((
+        input,
+        Statement::Config(
+            setting
+                .map(|(key, value): (&str, &str)| (key.to_string(), value.trim_end().to_string())),
+        ),
+    ))
+}
+
+// The name of a combatant, character or character field.
+fn name(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-')(input)
+}
+
+fn initiative(input: &str) -> IResult<&str, Statement> {
+    preceded(
+        tag("init"),
+        alt((
+            map(
+                preceded(space1, preceded(tag("roll"), preceded(space1, expression))),
+                |expr| Statement::InitRoll(Box::new(expr)),
+            ),
+            map(
+                preceded(
+                    space1,
+                    preceded(
+                        tag("add"),
+                        tuple((preceded(space1, name), preceded(space1, expression))),
+                    ),
+                ),
+                |(name, expr): (&str, Expression)| {
+                    Statement::InitAdd(name.to_string(), Box::new(expr))
+                },
+            ),
+            value(Statement::InitNext, preceded(space1, tag("next"))),
+            value(Statement::InitClear, preceded(space1, tag("clear"))),
+            value(Statement::InitList, opt(preceded(space1, tag("list")))),
+        )),
+    )(input)
+}
+
+fn character(input: &str) -> IResult<&str, Statement> {
+    let field = |command| {
+        preceded(
+            tag(command),
+            tuple((
+                preceded(space1, name),
+                preceded(space1, consumed(expression)),
+            )),
+        )
+    };
+    let (input, stmt) = preceded(
+        tag("char"),
+        preceded(
+            space1,
+            alt((
+                map(preceded(tag("create"), preceded(space1, name)), |name| {
+                    Statement::CharCreate(name.to_string())
+                }),
+                map(preceded(tag("use"), preceded(space1, name)), |name| {
+                    Statement::CharUse(name.to_string())
+                }),
+                map(
+                    preceded(tag("show"), opt(preceded(space1, name))),
+                    |name: Option<&str>| Statement::CharShow(name.map(str::to_string)),
+                ),
+                map(field("set"), |(name, (source, expr))| {
+                    Statement::CharSet(
+                        name.to_string(),
+                        Box::new(expr),
+                        source.trim_end().to_string(),
+                    )
+                }),
+                map(field("skill"), |(name, (source, expr))| {
+                    Statement::CharSkill(
+                        name.to_string(),
+                        Box::new(expr),
+                        source.trim_end().to_string(),
+                    )
+                }),
+            )),
+        ),
+    )(input)?;
+
+    Ok((input, stmt))
+}
+
+// Table entries are checked when the table is set, so the definition is kept
+// as written up to the end of the message.
+fn table(input: &str) -> IResult<&str, Statement> {
+    preceded(
+        tag("table"),
+        preceded(
+            space1,
+            alt((
+                map(
+                    preceded(
+                        tag("set"),
+                        tuple((preceded(space1, name), preceded(multispace1, rest))),
+                    ),
+                    |(name, definition): (&str, &str)| {
+                        Statement::TableSet(name.to_string(), definition.trim_end().to_string())
+                    },
+                ),
+                map(preceded(tag("roll"), preceded(space1, name)), |name| {
+                    Statement::TableRoll(name.to_string())
+                }),
+                map(preceded(tag("show"), preceded(space1, name)), |name| {
+                    Statement::TableShow(name.to_string())
+                }),
+                map(
+                    preceded(tag("import"), opt(preceded(space1, file_name))),
+                    |name: Option<&str>| Statement::TableImport(name.map(str::to_string)),
+                ),
+            )),
+        ),
+    )(input)
+}
+
+// A custom deck's cards are listed separated by commas or lines.
+fn deck_kind(input: &str) -> IResult<&str, DeckKind> {
+    let built_in = |name, kind| value(kind, terminated(tag(name), tuple((space0, eof))));
+    alt((
+        built_in("standard", DeckKind::Standard),
+        built_in("tarot", DeckKind::Tarot),
+        map(
+            verify(
+                map(rest, |cards: &str| {
+                    cards
+                        .split([',', '\n'])
+                        .map(str::trim)
+                        .filter(|card| !card.is_empty())
+                        .map(str::to_string)
+                        .collect::<Vec<_>>()
+                }),
+                |cards: &Vec<String>| !cards.is_empty(),
+            ),
+            DeckKind::Custom,
+        ),
+    ))(input)
+}
+
+fn deck(input: &str) -> IResult<&str, Statement> {
+    let count = || {
+        map(
+            opt(preceded(
+                space1,
+                map_res(digit1, |count: &str| count.parse()),
+            )),
+            |count| count.unwrap_or(1),
+        )
+    };
+    let named = |command, stmt: fn(String) -> Statement| {
+        map(
+            preceded(tag(command), preceded(space1, name)),
+            move |name| stmt(name.to_string()),
+        )
+    };
+    preceded(
+        tag("deck"),
+        preceded(
+            space1,
+            alt((
+                map(
+                    preceded(
+                        tag("new"),
+                        tuple((preceded(space1, name), preceded(multispace1, deck_kind))),
+                    ),
+                    |(name, kind)| Statement::DeckNew(name.to_string(), kind),
+                ),
+                map(
+                    preceded(tag("draw"), tuple((preceded(space1, name), count()))),
+                    |(name, count)| Statement::DeckDraw(name.to_string(), count),
+                ),
+                map(
+                    preceded(tag("peek"), tuple((preceded(space1, name), count()))),
+                    |(name, count)| Statement::DeckPeek(name.to_string(), count),
+                ),
+                named("discard", Statement::DeckDiscard),
+                named("shuffle", Statement::DeckShuffle),
+                named("reshuffle", Statement::DeckReshuffle),
+                named("show", Statement::DeckShow),
+            )),
+        ),
+    )(input)
+}
+
+fn roll_log(input: &str) -> IResult<&str, Statement> {
+    preceded(
+        tag("log"),
+        alt((
+            preceded(space1, log_export),
+            map(
+                tuple((
+                    opt(preceded(
+                        space1,
+                        preceded(
+                            tag("last"),
+                            preceded(space1, map_res(digit1, |count: &str| count.parse())),
+                        ),
+                    )),
+                    opt(preceded(space1, mention)),
+                )),
+                |(count, user)| Statement::LogLast(count.unwrap_or(DEFAULT_LOG_COUNT), user),
+            ),
+        )),
+    )(input)
+}
+
+fn log_export(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = tag("export")(input)?;
+    // A bare period isn't a file name, e.g. `!log export last 4h`.
+    if let Ok((input, period)) = terminated(last_period, tuple((space0, eof)))(input) {
+        return Ok((input, Statement::LogExport(None, Some(period))));
+    }
+
+    let (input, (name, period)) =
+        tuple((opt(preceded(space1, file_name)), opt(last_period)))(input)?;
+
+    Ok((
+        input,
+        Statement::LogExport(name.map(str::to_string), period),
+    ))
+}
+
+// ` last 4h` and the like, for commands that look back over a period.
+fn last_period(input: &str) -> IResult<&str, Period> {
+    preceded(space1, preceded(tag("last"), preceded(space1, period)))(input)
+}
+
+fn period(input: &str) -> IResult<&str, Period> {
+    map(
+        tuple((
+            map_res(digit1, |count: &str| count.parse()),
+            alt((
+                value(TimeUnit::Minutes, char('m')),
+                value(TimeUnit::Hours, char('h')),
+                value(TimeUnit::Days, char('d')),
+                value(TimeUnit::Weeks, char('w')),
+            )),
+        )),
+        |(count, unit)| Period { count, unit },
+    )(input)
+}
+
+fn luck(input: &str) -> IResult<&str, Statement> {
+    let (input, (of, period)) = preceded(
+        tag("luck"),
+        tuple((
+            opt(preceded(
+                space1,
+                alt((
+                    value(LuckOf::Channel, tag("channel")),
+                    map(mention, LuckOf::User),
+                )),
+            )),
+            opt(last_period),
+        )),
+    )(input)?;
+
+    Ok((input, Statement::Luck(of.unwrap_or(LuckOf::Me), period)))
+}
+
 fn help(input: &str) -> IResult<&str, Statement> {
-    let (input, _) = tag("help")(input)?;
+    // An unknown topic is an error rather than the overview.
+    let (input, topic) = preceded(
+        tag("help"),
+        alt((
+            map(preceded(space1, help_topic), Some),
+            value(None, tuple((space0, eof))),
+        )),
+    )(input)?;
 
-    Ok((input, Statement::Help))
+    Ok((input, Statement::Help(topic)))
+}
+
+fn help_topic(input: &str) -> IResult<&str, HelpTopic> {
+    let (rest, name) = take_while1(|c: char| c.is_ascii_alphabetic())(input)?;
+    match HelpTopic::ALL.iter().find(|topic| topic.name() == name) {
+        Some(topic) => Ok((rest, *topic)),
+        None => Err(Error(nom::error::Error::new(input, ErrorKind::Tag))),
+    }
 }
 
 fn command(input: &str) -> IResult<&str, Statement> {
-    preceded(char('!'), alt((roll, set_value, print_env, help)))(input)
+    prefixed_command("!", input)
+}
+
+// Statements starting with prefix instead of `!`, for servers where another
+// bot already answers to `!`.
+fn prefixed_command<'a>(prefix: &str, input: &'a str) -> IResult<&'a str, Statement> {
+    preceded(
+        tag(prefix),
+        alt((
+            roll, set_value, edit, export, import, copy_vars, history, revert, gm_roll, whisper,
+            gm, config, initiative, character, table, deck, roll_log, luck, print_env, help,
+        )),
+    )(input)
+}
+
+pub fn is_variable_name(input: &str) -> bool {
+    all_consuming(variable)(input).is_ok()
+}
+
+// Parses the whole of input as a single expression, e.g. a variable's saved
+// source text.
+pub fn parse_expression(input: &str) -> Result<Expression, RollerError> {
+    match all_consuming(delimited(space0, expression, space0))(input) {
+        Ok((_, expr)) => Ok(expr),
+        Err(err) => Err(RollerError::ParserError(format!("{}", err))),
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq)]
 pub struct StatementParser;
 
+impl StatementParser {
+    pub fn parse_prefixed(&self, input: &str, prefix: &str) -> Result<Statement, RollerError> {
+        match prefixed_command(prefix, input) {
+            Ok((_, stmt)) => Ok(stmt),
+            Err(err) => Err(RollerError::ParserError(format!("{}", err))),
+        }
+    }
+}
+
 impl Parser<RollerError> for StatementParser {
     fn parse(&self, input: &str) -> Result<Statement, RollerError> {
         match command(input) {
@@ -298,12 +847,45 @@ mod tests {
                 Op::Subtract
             )
         );
+
+        // Operators don't have to be spaced out.
+        let attack = Expression::Term(
+            Box::new(Expression::DiceRoll {
+                count: Box::new(Expression::Integer(1)),
+                sides: Box::new(Expression::Integer(20)),
+            }),
+            Box::new(Expression::Integer(2)),
+            Op::Add,
+        );
+        for input in ["1d20+2", "1d20 +2", "1d20+ 2", "1d20 + 2"] {
+            assert_eq!(term(input), Ok(("", attack.clone())), "{}", input);
+        }
+        assert_eq!(
+            term("{a}-1"),
+            Ok((
+                "",
+                Expression::Term(
+                    Box::new(Expression::Variable("a".to_string())),
+                    Box::new(Expression::Integer(1)),
+                    Op::Subtract
+                )
+            ))
+        );
+        assert_eq!(
+            term("1 - -1").unwrap().1,
+            Expression::Term(
+                Box::new(Expression::Integer(1)),
+                Box::new(Expression::Integer(-1)),
+                Op::Subtract
+            )
+        );
     }
 
     #[test]
     fn test_integer() {
         assert_eq!(integer("1"), Ok(("", Expression::Integer(1))));
         assert_eq!(integer("2"), Ok(("", Expression::Integer(2))));
+        assert_eq!(integer("-12"), Ok(("", Expression::Integer(-12))));
         assert_eq!(
             integer("f"),
             Err(Error(nom::error::Error {
@@ -361,7 +943,30 @@ mod tests {
                 input: "x9d420",
                 code: ErrorKind::Char
             }))
-        )
+        );
+        // Negative counts and sides aren't dice.
+        assert_eq!(
+            dice_roll("1d-3"),
+            Err(nom::Err::Failure(nom::error::Error {
+                input: "1d-3",
+                code: ErrorKind::Verify
+            }))
+        );
+        assert!(dice_roll("-1d6").is_err());
+        let parser = StatementParser;
+        assert!(parser.parse("!roll -1d6").is_err());
+        assert!(parser.parse("!roll 1d-3").is_err());
+        assert_eq!(
+            parser.parse("!roll 1d6 + -1").unwrap(),
+            Statement::Roll(Box::new(Expression::Term(
+                Box::new(Expression::DiceRoll {
+                    count: Box::new(Expression::Integer(1)),
+                    sides: Box::new(Expression::Integer(6))
+                }),
+                Box::new(Expression::Integer(-1)),
+                Op::Add
+            )))
+        );
     }
 
     #[test]
@@ -396,6 +1001,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_group() {
+        assert_eq!(
+            term("1 - (2 - 3)").unwrap().1,
+            Expression::Term(
+                Box::new(Expression::Integer(1)),
+                Box::new(Expression::Term(
+                    Box::new(Expression::Integer(2)),
+                    Box::new(Expression::Integer(3)),
+                    Op::Subtract
+                )),
+                Op::Subtract
+            )
+        );
+        assert_eq!(
+            dice_roll("(1 + {a})d(2d6)").unwrap().1,
+            Expression::DiceRoll {
+                count: Box::new(Expression::Term(
+                    Box::new(Expression::Integer(1)),
+                    Box::new(Expression::Variable("a".to_string())),
+                    Op::Add
+                )),
+                sides: Box::new(Expression::DiceRoll {
+                    count: Box::new(Expression::Integer(2)),
+                    sides: Box::new(Expression::Integer(6))
+                })
+            }
+        );
+        assert_eq!(term("(3)").unwrap().1, Expression::Integer(3));
+        // A template's body is a single expression.
+        assert!(dice_roll_template("(a) => ({a}, 1)").is_err());
+    }
+
     #[test]
     fn test_template_call() {
         assert_eq!(
@@ -411,8 +1049,25 @@ mod tests {
     fn test_set() {
         assert_eq!(
             set_value("set foo 1").unwrap().1,
-            Statement::SetValue("foo".to_string(), Box::new(Expression::Integer(1)))
+            Statement::SetValue(
+                "foo".to_string(),
+                Box::new(Expression::Integer(1)),
+                "1".to_string(),
+                None
+            )
         );
+        // `!edit` writes the version it read, so the save is turned down if the
+        // variable has changed since.
+        assert_eq!(
+            set_value("set@3 foo 1").unwrap().1,
+            Statement::SetValue(
+                "foo".to_string(),
+                Box::new(Expression::Integer(1)),
+                "1".to_string(),
+                Some(3)
+            )
+        );
+        assert!(set_value("set@ foo 1").is_err());
         assert_eq!(
             set_value("set bar 1d6").unwrap().1,
             Statement::SetValue(
@@ -420,7 +1075,9 @@ mod tests {
                 Box::new(Expression::DiceRoll {
                     count: Box::new(Expression::Integer(1)),
                     sides: Box::new(Expression::Integer(6))
-                })
+                }),
+                "1d6".to_string(),
+                None
             )
         );
         assert_eq!(
@@ -434,11 +1091,44 @@ mod tests {
                     }),
                     Box::new(Expression::Integer(1)),
                     Op::Add
-                ))
+                )),
+                "1d6 + 1".to_string(),
+                None
+            )
+        );
+        assert_eq!(
+            set_value("set attack (m) =>  (1d20 + {m})  ").unwrap().1,
+            Statement::SetValue(
+                "attack".to_string(),
+                Box::new(Expression::DiceRollTemplate {
+                    args: vec!["m".to_string()],
+                    expressions: vec![Expression::Term(
+                        Box::new(Expression::DiceRoll {
+                            count: Box::new(Expression::Integer(1)),
+                            sides: Box::new(Expression::Integer(20))
+                        }),
+                        Box::new(Expression::Variable("m".to_string())),
+                        Op::Add
+                    )]
+                }),
+                "(m) =>  (1d20 + {m})".to_string(),
+                None
             )
         );
     }
 
+    #[test]
+    fn test_parse_expression() {
+        assert_eq!(
+            parse_expression(" 1d6 ").unwrap(),
+            Expression::DiceRoll {
+                count: Box::new(Expression::Integer(1)),
+                sides: Box::new(Expression::Integer(6))
+            }
+        );
+        assert!(parse_expression("1d6 )").is_err());
+    }
+
     #[test]
     fn test_roll() {
         assert_eq!(
@@ -475,7 +1165,20 @@ mod tests {
     #[test]
     fn test_command() {
         assert_eq!(command("!print-env").unwrap().1, Statement::PrintEnv);
-        assert_eq!(command("!help").unwrap().1, Statement::Help);
+        assert_eq!(command("!help").unwrap().1, Statement::Help(None));
+        assert_eq!(
+            command("!help tables").unwrap().1,
+            Statement::Help(Some(HelpTopic::Tables))
+        );
+        assert!(StatementParser.parse("!help nonsense").is_err());
+        assert_eq!(
+            command("!history attack").unwrap().1,
+            Statement::History("attack".to_string())
+        );
+        assert_eq!(
+            command("!revert attack 3").unwrap().1,
+            Statement::Revert("attack".to_string(), 3)
+        );
         assert_eq!(
             command("!roll 1").unwrap().1,
             Statement::Roll(Box::new(Expression::Integer(1)))
@@ -504,8 +1207,202 @@ mod tests {
                 Box::new(Expression::DiceRoll {
                     count: Box::new(Expression::Integer(1)),
                     sides: Box::new(Expression::Integer(6))
+                }),
+                "1d6".to_string(),
+                None
+            )
+        );
+        assert_eq!(
+            command("!edit bar").unwrap().1,
+            Statement::Edit("bar".to_string())
+        );
+        assert_eq!(
+            command("!copy-vars from:<#1234>").unwrap().1,
+            Statement::CopyVars(ScopeRef::Channel("1234".to_string()), ScopeRef::Current)
+        );
+        assert_eq!(
+            command("!copy-vars from:#general to:home").unwrap().1,
+            Statement::CopyVars(ScopeRef::Channel("general".to_string()), ScopeRef::Home)
+        );
+        assert!(command("!copy-vars").is_err());
+        assert_eq!(command("!export").unwrap().1, Statement::Export(None));
+        assert_eq!(
+            command("!export vars.json").unwrap().1,
+            Statement::Export(Some("vars.json".to_string()))
+        );
+        assert_eq!(
+            command("!import").unwrap().1,
+            Statement::Import(None, OnConflict::Skip)
+        );
+        assert_eq!(
+            command("!import rename").unwrap().1,
+            Statement::Import(None, OnConflict::Rename)
+        );
+        assert_eq!(
+            command("!import skip.yaml overwrite").unwrap().1,
+            Statement::Import(Some("skip.yaml".to_string()), OnConflict::Overwrite)
+        );
+        assert_eq!(
+            command("!gmroll 1d20").unwrap().1,
+            Statement::GmRoll(Box::new(Expression::DiceRoll {
+                count: Box::new(Expression::Integer(1)),
+                sides: Box::new(Expression::Integer(20))
+            }))
+        );
+        assert_eq!(
+            command("!whisper <@!42> 3").unwrap().1,
+            Statement::Whisper(42, Box::new(Expression::Integer(3)))
+        );
+        assert_eq!(command("!gm add <@42>").unwrap().1, Statement::AddGm(42));
+        assert_eq!(
+            command("!gm remove <@42>").unwrap().1,
+            Statement::RemoveGm(42)
+        );
+        assert_eq!(command("!gm list").unwrap().1, Statement::ListGms);
+        assert!(command("!whisper 42 3").is_err());
+        assert_eq!(command("!config").unwrap().1, Statement::Config(None));
+        assert_eq!(
+            command("!config channels <#1> <#2> ").unwrap().1,
+            Statement::Config(Some(("channels".to_string(), "<#1> <#2>".to_string())))
+        );
+        assert_eq!(
+            StatementParser.parse_prefixed("dr?roll 1", "dr?").unwrap(),
+            Statement::Roll(Box::new(Expression::Integer(1)))
+        );
+        assert!(StatementParser.parse_prefixed("!roll 1", "?").is_err());
+        assert_eq!(
+            command("!init roll 1d20+{dex}").unwrap().1,
+            Statement::InitRoll(Box::new(Expression::Term(
+                Box::new(Expression::DiceRoll {
+                    count: Box::new(Expression::Integer(1)),
+                    sides: Box::new(Expression::Integer(20))
+                }),
+                Box::new(Expression::Variable("dex".to_string())),
+                Op::Add,
+            )))
+        );
+        assert_eq!(
+            command("!init add Goblin 2").unwrap().1,
+            Statement::InitAdd("Goblin".to_string(), Box::new(Expression::Integer(2)))
+        );
+        assert_eq!(command("!init next").unwrap().1, Statement::InitNext);
+        assert_eq!(command("!init clear").unwrap().1, Statement::InitClear);
+        assert_eq!(command("!init").unwrap().1, Statement::InitList);
+        assert_eq!(
+            command("!char create Thorn").unwrap().1,
+            Statement::CharCreate("Thorn".to_string())
+        );
+        assert_eq!(command("!char show").unwrap().1, Statement::CharShow(None));
+        assert_eq!(
+            command("!char set str_mod {char.str} - 10").unwrap().1,
+            Statement::CharSet(
+                "str_mod".to_string(),
+                Box::new(Expression::Term(
+                    Box::new(Expression::Variable("char.str".to_string())),
+                    Box::new(Expression::Integer(10)),
+                    Op::Subtract,
+                )),
+                "{char.str} - 10".to_string()
+            )
+        );
+        assert_eq!(
+            command("!table set loot\n1-3: Gold, lots of it\n4: [[1d6]] gems \n")
+                .unwrap()
+                .1,
+            Statement::TableSet(
+                "loot".to_string(),
+                "1-3: Gold, lots of it\n4: [[1d6]] gems".to_string()
+            )
+        );
+        assert_eq!(
+            command("!table roll loot").unwrap().1,
+            Statement::TableRoll("loot".to_string())
+        );
+        assert_eq!(command("!log").unwrap().1, Statement::LogLast(20, None));
+        assert_eq!(
+            command("!luck").unwrap().1,
+            Statement::Luck(LuckOf::Me, None)
+        );
+        assert_eq!(
+            command("!luck <@3> last 7d").unwrap().1,
+            Statement::Luck(
+                LuckOf::User(3),
+                Some(Period {
+                    count: 7,
+                    unit: TimeUnit::Days
+                })
+            )
+        );
+        assert_eq!(
+            command("!luck channel last 12h").unwrap().1,
+            Statement::Luck(
+                LuckOf::Channel,
+                Some(Period {
+                    count: 12,
+                    unit: TimeUnit::Hours
+                })
+            )
+        );
+        assert_eq!(
+            command("!log last 5 <@!12>").unwrap().1,
+            Statement::LogLast(5, Some(12))
+        );
+        assert_eq!(
+            command("!log <@12>").unwrap().1,
+            Statement::LogLast(20, Some(12))
+        );
+        assert_eq!(
+            command("!log export rolls.csv").unwrap().1,
+            Statement::LogExport(Some("rolls.csv".to_string()), None)
+        );
+        assert_eq!(
+            command("!log export last 4h").unwrap().1,
+            Statement::LogExport(
+                None,
+                Some(Period {
+                    count: 4,
+                    unit: TimeUnit::Hours
                 })
             )
         );
+        assert_eq!(
+            command("!deck new init standard").unwrap().1,
+            Statement::DeckNew("init".to_string(), DeckKind::Standard)
+        );
+        assert_eq!(
+            command("!deck new many\nSun, The Moon\nStar").unwrap().1,
+            Statement::DeckNew(
+                "many".to_string(),
+                DeckKind::Custom(vec![
+                    "Sun".to_string(),
+                    "The Moon".to_string(),
+                    "Star".to_string()
+                ])
+            )
+        );
+        assert_eq!(
+            command("!deck draw init").unwrap().1,
+            Statement::DeckDraw("init".to_string(), 1)
+        );
+        assert_eq!(
+            command("!deck peek init 3").unwrap().1,
+            Statement::DeckPeek("init".to_string(), 3)
+        );
+        assert_eq!(
+            command("!deck reshuffle init").unwrap().1,
+            Statement::DeckReshuffle("init".to_string())
+        );
+        assert_eq!(
+            command("!table import").unwrap().1,
+            Statement::TableImport(None)
+        );
+        assert_eq!(
+            command("!roll {table.loot} + 1").unwrap().1,
+            Statement::Roll(Box::new(Expression::Term(
+                Box::new(Expression::Variable("table.loot".to_string())),
+                Box::new(Expression::Integer(1)),
+                Op::Add,
+            )))
+        );
     }
 }
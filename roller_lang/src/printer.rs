@@ -0,0 +1,493 @@
+use std::fmt::{self, Display};
+
+use crate::types::{
+    DeckKind, Expression, LuckOf, OnConflict, Op, Period, ScopeRef, Statement, TimeUnit,
+};
+
+// Renders the AST back into source accepted by the StatementParser, such that
+// parsing the printed form of a parsed statement yields the same statement.
+
+fn write_separated<T: Display>(f: &mut fmt::Formatter, items: &[T]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    Ok(())
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Add => write!(f, "+"),
+            Op::Subtract => write!(f, "-"),
+        }
+    }
+}
+
+// Writes expr, in parentheses unless the parser reads it bare where it's
+// written.
+fn write_grouped(f: &mut fmt::Formatter, expr: &Expression, bare: bool) -> fmt::Result {
+    if bare {
+        write!(f, "{}", expr)
+    } else {
+        write!(f, "({})", expr)
+    }
+}
+
+// Whether expr can be an operand of a term without parentheses.
+fn is_operand(expr: &Expression) -> bool {
+    matches!(
+        expr,
+        Expression::DiceRoll { .. } | Expression::Integer(_) | Expression::Variable(_)
+    )
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expression::Variable(name) => write!(f, "{{{}}}", name),
+            Expression::Integer(value) => write!(f, "{}", value),
+            Expression::DiceRoll { count, sides } => {
+                let bare = |expr: &Expression| {
+                    matches!(expr, Expression::Integer(_) | Expression::Variable(_))
+                };
+                write_grouped(f, count, bare(count))?;
+                write!(f, "d")?;
+                write_grouped(f, sides, bare(sides))
+            }
+            // Terms are read left to right, so only a term on the right needs
+            // parentheses to keep its place.
+            Expression::Term(left, right, op) => {
+                let left_bare = is_operand(left) || matches!(**left, Expression::Term(..));
+                write_grouped(f, left, left_bare)?;
+                write!(f, " {} ", op)?;
+                write_grouped(f, right, is_operand(right))
+            }
+            Expression::DiceRollTemplate { args, expressions } => {
+                write!(f, "(")?;
+                write_separated(f, args)?;
+                write!(f, ") => (")?;
+                write_separated(f, expressions)?;
+                write!(f, ")")
+            }
+            Expression::DiceRollTemplateCall {
+                template_expression,
+                args,
+            } => {
+                let bare = matches!(
+                    **template_expression,
+                    Expression::DiceRollTemplate { .. } | Expression::Variable(_)
+                );
+                write_grouped(f, template_expression, bare)?;
+                write!(f, "(")?;
+                write_separated(f, args)?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl Display for OnConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OnConflict::Skip => write!(f, "skip"),
+            OnConflict::Overwrite => write!(f, "overwrite"),
+            OnConflict::Rename => write!(f, "rename"),
+        }
+    }
+}
+
+impl Display for ScopeRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScopeRef::Current => Ok(()),
+            ScopeRef::Home => write!(f, "home"),
+            ScopeRef::Channel(name) => write!(f, "#{}", name),
+        }
+    }
+}
+
+impl Display for DeckKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeckKind::Standard => write!(f, "standard"),
+            DeckKind::Tarot => write!(f, "tarot"),
+            DeckKind::Custom(cards) => write_separated(f, cards),
+        }
+    }
+}
+
+impl Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = match self.unit {
+            TimeUnit::Minutes => 'm',
+            TimeUnit::Hours => 'h',
+            TimeUnit::Days => 'd',
+            TimeUnit::Weeks => 'w',
+        };
+        write!(f, "{}{}", self.count, unit)
+    }
+}
+
+impl Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Statement::Roll(expr) => write!(f, "!roll {}", expr),
+            // Keep the author's phrasing rather than the canonical form.
+            Statement::SetValue(name, _, source, None) => write!(f, "!set {} {}", name, source),
+            Statement::SetValue(name, _, source, Some(version)) => {
+                write!(f, "!set@{} {} {}", version, name, source)
+            }
+            Statement::Edit(name) => write!(f, "!edit {}", name),
+            Statement::Export(None) => write!(f, "!export"),
+            Statement::Export(Some(name)) => write!(f, "!export {}", name),
+            Statement::Import(None, on_conflict) => write!(f, "!import {}", on_conflict),
+            Statement::Import(Some(name), on_conflict) => {
+                write!(f, "!import {} {}", name, on_conflict)
+            }
+            Statement::CopyVars(from, to) => {
+                write!(f, "!copy-vars")?;
+                if *from != ScopeRef::Current {
+                    write!(f, " from:{}", from)?;
+                }
+                if *to != ScopeRef::Current {
+                    write!(f, " to:{}", to)?;
+                }
+                Ok(())
+            }
+            Statement::History(name) => write!(f, "!history {}", name),
+            Statement::Revert(name, version) => write!(f, "!revert {} {}", name, version),
+            Statement::GmRoll(expr) => write!(f, "!gmroll {}", expr),
+            Statement::Whisper(user, expr) => write!(f, "!whisper <@{}> {}", user, expr),
+            Statement::AddGm(user) => write!(f, "!gm add <@{}>", user),
+            Statement::Config(None) => write!(f, "!config"),
+            Statement::Config(Some((key, value))) => write!(f, "!config {} {}", key, value),
+            Statement::InitRoll(expr) => write!(f, "!init roll {}", expr),
+            Statement::InitAdd(name, expr) => write!(f, "!init add {} {}", name, expr),
+            Statement::InitNext => write!(f, "!init next"),
+            Statement::InitClear => write!(f, "!init clear"),
+            Statement::InitList => write!(f, "!init list"),
+            Statement::CharCreate(name) => write!(f, "!char create {}", name),
+            Statement::CharUse(name) => write!(f, "!char use {}", name),
+            Statement::CharShow(None) => write!(f, "!char show"),
+            Statement::CharShow(Some(name)) => write!(f, "!char show {}", name),
+            Statement::CharSet(name, _, source) => write!(f, "!char set {} {}", name, source),
+            Statement::CharSkill(name, _, source) => write!(f, "!char skill {} {}", name, source),
+            Statement::TableSet(name, definition) => {
+                write!(f, "!table set {} {}", name, definition)
+            }
+            Statement::TableRoll(name) => write!(f, "!table roll {}", name),
+            Statement::TableShow(name) => write!(f, "!table show {}", name),
+            Statement::TableImport(None) => write!(f, "!table import"),
+            Statement::TableImport(Some(name)) => write!(f, "!table import {}", name),
+            Statement::DeckNew(name, kind) => write!(f, "!deck new {} {}", name, kind),
+            Statement::DeckDraw(name, count) => write!(f, "!deck draw {} {}", name, count),
+            Statement::DeckPeek(name, count) => write!(f, "!deck peek {} {}", name, count),
+            Statement::DeckDiscard(name) => write!(f, "!deck discard {}", name),
+            Statement::DeckShuffle(name) => write!(f, "!deck shuffle {}", name),
+            Statement::DeckReshuffle(name) => write!(f, "!deck reshuffle {}", name),
+            Statement::DeckShow(name) => write!(f, "!deck show {}", name),
+            Statement::LogLast(count, None) => write!(f, "!log last {}", count),
+            Statement::LogLast(count, Some(user)) => {
+                write!(f, "!log last {} <@{}>", count, user)
+            }
+            Statement::LogExport(name, period) => {
+                write!(f, "!log export")?;
+                if let Some(name) = name {
+                    write!(f, " {}", name)?;
+                }
+                if let Some(period) = period {
+                    write!(f, " last {}", period)?;
+                }
+                Ok(())
+            }
+            Statement::Luck(of, period) => {
+                write!(f, "!luck")?;
+                match of {
+                    LuckOf::Me => {}
+                    LuckOf::User(user) => write!(f, " <@{}>", user)?,
+                    LuckOf::Channel => write!(f, " channel")?,
+                }
+                if let Some(period) = period {
+                    write!(f, " last {}", period)?;
+                }
+                Ok(())
+            }
+            Statement::RemoveGm(user) => write!(f, "!gm remove <@{}>", user),
+            Statement::ListGms => write!(f, "!gm list"),
+            Statement::PrintEnv => write!(f, "!print-env"),
+            Statement::Help(None) => write!(f, "!help"),
+            Statement::Help(Some(topic)) => write!(f, "!help {}", topic.name()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::StatementParser;
+    use crate::types::{HelpTopic, Parser};
+    use proptest::prelude::*;
+
+    fn name() -> impl Strategy<Value = String> {
+        "[A-Za-z_][A-Za-z0-9_-]{0,8}"
+    }
+
+    fn file_name() -> impl Strategy<Value = String> {
+        prop_oneof![name(), "[A-Za-z0-9_./-]{1,12}", Just("skip".to_string())]
+    }
+
+    fn scope_ref() -> impl Strategy<Value = ScopeRef> {
+        prop_oneof![
+            Just(ScopeRef::Current),
+            Just(ScopeRef::Home),
+            name().prop_map(ScopeRef::Channel),
+        ]
+    }
+
+    fn integer() -> impl Strategy<Value = Expression> {
+        any::<i64>().prop_map(Expression::Integer)
+    }
+
+    fn variable() -> impl Strategy<Value = Expression> {
+        name().prop_map(Expression::Variable)
+    }
+
+    fn dice_roll() -> impl Strategy<Value = Expression> {
+        let operand = || prop_oneof![(0..=i64::MAX).prop_map(Expression::Integer), variable()];
+        (operand(), operand()).prop_map(|(count, sides)| Expression::DiceRoll {
+            count: Box::new(count),
+            sides: Box::new(sides),
+        })
+    }
+
+    // Any expression the parser could build, not just the ones it builds from
+    // source: terms nest either way, and dice counts, sides and called
+    // templates can be any expression.
+    fn expression() -> impl Strategy<Value = Expression> {
+        let leaf = prop_oneof![dice_roll(), integer(), variable()];
+        leaf.prop_recursive(3, 24, 3, |inner| {
+            let op = prop_oneof![Just(Op::Add), Just(Op::Subtract)];
+            // Dice can't have a negative count or sides.
+            let dice_operand = inner.clone().prop_filter(
+                "negative dice",
+                |expr| !matches!(expr, Expression::Integer(n) if *n < 0),
+            );
+            prop_oneof![
+                (inner.clone(), op, inner.clone()).prop_map(|(left, op, right)| {
+                    Expression::Term(Box::new(left), Box::new(right), op)
+                }),
+                (dice_operand.clone(), dice_operand).prop_map(|(count, sides)| {
+                    Expression::DiceRoll {
+                        count: Box::new(count),
+                        sides: Box::new(sides),
+                    }
+                }),
+                (prop::collection::vec(name(), 0..3), inner.clone()).prop_map(|(args, body)| {
+                    Expression::DiceRollTemplate {
+                        args,
+                        expressions: vec![body],
+                    }
+                }),
+                (inner.clone(), prop::collection::vec(inner, 0..3)).prop_map(
+                    |(template_expression, args)| Expression::DiceRollTemplateCall {
+                        template_expression: Box::new(template_expression),
+                        args,
+                    }
+                ),
+            ]
+        })
+    }
+
+    fn deck_kind() -> impl Strategy<Value = DeckKind> {
+        prop_oneof![
+            Just(DeckKind::Standard),
+            Just(DeckKind::Tarot),
+            prop::collection::vec("[!-+--~]([ -+--~]{0,8}[!-+--~])?", 1..4)
+                .prop_filter("built in deck names", |cards| {
+                    cards != &["standard"] && cards != &["tarot"]
+                })
+                .prop_map(DeckKind::Custom),
+        ]
+    }
+
+    fn period() -> impl Strategy<Value = Period> {
+        let unit = prop_oneof![
+            Just(TimeUnit::Minutes),
+            Just(TimeUnit::Hours),
+            Just(TimeUnit::Days),
+            Just(TimeUnit::Weeks),
+        ];
+        (any::<u64>(), unit).prop_map(|(count, unit)| Period { count, unit })
+    }
+
+    fn statement() -> impl Strategy<Value = Statement> {
+        prop_oneof![
+            expression().prop_map(|expr| Statement::Roll(Box::new(expr))),
+            (name(), expression(), prop::option::of(any::<u64>())).prop_map(
+                |(name, expr, version)| {
+                    let source = expr.to_string();
+                    Statement::SetValue(name, Box::new(expr), source, version)
+                }
+            ),
+            name().prop_map(Statement::Edit),
+            prop::option::of(file_name()).prop_map(Statement::Export),
+            (
+                prop::option::of(file_name()),
+                prop_oneof![
+                    Just(OnConflict::Skip),
+                    Just(OnConflict::Overwrite),
+                    Just(OnConflict::Rename)
+                ]
+            )
+                .prop_map(|(name, on_conflict)| Statement::Import(name, on_conflict)),
+            (scope_ref(), scope_ref())
+                .prop_filter("copy-vars needs a scope", |(from, to)| {
+                    *from != ScopeRef::Current || *to != ScopeRef::Current
+                })
+                .prop_map(|(from, to)| Statement::CopyVars(from, to)),
+            name().prop_map(Statement::History),
+            (name(), any::<u64>()).prop_map(|(name, version)| Statement::Revert(name, version)),
+            expression().prop_map(|expr| Statement::GmRoll(Box::new(expr))),
+            (any::<u64>(), expression())
+                .prop_map(|(user, expr)| Statement::Whisper(user, Box::new(expr))),
+            any::<u64>().prop_map(Statement::AddGm),
+            any::<u64>().prop_map(Statement::RemoveGm),
+            Just(Statement::ListGms),
+            prop::option::of(("[a-z-]{1,10}", "[!-~]([ -~]{0,10}[!-~])?"))
+                .prop_map(Statement::Config),
+            expression().prop_map(|expr| Statement::InitRoll(Box::new(expr))),
+            (name(), expression())
+                .prop_map(|(name, expr)| Statement::InitAdd(name, Box::new(expr))),
+            Just(Statement::InitNext),
+            Just(Statement::InitClear),
+            Just(Statement::InitList),
+            name().prop_map(Statement::CharCreate),
+            name().prop_map(Statement::CharUse),
+            prop::option::of(name()).prop_map(Statement::CharShow),
+            (name(), expression()).prop_map(|(name, expr)| {
+                let source = expr.to_string();
+                Statement::CharSet(name, Box::new(expr), source)
+            }),
+            (name(), expression()).prop_map(|(name, expr)| {
+                let source = expr.to_string();
+                Statement::CharSkill(name, Box::new(expr), source)
+            }),
+            (name(), "[1-9]: [!-~]([ -~]{0,10}[!-~])?")
+                .prop_map(|(name, definition)| Statement::TableSet(name, definition)),
+            name().prop_map(Statement::TableRoll),
+            name().prop_map(Statement::TableShow),
+            prop::option::of(file_name()).prop_map(Statement::TableImport),
+            (name(), deck_kind()).prop_map(|(name, kind)| Statement::DeckNew(name, kind)),
+            (name(), any::<usize>()).prop_map(|(name, count)| Statement::DeckDraw(name, count)),
+            (name(), any::<usize>()).prop_map(|(name, count)| Statement::DeckPeek(name, count)),
+            name().prop_map(Statement::DeckDiscard),
+            name().prop_map(Statement::DeckShuffle),
+            name().prop_map(Statement::DeckReshuffle),
+            name().prop_map(Statement::DeckShow),
+            (any::<usize>(), prop::option::of(any::<u64>()))
+                .prop_map(|(count, user)| Statement::LogLast(count, user)),
+            (prop::option::of(file_name()), prop::option::of(period()))
+                .prop_map(|(name, period)| Statement::LogExport(name, period)),
+            (
+                prop_oneof![
+                    Just(LuckOf::Me),
+                    any::<u64>().prop_map(LuckOf::User),
+                    Just(LuckOf::Channel),
+                ],
+                prop::option::of(period()),
+            )
+                .prop_map(|(of, period)| Statement::Luck(of, period)),
+            Just(Statement::PrintEnv),
+            prop::option::of(prop::sample::select(HelpTopic::ALL.to_vec()))
+                .prop_map(Statement::Help),
+        ]
+    }
+
+    #[test]
+    fn test_print_expression() {
+        let int = |n| Box::new(Expression::Integer(n));
+        assert_eq!(
+            Expression::Term(
+                int(1),
+                Box::new(Expression::Term(int(2), int(3), Op::Subtract)),
+                Op::Subtract
+            )
+            .to_string(),
+            "1 - (2 - 3)"
+        );
+        assert_eq!(
+            Expression::DiceRoll {
+                count: Box::new(Expression::Term(int(1), int(2), Op::Add)),
+                sides: int(6)
+            }
+            .to_string(),
+            "(1 + 2)d6"
+        );
+        assert_eq!(
+            Expression::Term(
+                Box::new(Expression::DiceRoll {
+                    count: Box::new(Expression::Integer(2)),
+                    sides: Box::new(Expression::Integer(6))
+                }),
+                Box::new(Expression::Integer(-1)),
+                Op::Subtract
+            )
+            .to_string(),
+            "2d6 - -1"
+        );
+        assert_eq!(
+            Expression::DiceRollTemplateCall {
+                template_expression: Box::new(Expression::DiceRollTemplate {
+                    args: vec!["a".to_string(), "b".to_string()],
+                    expressions: vec![Expression::Term(
+                        Box::new(Expression::DiceRoll {
+                            count: Box::new(Expression::Variable("a".to_string())),
+                            sides: Box::new(Expression::Integer(6))
+                        }),
+                        Box::new(Expression::Variable("b".to_string())),
+                        Op::Add,
+                    )]
+                }),
+                args: vec![Expression::Integer(1), Expression::Integer(10)]
+            }
+            .to_string(),
+            "(a, b) => ({a}d6 + {b})(1, 10)"
+        );
+    }
+
+    #[test]
+    fn test_print_statement() {
+        assert_eq!(Statement::PrintEnv.to_string(), "!print-env");
+        assert_eq!(Statement::Help(None).to_string(), "!help");
+        assert_eq!(
+            Statement::SetValue(
+                "foo-bar".to_string(),
+                Box::new(Expression::Variable("baz".to_string())),
+                "{baz}".to_string(),
+                None
+            )
+            .to_string(),
+            "!set foo-bar {baz}"
+        );
+        assert_eq!(
+            Statement::SetValue(
+                "foo-bar".to_string(),
+                Box::new(Expression::Integer(1)),
+                "1".to_string(),
+                Some(2)
+            )
+            .to_string(),
+            "!set@2 foo-bar 1"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_statement_round_trip(stmt in statement()) {
+            prop_assert_eq!(StatementParser.parse(&stmt.to_string()).unwrap(), stmt);
+        }
+    }
+}
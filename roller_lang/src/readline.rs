@@ -1,20 +1,78 @@
+use std::fs;
+
 use rustyline::error::ReadlineError;
 use rustyline::{DefaultEditor, Result};
 
+use crate::error::RollerError;
+use crate::render::{Renderer, TextRenderer};
 use crate::repl::{REPLContext, REPL};
-use crate::types::Environment;
+use crate::types::{Environment, Statement};
 
-pub async fn init<E: Environment + Clone>(repl: &mut REPL<E>) -> Result<()> {
+// Runs a line, reading and writing the files named by `!import`, `!export`,
+// `!log export` and `!table import`.
+async fn exec<E: Environment + Sync>(
+    repl: &mut REPL<E>,
+    ctx: &REPLContext,
+    line: &str,
+) -> std::result::Result<String, RollerError> {
+    let config = repl.config(ctx).await?;
+    let stmt = repl.parse_prefixed(line, &config.prefix)?;
+    let ctx = &ctx.clone().with_config(config);
+    match &stmt {
+        Statement::Export(Some(path)) => {
+            let document = repl.exec_statement(ctx, &stmt).await?.to_string();
+            fs::write(path, document).map_err(|err| {
+                RollerError::EvalError(format!("failed to write {}: {}", path, err))
+            })?;
+            Ok(format!("exported variables to {}", path))
+        }
+        Statement::Import(Some(path), on_conflict) => {
+            let document = fs::read_to_string(path).map_err(|err| {
+                RollerError::EvalError(format!("failed to read {}: {}", path, err))
+            })?;
+            repl.import(ctx, path, &document, *on_conflict).await
+        }
+        Statement::LogExport(Some(path), _) => {
+            let document = repl.exec_statement(ctx, &stmt).await?.to_string();
+            fs::write(path, document).map_err(|err| {
+                RollerError::EvalError(format!("failed to write {}: {}", path, err))
+            })?;
+            Ok(format!("exported the roll log to {}", path))
+        }
+        Statement::TableImport(Some(path)) => {
+            let document = fs::read_to_string(path).map_err(|err| {
+                RollerError::EvalError(format!("failed to read {}: {}", path, err))
+            })?;
+            repl.import_tables(ctx, path, &document).await
+        }
+        _ => repl
+            .exec_statement(ctx, &stmt)
+            .await
+            .map(|output| TextRenderer.render(&output)),
+    }
+}
+
+pub async fn init<E: Environment + Sync>(repl: &mut REPL<E>) -> Result<()> {
     let mut rl = DefaultEditor::new()?;
-    let ctx = &REPLContext::new("repl".to_string(), "user".to_string());
+    // Whoever runs the REPL owns it, so they can change its settings.
+    let ctx = &REPLContext::new("repl".to_string(), "user".to_string()).as_admin();
+    // Text to start the next line with, so `!edit` output can be changed in place.
+    let mut initial: Option<String> = None;
 
     loop {
-        let readline = rl.readline(">> ");
+        let readline = match initial.take() {
+            Some(text) => rl.readline_with_initial(">> ", (&text, "")),
+            None => rl.readline(">> "),
+        };
 
         match readline {
             Ok(line) => {
                 let _ = rl.add_history_entry(line.as_str());
-                match repl.exec(ctx, &line[..]).await {
+                let edit = format!("{}edit", repl.config(ctx).await.unwrap_or_default().prefix);
+                match exec(repl, ctx, &line).await {
+                    Ok(eval_result) if line.trim_start().starts_with(&edit) => {
+                        initial = Some(eval_result);
+                    }
                     Ok(eval_result) => {
                         println!("{}\n", eval_result);
                     }
@@ -0,0 +1,118 @@
+use std::fmt::{self, Display};
+
+use crate::types::{DieRoll, Output, RollResult};
+
+// Turns the result of a statement into whatever a frontend sends back, so the
+// REPL can print plain text while the bot builds embeds.
+pub trait Renderer {
+    type Rendered;
+
+    fn render(&self, output: &Output) -> Self::Rendered;
+}
+
+// The REPL's renderer: rolls, secret or not, print as their total, everything
+// else as is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TextRenderer;
+
+impl Renderer for TextRenderer {
+    type Rendered = String;
+
+    fn render(&self, output: &Output) -> String {
+        output.to_string()
+    }
+}
+
+impl Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Output::Text(text) => write!(f, "{}", text),
+            Output::Roll(roll) | Output::SecretRoll(roll, _) => write!(f, "{}", roll.total),
+        }
+    }
+}
+
+impl Display for DieRoll {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}d{} [", self.results.len(), self.sides)?;
+        for (i, result) in self.results.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", result)?;
+        }
+        write!(f, "]")
+    }
+}
+
+// The dice of a roll, one term per line, cut short with an ellipsis when it
+// won't fit in max_len bytes.
+pub fn breakdown(roll: &RollResult, max_len: usize) -> String {
+    let mut breakdown = roll
+        .dice
+        .iter()
+        .map(|die| die.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if breakdown.len() > max_len {
+        let mut end = max_len.saturating_sub('…'.len_utf8());
+        while !breakdown.is_char_boundary(end) {
+            end -= 1;
+        }
+        breakdown.truncate(end);
+        breakdown.push('…');
+    }
+    breakdown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Critical;
+
+    fn roll(dice: Vec<DieRoll>, total: i64) -> RollResult {
+        RollResult {
+            expression: "roll".to_string(),
+            dice,
+            total,
+        }
+    }
+
+    #[test]
+    fn test_breakdown() {
+        let attack = roll(
+            vec![
+                DieRoll {
+                    sides: 20,
+                    results: vec![20, 3],
+                },
+                DieRoll {
+                    sides: 6,
+                    results: vec![4],
+                },
+            ],
+            27,
+        );
+        assert_eq!(breakdown(&attack, 100), "2d20 [20, 3]\n1d6 [4]");
+        assert_eq!(breakdown(&attack, 10), "2d20 [2…");
+        assert_eq!(attack.critical(), Some(Critical::Success));
+        assert_eq!(TextRenderer.render(&Output::Roll(attack)), "27");
+
+        let fumble = roll(
+            vec![DieRoll {
+                sides: 20,
+                results: vec![1],
+            }],
+            1,
+        );
+        assert_eq!(fumble.critical(), Some(Critical::Failure));
+        let no_d20 = roll(
+            vec![DieRoll {
+                sides: 6,
+                results: vec![1],
+            }],
+            1,
+        );
+        assert_eq!(no_d20.critical(), None);
+    }
+}
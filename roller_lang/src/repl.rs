@@ -1,26 +1,84 @@
+use crate::config::Config;
 use crate::dynamodb::DDBClient;
 use crate::environments::dynamodb_environment::DynamoDBEnvironment;
+use crate::environments::file_environment::FileEnvironment;
 use crate::environments::hash_map_environment::HashMapEnvironment;
+use crate::environments::sqlite_environment::SqliteEnvironment;
 use crate::error::RollerError;
 use crate::eval::EvalVisitor;
+use crate::export::{self, Document, Format};
 use crate::parser::StatementParser;
-use crate::types::{Context, Environment, Parser, Visitor};
+use crate::render::{Renderer, TextRenderer};
+use crate::roll_log::LoggedRoll;
+use crate::sqlite::SqliteClient;
+use crate::tables;
+use crate::types::{
+    Context, Environment, OnConflict, Output, Parser, RollResult, SharedEnvironment, Statement,
+    Visitor,
+};
 use rand::rngs::StdRng;
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
+use std::sync::Arc;
+
+// Scope holding the variables a user wants available everywhere.
+pub const HOME_SCOPE: &str = "home";
 
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct REPLContext {
     repl_scope: String,
     user_id: String,
+    guild_id: Option<String>,
+    use_home_scope: bool,
+    config: Config,
+    admin: bool,
 }
 
 impl REPLContext {
+    // user_id should be something the user can't change, like their Discord id,
+    // since it's what their variables are stored under.
     pub fn new(repl_scope: String, user_id: String) -> Self {
         REPLContext {
             repl_scope,
             user_id,
+            guild_id: None,
+            use_home_scope: false,
+            config: Config::default(),
+            admin: false,
         }
     }
+
+    // Records the server the scope belongs to, giving it a guild wide scope.
+    pub fn with_guild(mut self, guild_id: String) -> Self {
+        self.guild_id = Some(guild_id);
+        self
+    }
+
+    // Falls back to the user's home scope for variables that aren't set in
+    // this one.
+    pub fn with_home_scope(mut self) -> Self {
+        self.use_home_scope = true;
+        self
+    }
+
+    // Applies the server's settings, as read by REPL::config.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    // Lets the user change the server's settings.
+    pub fn as_admin(mut self) -> Self {
+        self.admin = true;
+        self
+    }
+
+    // The context whose user_context_key is key, if it's a user's scope.
+    pub fn from_user_context_key(key: &str) -> Option<Self> {
+        let (scope, user_id) = key
+            .strip_prefix("scope:")?
+            .split_once("#scope_type:user#user:")?;
+        Some(REPLContext::new(scope.to_string(), user_id.to_string()))
+    }
 }
 
 impl Context for &REPLContext {
@@ -34,11 +92,52 @@ impl Context for &REPLContext {
     fn global_context_key(&self) -> String {
         format!("scope:{}#scope_type:global", self.repl_scope)
     }
+
+    fn guild_context_key(&self) -> Option<String> {
+        self.guild_id
+            .as_ref()
+            .map(|guild_id| format!("guild:{}#scope_type:guild", guild_id))
+    }
+
+    fn scope_context(&self, scope: &str) -> REPLContext {
+        REPLContext {
+            repl_scope: scope.to_string(),
+            user_id: self.user_id.clone(),
+            guild_id: self.guild_id.clone(),
+            use_home_scope: false,
+            config: self.config.clone(),
+            admin: self.admin,
+        }
+    }
+
+    fn user_id(&self) -> String {
+        self.user_id.clone()
+    }
+
+    fn scope_name(&self) -> String {
+        self.repl_scope.clone()
+    }
+
+    fn config(&self) -> Config {
+        self.config.clone()
+    }
+
+    fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    fn home_context(&self) -> Option<REPLContext> {
+        if self.use_home_scope && self.repl_scope != HOME_SCOPE {
+            Some(self.scope_context(HOME_SCOPE))
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct REPL<E: Environment> {
-    parser: StatementParser,
+    parser: Arc<StatementParser>,
     rng: StdRng,
     environment: E,
 }
@@ -46,32 +145,197 @@ pub struct REPL<E: Environment> {
 impl REPL<DynamoDBEnvironment> {
     pub fn new(client: DDBClient) -> Self {
         REPL {
-            parser: StatementParser,
+            parser: Arc::new(StatementParser),
             rng: StdRng::from_entropy(),
             environment: DynamoDBEnvironment::new(client),
         }
     }
 }
 
+impl REPL<SqliteEnvironment> {
+    pub fn new_sqlite(client: SqliteClient) -> Self {
+        REPL {
+            parser: Arc::new(StatementParser),
+            rng: StdRng::from_entropy(),
+            environment: SqliteEnvironment::new(client),
+        }
+    }
+}
+
+impl REPL<FileEnvironment> {
+    pub fn new_file(environment: FileEnvironment) -> Self {
+        REPL {
+            parser: Arc::new(StatementParser),
+            rng: StdRng::from_entropy(),
+            environment,
+        }
+    }
+}
+
 impl Default for REPL<HashMapEnvironment> {
     fn default() -> Self {
         REPL {
-            parser: StatementParser,
+            parser: Arc::new(StatementParser),
             rng: StdRng::from_entropy(),
             environment: HashMapEnvironment::new(),
         }
     }
 }
 
-impl<E: Environment + Clone> REPL<E> {
+impl<E: SharedEnvironment> REPL<E> {
+    // A REPL sharing this one's parser and storage, with its own RNG, for
+    // evaluating statements on another task. Cloning would copy the RNG and
+    // repeat its rolls.
+    pub fn fork(&self) -> Self {
+        REPL {
+            parser: self.parser.clone(),
+            rng: StdRng::from_entropy(),
+            environment: self.environment.clone(),
+        }
+    }
+}
+
+impl<E: Environment + Sync> REPL<E> {
+    pub fn with_environment(environment: E) -> Self {
+        REPL {
+            parser: Arc::new(StatementParser),
+            rng: StdRng::from_entropy(),
+            environment,
+        }
+    }
+
+    pub fn parse(&self, input: &str) -> Result<Statement, RollerError> {
+        self.parser
+            .parse(input)
+            .map_err(|_| RollerError::ParserError("failed to parse".to_string()))
+    }
+
+    pub fn parse_prefixed(&self, input: &str, prefix: &str) -> Result<Statement, RollerError> {
+        self.parser.parse_prefixed(input, prefix)
+    }
+
+    // The settings of ctx's server.
+    pub async fn config(&self, ctx: &REPLContext) -> Result<Config, RollerError> {
+        Config::load(&self.environment, ctx).await
+    }
+
     pub async fn exec(&mut self, ctx: &REPLContext, input: &str) -> Result<String, RollerError> {
-        match self.parser.parse(input) {
-            Ok(ast) => {
-                EvalVisitor::new(&mut self.rng, &mut self.environment, ctx)
-                    .visit_statement(&ast)
-                    .await
-            }
-            Err(_) => Err(RollerError::ParserError("failed to parse".to_string())),
+        Ok(TextRenderer.render(&self.eval(ctx, input).await?))
+    }
+
+    pub async fn eval(&mut self, ctx: &REPLContext, input: &str) -> Result<Output, RollerError> {
+        let ast = self.parse(input)?;
+        self.exec_statement(ctx, &ast).await
+    }
+
+    pub async fn exec_statement(
+        &mut self,
+        ctx: &REPLContext,
+        stmt: &Statement,
+    ) -> Result<Output, RollerError> {
+        // Each statement rolls from its own seed, drawn from the REPL's RNG, so
+        // the roll log can say how to repeat it.
+        let seed = self.rng.gen();
+        EvalVisitor::new(&mut StdRng::seed_from_u64(seed), &mut self.environment, ctx)
+            .with_seed(seed)
+            .visit_statement(stmt)
+            .await
+    }
+
+    // Like exec_statement, but leaves rolls out of the roll log, returning the
+    // seed the statement rolled from so the caller can log the roll it keeps.
+    pub async fn exec_unlogged(
+        &mut self,
+        ctx: &REPLContext,
+        stmt: &Statement,
+    ) -> Result<(Output, u64), RollerError> {
+        let seed = self.rng.gen();
+        let output = EvalVisitor::new(&mut StdRng::seed_from_u64(seed), &mut self.environment, ctx)
+            .with_seed(seed)
+            .without_log()
+            .visit_statement(stmt)
+            .await?;
+        Ok((output, seed))
+    }
+
+    // Logs a roll made by exec_unlogged from seed.
+    pub async fn log_roll(
+        &mut self,
+        ctx: &REPLContext,
+        roll: &RollResult,
+        seed: u64,
+    ) -> Result<(), RollerError> {
+        LoggedRoll::new(ctx, roll, Some(seed))
+            .log(&mut self.environment, ctx)
+            .await
+    }
+
+    // Moves the variables and state saved in one context's scope to another's,
+    // e.g. when the user id they're stored under changes.
+    pub async fn move_scope(
+        &mut self,
+        from: &REPLContext,
+        to: &REPLContext,
+    ) -> Result<u64, RollerError> {
+        self.environment.move_scope(from, to).await
+    }
+
+    // Remembers stmt as the roll repeated by the buttons on message_id.
+    pub async fn remember_roll(
+        &mut self,
+        message_id: &str,
+        stmt: &Statement,
+    ) -> Result<(), RollerError> {
+        self.environment
+            .set_pending_roll(message_id, &stmt.to_string())
+            .await
+    }
+
+    // The roll remembered for message_id's buttons, if there is one.
+    pub async fn pending_roll(&self, message_id: &str) -> Result<Option<Statement>, RollerError> {
+        self.environment
+            .pending_roll(message_id)
+            .await?
+            .map(|stmt| self.parse(&stmt))
+            .transpose()
+    }
+
+    // Names of the variables set in ctx's scope, sorted.
+    pub async fn variable_names(&self, ctx: &REPLContext) -> Result<Vec<String>, RollerError> {
+        let closure = self.environment.closure(ctx).await?;
+        let mut names: Vec<String> = closure.into_keys().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    // Merges an exported document, read from the file or attachment called name,
+    // into ctx's scope.
+    pub async fn import(
+        &mut self,
+        ctx: &REPLContext,
+        name: &str,
+        document: &str,
+        on_conflict: OnConflict,
+    ) -> Result<String, RollerError> {
+        let document = Document::parse(document, Format::from_name(name)?)?;
+        let report = export::import(&mut self.environment, ctx, &document, on_conflict).await?;
+        Ok(report.to_string())
+    }
+
+    // Saves the tables in the CSV or YAML file or attachment called name to
+    // ctx's server, replacing any with the same names.
+    pub async fn import_tables(
+        &mut self,
+        ctx: &REPLContext,
+        name: &str,
+        document: &str,
+    ) -> Result<String, RollerError> {
+        let tables = tables::read_file(name, document)?;
+        let mut names = vec![];
+        for (name, table) in &tables {
+            table.save(&mut self.environment, ctx, name).await?;
+            names.push(name.as_str());
         }
+        Ok(format!("imported {}", names.join(", ")))
     }
 }
@@ -0,0 +1,307 @@
+use std::fmt::{self, Display};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::RollerError;
+use crate::types::{Context, Environment, Global, Period, RollResult};
+
+// How long logged rolls are kept before storage that can expire them drops them.
+pub const ROLL_LOG_LIFETIME: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+pub const DEFAULT_LOG_NAME: &str = "rolls.md";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Csv,
+    Markdown,
+}
+
+impl LogFormat {
+    // Picks the format from a file or attachment name's extension.
+    pub fn from_name(name: &str) -> Result<Self, RollerError> {
+        let extension = name.rsplit_once('.').map(|(_, extension)| extension);
+        match extension
+            .map(|extension| extension.to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("csv") => Ok(LogFormat::Csv),
+            Some("md") | Some("markdown") => Ok(LogFormat::Markdown),
+            _ => Err(RollerError::EvalError(format!(
+                "{} should end in .csv or .md",
+                name
+            ))),
+        }
+    }
+}
+
+// The dice one term of a roll showed, e.g. [3, 5] on a d6.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoggedDice {
+    pub sides: i64,
+    pub results: Vec<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoggedRoll {
+    pub user: String,
+    pub channel: String,
+    pub expression: String,
+    pub dice: Vec<LoggedDice>,
+    pub total: i64,
+    // Seconds since the Unix epoch.
+    pub timestamp: u64,
+    // The seed the roll's RNG started from, when it was seeded, so the roll
+    // can be repeated.
+    pub seed: Option<u64>,
+}
+
+impl LoggedRoll {
+    // Records roll as rolled just now by ctx's user, in ctx's scope.
+    pub fn new<C: Context>(ctx: C, roll: &RollResult, seed: Option<u64>) -> Self {
+        LoggedRoll {
+            user: ctx.user_id(),
+            channel: ctx.scope_name(),
+            expression: roll.expression.clone(),
+            dice: roll
+                .dice
+                .iter()
+                .map(|die| LoggedDice {
+                    sides: die.sides,
+                    results: die.results.clone(),
+                })
+                .collect(),
+            total: roll.total,
+            timestamp: now(),
+            seed,
+        }
+    }
+
+    // Adds the roll to the log of ctx's channel, kept in its global scope.
+    pub async fn log<E: Environment, C: Context + Send>(
+        &self,
+        env: &mut E,
+        ctx: C,
+    ) -> Result<(), RollerError> {
+        env.log_roll(Global(ctx), self).await
+    }
+
+    // When the roll stops being kept, in seconds since the Unix epoch.
+    pub fn expires_at(&self) -> u64 {
+        self.timestamp + ROLL_LOG_LIFETIME.as_secs()
+    }
+
+    // Each die rolled, e.g. `d6 [3, 5] d20 [12]`.
+    fn dice(&self) -> String {
+        self.dice
+            .iter()
+            .map(|die| {
+                let results: Vec<String> = die.results.iter().map(i64::to_string).collect();
+                format!("d{} [{}]", die.sides, results.join(", "))
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Display for LoggedRoll {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} <@{}> {} = {}",
+            format_time(self.timestamp),
+            self.user,
+            self.expression,
+            self.total
+        )?;
+        if !self.dice.is_empty() {
+            write!(f, " {}", self.dice())?;
+        }
+        Ok(())
+    }
+}
+
+// Some of a channel's logged rolls, oldest first.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RollLog {
+    pub rolls: Vec<LoggedRoll>,
+}
+
+impl RollLog {
+    // The rolls logged in ctx's channel in the last period, or all that are
+    // still kept.
+    pub async fn load<E: Environment + Sync, C: Context + Send>(
+        env: &E,
+        ctx: C,
+        period: Option<Period>,
+    ) -> Result<Self, RollerError> {
+        let since = period
+            .map(|period| now().saturating_sub(period.seconds()))
+            .unwrap_or(0);
+        let rolls = env.rolls(Global(ctx), since, None).await?;
+        Ok(RollLog { rolls })
+    }
+
+    // The last count rolls logged in ctx's channel.
+    pub async fn load_last<E: Environment + Sync, C: Context + Send>(
+        env: &E,
+        ctx: C,
+        count: usize,
+    ) -> Result<Self, RollerError> {
+        let rolls = env.rolls(Global(ctx), 0, Some(count)).await?;
+        Ok(RollLog { rolls })
+    }
+
+    // The rolls made by user, or by anyone if it's None.
+    pub fn by<'a>(&'a self, user: Option<&'a str>) -> impl Iterator<Item = &'a LoggedRoll> {
+        self.rolls
+            .iter()
+            .filter(move |roll| user.is_none_or(|user| roll.user == user))
+    }
+
+    // The last count rolls made by user, or by anyone, one per line.
+    pub fn last(&self, count: usize, user: Option<&str>) -> String {
+        let rolls: Vec<&LoggedRoll> = self.by(user).collect();
+        let rolls = &rolls[rolls.len().saturating_sub(count)..];
+        if rolls.is_empty() {
+            return "no rolls have been logged".to_string();
+        }
+        rolls
+            .iter()
+            .map(|roll| roll.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn render(&self, format: LogFormat) -> String {
+        let mut document = match format {
+            LogFormat::Csv => "time,user,channel,expression,dice,total,seed\n".to_string(),
+            LogFormat::Markdown => "| Time | User | Expression | Dice | Total | Seed |\n\
+                                    | --- | --- | --- | --- | --- | --- |\n"
+                .to_string(),
+        };
+        for roll in &self.rolls {
+            let seed = roll.seed.map(|seed| seed.to_string()).unwrap_or_default();
+            let row = match format {
+                LogFormat::Csv => [
+                    format_time(roll.timestamp),
+                    roll.user.clone(),
+                    roll.channel.clone(),
+                    roll.expression.clone(),
+                    roll.dice(),
+                    roll.total.to_string(),
+                    seed,
+                ]
+                .map(|field| csv_field(&field))
+                .join(","),
+                LogFormat::Markdown => format!(
+                    "| {} | {} | {} | {} | {} | {} |",
+                    format_time(roll.timestamp),
+                    roll.user,
+                    roll.expression,
+                    roll.dice(),
+                    roll.total,
+                    seed
+                ),
+            };
+            document.push_str(&row);
+            document.push('\n');
+        }
+        document
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+// Formats seconds since the Unix epoch as a UTC date and time, e.g.
+// `2026-10-19 14:03:07 UTC`.
+pub fn format_time(timestamp: u64) -> String {
+    let (days, seconds) = (timestamp / 86400, timestamp % 86400);
+    // Days to a civil date, from Howard Hinnant's date algorithms.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roll(user: &str, total: i64) -> LoggedRoll {
+        LoggedRoll {
+            user: user.to_string(),
+            channel: "table".to_string(),
+            expression: "2d6 + 1".to_string(),
+            dice: vec![LoggedDice {
+                sides: 6,
+                results: vec![3, total - 4],
+            }],
+            total,
+            timestamp: 1_792_418_587,
+            seed: Some(7),
+        }
+    }
+
+    #[test]
+    fn test_log() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_time(951_868_799), "2000-02-29 23:59:59 UTC");
+
+        let log = RollLog {
+            rolls: [("1", 5), ("2", 6), ("1", 7)]
+                .into_iter()
+                .map(|(user, total)| roll(user, total))
+                .collect(),
+        };
+        assert_eq!(
+            log.last(1, Some("1")),
+            "2026-10-19 14:03:07 UTC <@1> 2d6 + 1 = 7 d6 [3, 3]"
+        );
+        assert_eq!(log.last(20, None).lines().count(), 3);
+        assert_eq!(log.last(20, Some("3")), "no rolls have been logged");
+
+        assert_eq!(
+            log.render(LogFormat::Csv).lines().nth(1).unwrap(),
+            "2026-10-19 14:03:07 UTC,1,table,2d6 + 1,\"d6 [3, 1]\",5,7"
+        );
+        assert_eq!(
+            log.render(LogFormat::Markdown).lines().nth(2).unwrap(),
+            "| 2026-10-19 14:03:07 UTC | 1 | 2d6 + 1 | d6 [3, 1] | 5 | 7 |"
+        );
+        assert!(LogFormat::from_name("rolls.txt").is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt::{self, Display};
+
+use serde_json::Value;
+
+use crate::error::RollerError;
+use crate::parser::parse_expression;
+use crate::types::Expression;
+
+// Version of the serialized Expression layout written by this build. Items
+// written before the layout was versioned have no version and count as 0.
+pub const CURRENT_VERSION: u32 = 1;
+
+type Migration = fn(Value) -> Result<Value, String>;
+
+// Each entry upgrades a serialized Expression by one version, so MIGRATIONS[n]
+// turns a version n value into a version n + 1 value. Add an entry here, and
+// bump CURRENT_VERSION, whenever a change to Expression or Op would stop old
+// values from deserializing.
+const MIGRATIONS: &[Migration] = &[
+    // Version 1 only started recording the version; the layout is unchanged.
+    Ok,
+];
+
+// Outcome of rewriting a store's expressions in the current layout.
+#[derive(Debug, Default, PartialEq)]
+pub struct MigrationReport {
+    pub scanned: u64,
+    pub migrated: u64,
+    // Each expression that couldn't be upgraded, and why.
+    pub failures: Vec<String>,
+}
+
+impl MigrationReport {
+    pub fn succeeded(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "scanned: {}, migrated: {}, failed: {}",
+            self.scanned,
+            self.migrated,
+            self.failures.len()
+        )?;
+        for failure in &self.failures {
+            write!(f, "\n{}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+// Upgrades a serialized Expression written at from_version to the current
+// layout and deserializes it. When the migrations can't make sense of the
+// value, the source text it was saved with is parsed again instead.
+pub fn upgrade(
+    value: Value,
+    from_version: u32,
+    source: Option<&str>,
+) -> Result<Expression, RollerError> {
+    upgrade_with(MIGRATIONS, value, from_version).or_else(|err| match source {
+        Some(source) => parse_expression(source).map_err(|_| err),
+        None => Err(err),
+    })
+}
+
+fn upgrade_with(
+    migrations: &[Migration],
+    mut value: Value,
+    from_version: u32,
+) -> Result<Expression, RollerError> {
+    if from_version as usize > migrations.len() {
+        return Err(RollerError::StorageError(format!(
+            "variable was saved with schema version {}, which is newer than this version of the bot understands",
+            from_version
+        )));
+    }
+
+    for (version, migration) in migrations.iter().enumerate().skip(from_version as usize) {
+        value = migration(value).map_err(|err| {
+            RollerError::StorageError(format!(
+                "failed to upgrade variable from schema version {}: {}",
+                version, err
+            ))
+        })?;
+    }
+
+    serde_json::from_value(value).map_err(|err| {
+        RollerError::StorageError(format!("failed to read stored variable: {}", err))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Renames the "number" expression type of a made up version 0 to "integer".
+    fn rename_number(mut value: Value) -> Result<Value, String> {
+        if value["expression_type"] == "number" {
+            value["expression_type"] = json!("integer");
+        }
+        Ok(value)
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let legacy = json!({"expression_type": "integer", "expression": 3});
+        assert_eq!(upgrade(legacy, 0, None).unwrap(), Expression::Integer(3));
+        assert_eq!(CURRENT_VERSION as usize, MIGRATIONS.len());
+
+        let old = json!({"expression_type": "number", "expression": 3});
+        assert_eq!(
+            upgrade_with(&[rename_number], old.clone(), 0).unwrap(),
+            Expression::Integer(3)
+        );
+        // Already upgraded values skip the migration.
+        assert!(upgrade_with(&[rename_number], old.clone(), 1).is_err());
+        assert!(upgrade_with(&[rename_number], old, 2).is_err());
+    }
+
+    #[test]
+    fn test_upgrade_reparses_source() {
+        let unknown = json!({"expression_type": "exploding_dice", "expression": {}});
+        assert!(upgrade(unknown.clone(), 0, None).is_err());
+        assert_eq!(
+            upgrade(unknown.clone(), 0, Some("2d6")).unwrap(),
+            Expression::DiceRoll {
+                count: Box::new(Expression::Integer(2)),
+                sides: Box::new(Expression::Integer(6))
+            }
+        );
+        assert!(upgrade(unknown, 0, Some("2d6!")).is_err());
+    }
+}
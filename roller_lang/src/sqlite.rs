@@ -0,0 +1,710 @@
+use crate::environments::file_environment::data_dir;
+use crate::roll_log::{LoggedRoll, ROLL_LOG_LIFETIME};
+use crate::schema::{self, MigrationReport, CURRENT_VERSION};
+use crate::types::{Expression, VariableVersion};
+use rusqlite::types::Type;
+use rusqlite::{ffi, params, Connection, OptionalExtension, Transaction};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const DATABASE_FILE_NAME: &str = "dice-roller.sqlite3";
+
+// Number of versions of each variable kept in its history.
+const HISTORY_LENGTH: u64 = 10;
+
+// Each entry upgrades the schema by one version. The current version is kept in
+// sqlite's user_version pragma, so only migrations newer than it are applied.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE variables (
+        scope TEXT NOT NULL,
+        var_name TEXT NOT NULL,
+        expression TEXT NOT NULL,
+        PRIMARY KEY (scope, var_name)
+    )",
+    // Rows written before this migration hold schema version 0 expressions.
+    "ALTER TABLE variables ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 0",
+    "CREATE TABLE state (
+        scope TEXT NOT NULL,
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (scope, name)
+    )",
+    // Variables are numbered from 1 as they're saved, and their last few values
+    // kept in history. Rows written before this count as version 0.
+    "ALTER TABLE variables ADD COLUMN source TEXT;
+     ALTER TABLE variables ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+     CREATE TABLE history (
+        scope TEXT NOT NULL,
+        var_name TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        expression TEXT NOT NULL,
+        schema_version INTEGER NOT NULL,
+        source TEXT,
+        PRIMARY KEY (scope, var_name, version)
+    )",
+    // State is numbered the same way, so writes based on an old read can be
+    // turned down.
+    "ALTER TABLE state ADD COLUMN version INTEGER NOT NULL DEFAULT 0",
+    // Each roll gets its own row, so rolls made at once don't contend for the
+    // channel's log.
+    "CREATE TABLE rolls (
+        scope TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        roll TEXT NOT NULL
+    );
+    CREATE INDEX rolls_by_time ON rolls (scope, timestamp)",
+];
+
+#[derive(Debug, Clone)]
+pub struct SqliteClient {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteClient {
+    pub fn new(mut connection: Connection) -> Result<Self, rusqlite::Error> {
+        migrate(&mut connection)?;
+        Ok(SqliteClient {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, rusqlite::Error> {
+        SqliteClient::new(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, rusqlite::Error> {
+        SqliteClient::new(Connection::open_in_memory()?)
+    }
+
+    // Opens the database in the same data directory the file backend uses,
+    // creating the directory if it isn't there yet.
+    pub fn with_default_path() -> Result<Self, rusqlite::Error> {
+        let path = default_path().map_err(|err| {
+            rusqlite::Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_CANTOPEN),
+                Some(format!("can't create the data directory: {}", err)),
+            )
+        })?;
+        SqliteClient::open(path)
+    }
+
+    pub fn get_expression(
+        &self,
+        scope: &str,
+        var_name: &str,
+    ) -> Result<Option<Expression>, rusqlite::Error> {
+        let row: Option<(String, u32)> = self
+            .lock()
+            .query_row(
+                "SELECT expression, schema_version FROM variables
+                 WHERE scope = ?1 AND var_name = ?2",
+                params![scope, var_name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        row.map(|(value, schema_version)| from_json(&value, schema_version))
+            .transpose()
+    }
+
+    // The source text a variable was saved with, if any.
+    pub fn get_source(
+        &self,
+        scope: &str,
+        var_name: &str,
+    ) -> Result<Option<String>, rusqlite::Error> {
+        Ok(self
+            .lock()
+            .query_row(
+                "SELECT source FROM variables WHERE scope = ?1 AND var_name = ?2",
+                params![scope, var_name],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    // The version a variable was last saved as, or 0 if it isn't set.
+    pub fn get_version(&self, scope: &str, var_name: &str) -> Result<u64, rusqlite::Error> {
+        variable_version(&self.lock(), scope, var_name)
+    }
+
+    // Saves expr, and the source text it was parsed from if there is any, as the
+    // next version of the variable, and records it in the variable's history.
+    pub fn set_expression(
+        &self,
+        scope: &str,
+        var_name: &str,
+        expr: &Expression,
+        source: Option<&str>,
+    ) -> Result<u64, rusqlite::Error> {
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        let version = variable_version(&transaction, scope, var_name)? + 1;
+        put_expression(&transaction, scope, var_name, expr, source, version)?;
+        transaction.commit()?;
+        Ok(version)
+    }
+
+    // Like set_expression, but only if the variable is still at
+    // expected_version. Returns the new version, or None if someone else has
+    // saved the variable since.
+    pub fn set_expression_version(
+        &self,
+        scope: &str,
+        var_name: &str,
+        expr: &Expression,
+        source: Option<&str>,
+        expected_version: u64,
+    ) -> Result<Option<u64>, rusqlite::Error> {
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        if variable_version(&transaction, scope, var_name)? != expected_version {
+            return Ok(None);
+        }
+        let version = expected_version + 1;
+        put_expression(&transaction, scope, var_name, expr, source, version)?;
+        transaction.commit()?;
+        Ok(Some(version))
+    }
+
+    // The retained versions of a variable, newest first.
+    pub fn get_history(
+        &self,
+        scope: &str,
+        var_name: &str,
+    ) -> Result<Vec<VariableVersion>, rusqlite::Error> {
+        let connection = self.lock();
+        let mut statement = connection.prepare(
+            "SELECT version, expression, schema_version, source FROM history
+             WHERE scope = ?1 AND var_name = ?2 ORDER BY version DESC",
+        )?;
+        let rows = statement.query_map(params![scope, var_name], |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, u32>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?;
+
+        let mut history = vec![];
+        for row in rows {
+            let (version, value, schema_version, source) = row?;
+            history.push(VariableVersion {
+                version,
+                expression: from_json(&value, schema_version)?,
+                source,
+            });
+        }
+        Ok(history)
+    }
+
+    pub fn get_all_in_scope(
+        &self,
+        scope: &str,
+    ) -> Result<HashMap<String, Expression>, rusqlite::Error> {
+        Ok(self
+            .get_all_with_sources_in_scope(scope)?
+            .into_iter()
+            .map(|(name, (expr, _))| (name, expr))
+            .collect())
+    }
+
+    // Like get_all_in_scope, along with the source text each variable was saved
+    // with.
+    pub fn get_all_with_sources_in_scope(
+        &self,
+        scope: &str,
+    ) -> Result<HashMap<String, (Expression, Option<String>)>, rusqlite::Error> {
+        let connection = self.lock();
+        let mut statement = connection.prepare(
+            "SELECT var_name, expression, schema_version, source FROM variables WHERE scope = ?1",
+        )?;
+        let rows = statement.query_map(params![scope], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, u32>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?;
+
+        let mut new_env = HashMap::new();
+        for row in rows {
+            let (var_name, value, schema_version, source) = row?;
+            new_env.insert(var_name, (from_json(&value, schema_version)?, source));
+        }
+        Ok(new_env)
+    }
+
+    pub fn get_state(&self, scope: &str, name: &str) -> Result<Option<Value>, rusqlite::Error> {
+        Ok(self.get_state_version(scope, name)?.0)
+    }
+
+    // The state saved under name, and the version it was saved as, or 0 if it
+    // hasn't been.
+    pub fn get_state_version(
+        &self,
+        scope: &str,
+        name: &str,
+    ) -> Result<(Option<Value>, u64), rusqlite::Error> {
+        let row: Option<(String, u64)> = self
+            .lock()
+            .query_row(
+                "SELECT value, version FROM state WHERE scope = ?1 AND name = ?2",
+                params![scope, name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((value, version)) = row else {
+            return Ok((None, 0));
+        };
+        let value = serde_json::from_str(&value).map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(err))
+        })?;
+        Ok((Some(value), version))
+    }
+
+    pub fn set_state(&self, scope: &str, name: &str, value: &Value) -> Result<(), rusqlite::Error> {
+        self.lock().execute(
+            "INSERT INTO state (scope, name, value, version) VALUES (?1, ?2, ?3, 1)
+             ON CONFLICT (scope, name) DO UPDATE
+             SET value = excluded.value, version = state.version + 1",
+            params![scope, name, value.to_string()],
+        )?;
+        Ok(())
+    }
+
+    // Saves value as the next version of the state, but only if it's still at
+    // expected_version. Returns false if someone else has saved it since.
+    pub fn set_state_version(
+        &self,
+        scope: &str,
+        name: &str,
+        value: &Value,
+        expected_version: u64,
+    ) -> Result<bool, rusqlite::Error> {
+        let changed = self.lock().execute(
+            "INSERT INTO state (scope, name, value, version) VALUES (?1, ?2, ?3, ?4 + 1)
+             ON CONFLICT (scope, name) DO UPDATE
+             SET value = excluded.value, version = excluded.version
+             WHERE state.version = ?4",
+            params![scope, name, value.to_string(), expected_version],
+        )?;
+        Ok(changed == 1)
+    }
+
+    // Adds a roll to scope's log, dropping rolls that have outlived the log.
+    pub fn log_roll(&self, scope: &str, roll: &LoggedRoll) -> Result<(), rusqlite::Error> {
+        let json = serde_json::to_string(roll)
+            .map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+        let expired = roll.timestamp.saturating_sub(ROLL_LOG_LIFETIME.as_secs());
+        let connection = self.lock();
+        connection.execute(
+            "INSERT INTO rolls (scope, timestamp, roll) VALUES (?1, ?2, ?3)",
+            params![scope, roll.timestamp, json],
+        )?;
+        connection.execute(
+            "DELETE FROM rolls WHERE scope = ?1 AND timestamp < ?2",
+            params![scope, expired],
+        )?;
+        Ok(())
+    }
+
+    // The rolls logged in scope at or after since, oldest first. With a limit,
+    // only the newest that many.
+    pub fn get_rolls(
+        &self,
+        scope: &str,
+        since: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<LoggedRoll>, rusqlite::Error> {
+        // A negative limit means no limit to sqlite.
+        let limit = limit.map_or(-1, |limit| limit as i64);
+        let connection = self.lock();
+        let mut statement = connection.prepare(
+            "SELECT roll FROM rolls WHERE scope = ?1 AND timestamp >= ?2
+             ORDER BY timestamp DESC, rowid DESC LIMIT ?3",
+        )?;
+        let mut rolls = statement
+            .query_map(params![scope, since, limit], |row| row.get::<_, String>(0))?
+            .map(|roll| {
+                serde_json::from_str(&roll?).map_err(|err| {
+                    rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(err))
+                })
+            })
+            .collect::<Result<Vec<LoggedRoll>, _>>()?;
+        rolls.reverse();
+        Ok(rolls)
+    }
+
+    // Every scope with a variable or state saved in it.
+    pub fn scopes(&self) -> Result<Vec<String>, rusqlite::Error> {
+        let connection = self.lock();
+        let mut statement =
+            connection.prepare("SELECT scope FROM variables UNION SELECT scope FROM state")?;
+        let scopes = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect();
+        scopes
+    }
+
+    // Moves every variable, its history and every piece of state in one scope to
+    // another, keeping whatever the target already has.
+    pub fn move_scope(&self, from: &str, to: &str) -> Result<u64, rusqlite::Error> {
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        let moved = transaction.execute(
+            "INSERT OR IGNORE INTO variables
+             (scope, var_name, expression, schema_version, source, version)
+             SELECT ?2, var_name, expression, schema_version, source, version
+             FROM variables WHERE scope = ?1",
+            params![from, to],
+        )? + transaction.execute(
+            "INSERT OR IGNORE INTO state (scope, name, value, version)
+             SELECT ?2, name, value, version FROM state WHERE scope = ?1",
+            params![from, to],
+        )?;
+        // History only follows the variables that were moved.
+        transaction.execute(
+            "INSERT OR IGNORE INTO history
+             (scope, var_name, version, expression, schema_version, source)
+             SELECT ?2, history.var_name, history.version, history.expression,
+                    history.schema_version, history.source
+             FROM history JOIN variables
+             ON variables.scope = ?2 AND variables.var_name = history.var_name
+             WHERE history.scope = ?1",
+            params![from, to],
+        )?;
+        if from != to {
+            transaction.execute("DELETE FROM variables WHERE scope = ?1", params![from])?;
+            transaction.execute("DELETE FROM history WHERE scope = ?1", params![from])?;
+            transaction.execute("DELETE FROM state WHERE scope = ?1", params![from])?;
+        }
+        transaction.commit()?;
+        Ok(moved as u64)
+    }
+
+    // Rewrites every expression saved with an older schema in the current
+    // layout. Rows that fail to upgrade are left alone and counted.
+    pub fn migrate_expressions(&self) -> Result<MigrationReport, rusqlite::Error> {
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        let mut report = MigrationReport::default();
+        {
+            let mut statement = transaction
+                .prepare("SELECT scope, var_name, expression, schema_version FROM variables")?;
+            let rows = statement
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, u32>(3)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            for (scope, var_name, value, schema_version) in rows {
+                report.scanned += 1;
+                if schema_version >= CURRENT_VERSION {
+                    continue;
+                }
+                let migrated = from_json(&value, schema_version).and_then(|expr| to_json(&expr));
+                match migrated {
+                    Ok(migrated) => {
+                        transaction.execute(
+                            "UPDATE variables SET expression = ?3, schema_version = ?4
+                             WHERE scope = ?1 AND var_name = ?2",
+                            params![scope, var_name, migrated, CURRENT_VERSION],
+                        )?;
+                        report.migrated += 1;
+                    }
+                    Err(err) => report
+                        .failures
+                        .push(format!("{} in {}: {}", var_name, scope, err)),
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(report)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        // A poisoned lock only means another task panicked mid-query; sqlite
+        // itself keeps the database consistent, so the connection is reusable.
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn variable_version(
+    connection: &Connection,
+    scope: &str,
+    var_name: &str,
+) -> Result<u64, rusqlite::Error> {
+    Ok(connection
+        .query_row(
+            "SELECT version FROM variables WHERE scope = ?1 AND var_name = ?2",
+            params![scope, var_name],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0))
+}
+
+// Writes expr as the given version of the variable, adds it to the variable's
+// history and drops the history that falls out of the retained window.
+fn put_expression(
+    transaction: &Transaction,
+    scope: &str,
+    var_name: &str,
+    expr: &Expression,
+    source: Option<&str>,
+    version: u64,
+) -> Result<(), rusqlite::Error> {
+    let value = to_json(expr)?;
+    transaction.execute(
+        "INSERT INTO variables (scope, var_name, expression, schema_version, source, version)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT (scope, var_name) DO UPDATE
+         SET expression = excluded.expression, schema_version = excluded.schema_version,
+             source = excluded.source, version = excluded.version",
+        params![scope, var_name, value, CURRENT_VERSION, source, version],
+    )?;
+    transaction.execute(
+        "INSERT OR REPLACE INTO history
+         (scope, var_name, version, expression, schema_version, source)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![scope, var_name, version, value, CURRENT_VERSION, source],
+    )?;
+    transaction.execute(
+        "DELETE FROM history WHERE scope = ?1 AND var_name = ?2 AND version <= ?3",
+        params![scope, var_name, version.saturating_sub(HISTORY_LENGTH)],
+    )?;
+    Ok(())
+}
+
+// $XDG_DATA_HOME/dice-roller/dice-roller.sqlite3.
+pub fn default_path() -> io::Result<PathBuf> {
+    let dir = data_dir()?;
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(DATABASE_FILE_NAME))
+}
+
+fn to_json(expr: &Expression) -> Result<String, rusqlite::Error> {
+    serde_json::to_string(expr)
+        .map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))
+}
+
+fn from_json(value: &str, schema_version: u32) -> Result<Expression, rusqlite::Error> {
+    let conversion_failure = |err: Box<dyn std::error::Error + Send + Sync>| {
+        rusqlite::Error::FromSqlConversionFailure(0, Type::Text, err)
+    };
+    let value = serde_json::from_str(value).map_err(|err| conversion_failure(Box::new(err)))?;
+    schema::upgrade(value, schema_version, None).map_err(|err| conversion_failure(Box::new(err)))
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), rusqlite::Error> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_migrate() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        // Re-running against an up to date database must be a no-op.
+        migrate(&mut connection).unwrap();
+
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_migrate_expressions() {
+        let client = SqliteClient::open_in_memory().unwrap();
+        client
+            .set_expression("scope", "current", &Expression::Integer(1), None)
+            .unwrap();
+        client
+            .lock()
+            .execute(
+                "INSERT INTO variables (scope, var_name, expression) VALUES
+                 ('scope', 'legacy', '{\"expression_type\":\"integer\",\"expression\":2}'),
+                 ('scope', 'broken', '{\"expression_type\":\"unknown\"}')",
+                [],
+            )
+            .unwrap();
+
+        // Legacy rows are readable before they're migrated.
+        assert_eq!(
+            client.get_expression("scope", "legacy").unwrap(),
+            Some(Expression::Integer(2))
+        );
+        let report = client.migrate_expressions().unwrap();
+        assert_eq!((report.scanned, report.migrated), (3, 1));
+        assert_eq!(report.failures.len(), 1);
+        assert!(report.failures[0].starts_with("broken in scope: "));
+        assert!(!report.succeeded());
+        let schema_version: u32 = client
+            .lock()
+            .query_row(
+                "SELECT schema_version FROM variables WHERE var_name = 'legacy'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(schema_version, CURRENT_VERSION);
+    }
+
+    #[test]
+    fn test_history() {
+        let client = SqliteClient::open_in_memory().unwrap();
+        for i in 1..=12 {
+            client
+                .set_expression("scope", "a", &Expression::Integer(i), None)
+                .unwrap();
+        }
+        client
+            .set_expression("scope", "a", &Expression::Integer(13), Some("13"))
+            .unwrap();
+        client
+            .set_expression("scope", "b", &Expression::Integer(0), None)
+            .unwrap();
+
+        let history = client.get_history("scope", "a").unwrap();
+        let versions: Vec<u64> = history.iter().map(|version| version.version).collect();
+        assert_eq!(versions, (4..=13).rev().collect::<Vec<u64>>());
+        assert_eq!(history[0].source, Some("13".to_string()));
+        assert_eq!(history[1].expression, Expression::Integer(12));
+        assert_eq!(
+            client.get_source("scope", "a").unwrap(),
+            Some("13".to_string())
+        );
+        assert_eq!(client.get_source("scope", "b").unwrap(), None);
+
+        // History moves along with its variable.
+        client.move_scope("scope", "other").unwrap();
+        assert_eq!(client.get_history("other", "a").unwrap().len(), 10);
+        assert!(client.get_history("scope", "a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_state() {
+        let client = SqliteClient::open_in_memory().unwrap();
+        assert_eq!(client.get_state("scope", "gms").unwrap(), None);
+        client
+            .set_state("scope", "gms", &serde_json::json!([1]))
+            .unwrap();
+        client
+            .set_state("scope", "gms", &serde_json::json!([1, 2]))
+            .unwrap();
+        assert_eq!(
+            client.get_state("scope", "gms").unwrap(),
+            Some(serde_json::json!([1, 2]))
+        );
+        assert_eq!(client.get_state("other", "gms").unwrap(), None);
+
+        // A write based on version 2 goes through once; the second is stale.
+        assert_eq!(client.get_state_version("scope", "gms").unwrap().1, 2);
+        for (value, saved) in [(json!([3]), true), (json!([4]), false)] {
+            assert_eq!(
+                client.set_state_version("scope", "gms", &value, 2).unwrap(),
+                saved
+            );
+        }
+        assert_eq!(
+            client.get_state_version("scope", "gms").unwrap(),
+            (Some(json!([3])), 3)
+        );
+        // State that isn't there yet is at version 0.
+        assert!(client
+            .set_state_version("other", "gms", &json!([]), 0)
+            .unwrap());
+        assert!(!client
+            .set_state_version("other", "gms", &json!([]), 0)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_rolls() {
+        let client = SqliteClient::open_in_memory().unwrap();
+        let roll = |total: i64, timestamp: u64| LoggedRoll {
+            user: "1".to_string(),
+            channel: "channel".to_string(),
+            expression: "d6".to_string(),
+            dice: Vec::new(),
+            total,
+            timestamp,
+            seed: None,
+        };
+        for (total, timestamp) in [(1, 100), (2, 200), (3, 200)] {
+            client.log_roll("scope", &roll(total, timestamp)).unwrap();
+        }
+        let totals = |since, limit| -> Vec<i64> {
+            client
+                .get_rolls("scope", since, limit)
+                .unwrap()
+                .iter()
+                .map(|roll| roll.total)
+                .collect()
+        };
+        assert_eq!(totals(0, None), [1, 2, 3]);
+        assert_eq!(totals(150, None), [2, 3]);
+        assert_eq!(totals(0, Some(2)), [2, 3]);
+        assert!(client.get_rolls("other", 0, None).unwrap().is_empty());
+
+        // Logging a roll drops those older than the log's lifetime.
+        let later = 100 + ROLL_LOG_LIFETIME.as_secs() + 1;
+        client.log_roll("scope", &roll(4, later)).unwrap();
+        assert_eq!(totals(0, None), [2, 3, 4]);
+    }
+
+    #[test]
+    fn test_expression_version() {
+        let client = SqliteClient::open_in_memory().unwrap();
+        assert_eq!(client.get_version("scope", "a").unwrap(), 0);
+        client
+            .set_expression("scope", "a", &Expression::Integer(1), None)
+            .unwrap();
+        assert_eq!(
+            client
+                .set_expression_version("scope", "a", &Expression::Integer(2), Some("2"), 1)
+                .unwrap(),
+            Some(2)
+        );
+        // Someone who read version 1 too is turned down.
+        assert_eq!(
+            client
+                .set_expression_version("scope", "a", &Expression::Integer(3), None, 1)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            client.get_expression("scope", "a").unwrap(),
+            Some(Expression::Integer(2))
+        );
+        assert_eq!(client.get_history("scope", "a").unwrap().len(), 2);
+    }
+}
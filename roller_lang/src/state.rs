@@ -0,0 +1,50 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::RollerError;
+use crate::types::{Context, Environment};
+
+// Reads the state saved under name as a T, along with the version it was saved
+// as. what names it in errors, e.g. "deck".
+pub async fn load<T, E, C>(
+    env: &E,
+    ctx: C,
+    name: &str,
+    what: &str,
+) -> Result<(Option<T>, u64), RollerError>
+where
+    T: DeserializeOwned,
+    E: Environment + Sync,
+    C: Context + Send,
+{
+    let (value, version) = env.versioned_state(ctx, name).await?;
+    let value = value
+        .map(serde_json::from_value)
+        .transpose()
+        .map_err(|err| RollerError::StorageError(format!("invalid {}: {}", what, err)))?;
+    Ok((value, version))
+}
+
+// Saves value under name. State that was loaded at a version is only saved if
+// nobody has saved it since, failing with a ConflictError otherwise; state that
+// wasn't loaded replaces whatever is there.
+pub async fn save<T, E, C>(
+    env: &mut E,
+    ctx: C,
+    name: &str,
+    what: &str,
+    value: &T,
+    version: Option<u64>,
+) -> Result<(), RollerError>
+where
+    T: Serialize,
+    E: Environment,
+    C: Context + Send,
+{
+    let value = serde_json::to_value(value)
+        .map_err(|err| RollerError::StorageError(format!("invalid {}: {}", what, err)))?;
+    match version {
+        Some(version) => env.set_state_version(ctx, name, &value, version).await,
+        None => env.set_state(ctx, name, &value).await,
+    }
+}
@@ -0,0 +1,179 @@
+use std::fmt::{self, Display};
+
+use crate::roll_log::LoggedRoll;
+
+// The chi-squared statistic a fair d20's 20 faces, with 19 degrees of freedom,
+// only exceed 5% and 1% of the time.
+const CHI_SQUARED_5_PERCENT: f64 = 30.144;
+const CHI_SQUARED_1_PERCENT: f64 = 36.191;
+// Fewer d20s than this expect under 5 of each face, too few for the check.
+const MIN_FAIRNESS_D20S: u64 = 100;
+const BAR_WIDTH: u64 = 10;
+
+// What a set of logged rolls showed, compared with what fair dice, sampled
+// uniformly like handle_roll does, would be expected to show.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stats {
+    pub rolls: usize,
+    pub dice: u64,
+    // The faces shown by every die, added up, and what they'd be expected to
+    // add up to.
+    pub total: i64,
+    pub expected: f64,
+    // How many times each face of a d20 came up, from 1 to 20.
+    pub d20s: [u64; 20],
+}
+
+impl Stats {
+    pub fn new<'a>(rolls: impl IntoIterator<Item = &'a LoggedRoll>) -> Self {
+        let mut stats = Stats::default();
+        for roll in rolls {
+            stats.rolls += 1;
+            for die in &roll.dice {
+                for result in &die.results {
+                    stats.dice += 1;
+                    stats.total += result;
+                    stats.expected += (die.sides + 1) as f64 / 2.0;
+                    if die.sides == 20 && (1..=20).contains(result) {
+                        stats.d20s[*result as usize - 1] += 1;
+                    }
+                }
+            }
+        }
+        stats
+    }
+
+    pub fn d20_count(&self) -> u64 {
+        self.d20s.iter().sum()
+    }
+
+    pub fn d20_average(&self) -> Option<f64> {
+        let count = self.d20_count();
+        let total: u64 = (1..=20).zip(self.d20s).map(|(face, n)| face * n).sum();
+        (count > 0).then(|| total as f64 / count as f64)
+    }
+
+    // Pearson's chi-squared statistic for the d20s against a uniform
+    // distribution, or None if too few were rolled for it to mean much.
+    pub fn chi_squared(&self) -> Option<f64> {
+        let count = self.d20_count();
+        if count < MIN_FAIRNESS_D20S {
+            return None;
+        }
+        let expected = count as f64 / 20.0;
+        Some(
+            self.d20s
+                .iter()
+                .map(|observed| (*observed as f64 - expected).powi(2) / expected)
+                .sum(),
+        )
+    }
+}
+
+// e.g. "1 roll" or "3 rolls".
+fn counted(count: u64, one: &str, many: &str) -> String {
+    format!("{} {}", count, if count == 1 { one } else { many })
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.rolls == 0 {
+            return write!(f, "no rolls");
+        }
+        write!(
+            f,
+            "{} of {}, adding up to {} against {:.1} expected",
+            counted(self.rolls as u64, "roll", "rolls"),
+            counted(self.dice, "die", "dice"),
+            self.total,
+            self.expected
+        )?;
+        let count = self.d20_count();
+        let Some(average) = self.d20_average() else {
+            return write!(f, "\nno d20s rolled");
+        };
+        write!(
+            f,
+            "\n{} averaging {:.2} against 10.50 expected\n\
+             {} and {}, against {:.2} of each expected",
+            counted(count, "d20", "d20s"),
+            average,
+            counted(self.d20s[19], "crit", "crits"),
+            counted(self.d20s[0], "fumble", "fumbles"),
+            count as f64 / 20.0
+        )?;
+
+        let most = self.d20s.iter().max().copied().unwrap_or(0).max(1);
+        for (face, n) in self.d20s.iter().enumerate().rev() {
+            let bar = "▇".repeat(((n * BAR_WIDTH).div_ceil(most)) as usize);
+            write!(f, "\n{:>2} {} {}", face + 1, bar, n)?;
+        }
+
+        match self.chi_squared() {
+            Some(chi_squared) => write!(
+                f,
+                "\nχ² = {:.1} with 19 degrees of freedom: {}",
+                chi_squared,
+                if chi_squared < CHI_SQUARED_5_PERCENT {
+                    "nothing fair dice wouldn't roll"
+                } else if chi_squared < CHI_SQUARED_1_PERCENT {
+                    "fair dice would roll this less than 1 time in 20"
+                } else {
+                    "fair dice would roll this less than 1 time in 100"
+                }
+            ),
+            None => write!(
+                f,
+                "\nroll {} more d20s for a fairness check",
+                MIN_FAIRNESS_D20S - count
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roll_log::LoggedDice;
+
+    fn roll(sides: i64, results: Vec<i64>) -> LoggedRoll {
+        LoggedRoll {
+            user: "1".to_string(),
+            channel: "table".to_string(),
+            expression: format!("{}d{}", results.len(), sides),
+            total: results.iter().sum(),
+            dice: vec![LoggedDice { sides, results }],
+            timestamp: 0,
+            seed: None,
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        assert_eq!(Stats::new(&[]).to_string(), "no rolls");
+
+        let rolls = [roll(20, vec![20, 20, 1]), roll(6, vec![6, 5])];
+        let stats = Stats::new(&rolls);
+        assert_eq!((stats.rolls, stats.dice, stats.total), (2, 5, 52));
+        assert_eq!(stats.expected, 38.5);
+        assert_eq!(stats.d20_average(), Some(41.0 / 3.0));
+        let text = stats.to_string();
+        assert!(text.starts_with(
+            "2 rolls of 5 dice, adding up to 52 against 38.5 expected\n\
+             3 d20s averaging 13.67 against 10.50 expected\n\
+             2 crits and 1 fumble, against 0.15 of each expected\n\
+             20 ▇▇▇▇▇▇▇▇▇▇ 2\n\
+             19  0"
+        ));
+        assert!(text.contains("\n 1 ▇▇▇▇▇ 1\n"));
+        assert!(text.ends_with("roll 97 more d20s for a fairness check"));
+
+        // Every face equally often is as fair as it gets; only ever rolling
+        // 20s isn't.
+        let fair: Vec<LoggedRoll> = (1..=20).map(|face| roll(20, vec![face; 5])).collect();
+        assert_eq!(Stats::new(&fair).chi_squared(), Some(0.0));
+        let cursed = Stats::new(&[roll(20, vec![20; 100])]);
+        assert_eq!(cursed.chi_squared(), Some(1900.0));
+        assert!(cursed.to_string().ends_with("less than 1 time in 100"));
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+
+use crate::error::RollerError;
+use crate::state;
+use crate::types::{Context, Environment, Guild};
+
+// Variables starting with this, e.g. {table.loot}, roll on a table. In a
+// table's entries they're replaced by the text rolled; in dice expressions the
+// text rolled is itself rolled as an expression.
+pub const TABLE_PREFIX: &str = "table.";
+const TABLE_REF: &str = "{table.";
+
+// Dice expressions embedded in an entry, e.g. `[[2d6]] goblins`.
+pub const INLINE_ROLL_START: &str = "[[";
+pub const INLINE_ROLL_END: &str = "]]";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub low: i64,
+    pub high: i64,
+    pub text: String,
+}
+
+// Entries keyed by ranges of a die roll, from 1 up to the table's size, so an
+// entry's weight is the width of its range.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Table {
+    pub entries: Vec<Entry>,
+    // The version the table was loaded at. A new table replaces any table of
+    // the same name.
+    #[serde(skip)]
+    pub version: Option<u64>,
+}
+
+fn invalid(message: String) -> RollerError {
+    RollerError::EvalError(message)
+}
+
+fn state_name(name: &str) -> String {
+    format!("table:{}", name)
+}
+
+// Reads a range like `1-3` or `6`.
+fn parse_range(range: &str) -> Option<(i64, i64)> {
+    let range = range.trim();
+    let (low, high) = range.split_once('-').unwrap_or((range, range));
+    let (low, high) = (low.trim().parse().ok()?, high.trim().parse().ok()?);
+    (low <= high).then_some((low, high))
+}
+
+// Splits `1-3: Goblins` into its range and text.
+fn parse_entry(entry: &str) -> Option<Entry> {
+    let (range, text) = entry.split_once(':')?;
+    let (low, high) = parse_range(range)?;
+    Some(Entry {
+        low,
+        high,
+        text: text.trim().to_string(),
+    })
+}
+
+// A piece of an entry's text: plain text, a dice expression to roll, or the
+// name of a table to roll on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Part<'a> {
+    Text(&'a str),
+    Roll(&'a str),
+    Table(&'a str),
+}
+
+impl Entry {
+    pub fn parts(&self) -> Vec<Part<'_>> {
+        let mut parts = vec![];
+        let mut rest = self.text.as_str();
+        loop {
+            // Whichever of `[[...]]` and `{table....}` comes first.
+            let next = [(INLINE_ROLL_START, INLINE_ROLL_END), (TABLE_REF, "}")]
+                .into_iter()
+                .filter_map(|(start, end)| {
+                    let at = rest.find(start)?;
+                    let len = rest[at + start.len()..].find(end)?;
+                    Some((at, start, len, end))
+                })
+                .min_by_key(|(at, ..)| *at);
+            let Some((at, start, len, end)) = next else {
+                if !rest.is_empty() {
+                    parts.push(Part::Text(rest));
+                }
+                return parts;
+            };
+            if at > 0 {
+                parts.push(Part::Text(&rest[..at]));
+            }
+            let inner = &rest[at + start.len()..at + start.len() + len];
+            parts.push(if start == TABLE_REF {
+                Part::Table(inner)
+            } else {
+                Part::Roll(inner)
+            });
+            rest = &rest[at + start.len() + len + end.len()..];
+        }
+    }
+}
+
+impl Table {
+    // Checks the entries cover 1 up to the table's size without gaps or
+    // overlaps, putting them in order.
+    pub fn new(mut entries: Vec<Entry>) -> Result<Self, RollerError> {
+        entries.sort_by_key(|entry| entry.low);
+        let mut next = 1;
+        for entry in &entries {
+            if entry.low != next {
+                return Err(invalid(format!(
+                    "table entries should cover every roll from 1 up, but {} is {}",
+                    next,
+                    if entry.low < next {
+                        "covered twice"
+                    } else {
+                        "missing"
+                    }
+                )));
+            }
+            next = entry.high + 1;
+        }
+        if entries.is_empty() {
+            return Err(invalid("tables need at least one entry".to_string()));
+        }
+        Ok(Table {
+            entries,
+            version: None,
+        })
+    }
+
+    // Reads entries written as `1-3: Goblins, 4-5: Wolves`, or one per line.
+    // Commas that aren't followed by a range are part of an entry's text.
+    pub fn parse(definition: &str) -> Result<Self, RollerError> {
+        let mut entries: Vec<String> = vec![];
+        for line in definition.lines() {
+            for (i, part) in line.split(',').enumerate() {
+                match entries.last_mut() {
+                    Some(entry) if i > 0 && parse_entry(part).is_none() => {
+                        entry.push(',');
+                        entry.push_str(part);
+                    }
+                    _ if part.trim().is_empty() => {}
+                    _ => entries.push(part.to_string()),
+                }
+            }
+        }
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                parse_entry(entry)
+                    .ok_or_else(|| invalid(format!("{} should look like 1-3: text", entry.trim())))
+            })
+            .collect::<Result<_, _>>()?;
+        Table::new(entries)
+    }
+
+    // Reads a CSV file of range and text columns. A header row is skipped.
+    pub fn from_csv(document: &str) -> Result<Self, RollerError> {
+        let mut entries = vec![];
+        for (i, line) in document.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (range, text) = line.split_once(',').unwrap_or((line, ""));
+            let Some((low, high)) = parse_range(range) else {
+                if i == 0 {
+                    continue;
+                }
+                return Err(invalid(format!("line {} should start with a range", i + 1)));
+            };
+            entries.push(Entry {
+                low,
+                high,
+                text: unquote(text.trim()),
+            });
+        }
+        Table::new(entries)
+    }
+
+    // Reads a YAML file mapping table names to their entries, e.g.
+    //
+    // encounters:
+    //   1-3: Goblins
+    //   4-5: Wolves
+    pub fn from_yaml(document: &str) -> Result<Vec<(String, Self)>, RollerError> {
+        let document: Value = serde_yaml::from_str(document)
+            .map_err(|err| invalid(format!("invalid tables: {}", err)))?;
+        let Value::Mapping(tables) = document else {
+            return Err(invalid("tables should map names to entries".to_string()));
+        };
+        let mut parsed = vec![];
+        for (name, entries) in tables {
+            let name = yaml_text(&name)
+                .ok_or_else(|| invalid("table names should be text".to_string()))?;
+            let Value::Mapping(entries) = entries else {
+                return Err(invalid(format!("{} should map ranges to entries", name)));
+            };
+            let entries = entries
+                .iter()
+                .map(|(range, text)| {
+                    let range = yaml_text(range).and_then(|range| parse_range(&range));
+                    match (range, yaml_text(text)) {
+                        (Some((low, high)), Some(text)) => Ok(Entry { low, high, text }),
+                        _ => Err(invalid(format!(
+                            "{} has an entry that isn't range: text",
+                            name
+                        ))),
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+            parsed.push((name, Table::new(entries)?));
+        }
+        Ok(parsed)
+    }
+
+    pub fn size(&self) -> i64 {
+        self.entries.last().map(|entry| entry.high).unwrap_or(0)
+    }
+
+    pub fn entry(&self, roll: i64) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.low <= roll && roll <= entry.high)
+    }
+
+    // The names of the tables this one's entries roll on.
+    pub fn refs(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .flat_map(|entry| entry.parts())
+            .filter_map(|part| match part {
+                Part::Table(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub async fn load<E: Environment + Sync, C: Context + Send>(
+        env: &E,
+        ctx: C,
+        name: &str,
+    ) -> Result<Self, RollerError> {
+        match state::load(env, Guild(ctx), &state_name(name), "table").await? {
+            (Some(table), version) => Ok(Table {
+                version: Some(version),
+                ..table
+            }),
+            (None, _) => Err(invalid(format!("there's no table called {}", name))),
+        }
+    }
+
+    // Saves the table as name, unless it would end up rolling on itself.
+    pub async fn save<E: Environment + Sync, C: Context + Copy + Send>(
+        &self,
+        env: &mut E,
+        ctx: C,
+        name: &str,
+    ) -> Result<(), RollerError> {
+        let mut pending = self.refs();
+        let mut seen = HashSet::new();
+        while let Some(table) = pending.pop() {
+            if table == name {
+                return Err(invalid(format!("{} can't roll on itself", name)));
+            }
+            if seen.insert(table.clone()) {
+                if let Ok(table) = Table::load(env, ctx, &table).await {
+                    pending.extend(table.refs());
+                }
+            }
+        }
+
+        state::save(
+            env,
+            Guild(ctx),
+            &state_name(name),
+            "table",
+            self,
+            self.version,
+        )
+        .await
+    }
+}
+
+// Reads the tables in an imported file: a CSV file holds one table, named
+// after the file, and a YAML file holds any number.
+pub fn read_file(file_name: &str, document: &str) -> Result<Vec<(String, Table)>, RollerError> {
+    let (stem, extension) = file_name
+        .rsplit_once('.')
+        .map(|(stem, extension)| (stem, extension.to_ascii_lowercase()))
+        .unwrap_or((file_name, String::new()));
+    let tables = match extension.as_str() {
+        "csv" => {
+            let name = stem.rsplit(['/', '\\']).next().unwrap_or(stem);
+            vec![(name.to_string(), Table::from_csv(document)?)]
+        }
+        "yaml" | "yml" => Table::from_yaml(document)?,
+        _ => {
+            return Err(invalid(format!(
+                "{} should end in .csv, .yaml or .yml",
+                file_name
+            )))
+        }
+    };
+    // Names that can't be written in `!table roll` couldn't be rolled on.
+    if let Some((name, _)) = tables.iter().find(|(name, _)| {
+        name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    }) {
+        return Err(invalid(format!(
+            "{} can only use letters, numbers, _ and - in its name",
+            name
+        )));
+    }
+    Ok(tables)
+}
+
+fn unquote(text: &str) -> String {
+    match text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+    {
+        Some(text) => text.replace("\"\"", "\""),
+        None => text.to_string(),
+    }
+}
+
+fn yaml_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+impl Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            if entry.low == entry.high {
+                write!(f, "{}: {}", entry.low, entry.text)?;
+            } else {
+                write!(f, "{}-{}: {}", entry.low, entry.high, entry.text)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let table =
+            Table::parse("4-5: Wolves, 1-3: Goblins, led by a chief\n6: {table.boss}").unwrap();
+        assert_eq!(
+            table.to_string(),
+            "1-3: Goblins, led by a chief\n4-5: Wolves\n6: {table.boss}"
+        );
+        assert_eq!(table.size(), 6);
+        assert_eq!(table.entry(5).unwrap().text, "Wolves");
+        assert_eq!(table.refs(), vec!["boss".to_string()]);
+        assert_eq!(
+            Table::parse("1: [[1d6]] {table.a}s and {x} [[2d4 + 1]]!")
+                .unwrap()
+                .entries[0]
+                .parts(),
+            vec![
+                Part::Roll("1d6"),
+                Part::Text(" "),
+                Part::Table("a"),
+                Part::Text("s and {x} "),
+                Part::Roll("2d4 + 1"),
+                Part::Text("!"),
+            ]
+        );
+
+        assert!(Table::parse("1-3: Goblins, 5: Wolves").is_err());
+        assert!(Table::parse("1-3: Goblins, 3: Wolves").is_err());
+        assert!(Table::parse("Goblins").is_err());
+
+        let csv =
+            Table::from_csv("range,result\n1-2,\"Gold, \"\"lots\"\"\"\n3,[[1d6]] gems\n").unwrap();
+        assert_eq!(csv.to_string(), "1-2: Gold, \"lots\"\n3: [[1d6]] gems");
+
+        let yaml = Table::from_yaml("loot:\n  1-2: Gold\n  3: 4\nweather:\n  1: Rain\n").unwrap();
+        assert_eq!(yaml[0].0, "loot");
+        assert_eq!(yaml[0].1.to_string(), "1-2: Gold\n3: 4");
+        assert_eq!(yaml[1].1.size(), 1);
+
+        let file = read_file("tables/Loot.CSV", "1,Gold").unwrap();
+        assert_eq!(file[0].0, "Loot");
+        assert!(read_file("loot.txt", "1,Gold").is_err());
+        assert!(read_file("loot.yaml", "big loot:\n  1: Gold").is_err());
+    }
+}
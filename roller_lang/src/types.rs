@@ -1,12 +1,180 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
 
+use crate::config::Config;
+use crate::error::RollerError;
+use crate::repl::{REPLContext, HOME_SCOPE};
+use crate::roll_log::LoggedRoll;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Statement {
     Roll(Box<Expression>),
-    SetValue(String, Box<Expression>),
+    // The variable, its expression, the source text the expression was parsed
+    // from, and the version `!edit` read it at, if the save should be turned
+    // down once someone else has changed it.
+    SetValue(String, Box<Expression>, String, Option<u64>),
+    Edit(String),
+    // Optionally names the file or attachment to write to or read from.
+    Export(Option<String>),
+    Import(Option<String>, OnConflict),
+    // Copies variables from one scope to another.
+    CopyVars(ScopeRef, ScopeRef),
+    History(String),
+    Revert(String, u64),
+    // Rolls only the roller and the channel's GMs get to see.
+    GmRoll(Box<Expression>),
+    // A roll only the roller and the given user get to see.
+    Whisper(u64, Box<Expression>),
+    AddGm(u64),
+    RemoveGm(u64),
+    ListGms,
+    // `!config` on its own shows the server's settings; with a setting and a
+    // value it changes one.
+    Config(Option<(String, String)>),
+    // The channel's initiative order: rolling for yourself, adding an NPC,
+    // moving to the next turn, starting over, and showing the order.
+    InitRoll(Box<Expression>),
+    InitAdd(String, Box<Expression>),
+    InitNext,
+    InitClear,
+    InitList,
+    // Character sheets: making one, switching to one, showing one (the one
+    // being played by default) and setting fields, keeping the source text.
+    CharCreate(String),
+    CharUse(String),
+    CharShow(Option<String>),
+    CharSet(String, Box<Expression>, String),
+    CharSkill(String, Box<Expression>, String),
+    // Random tables: setting one from its entries, rolling on one, showing one
+    // and importing them from a file or attachment.
+    TableSet(String, String),
+    TableRoll(String),
+    TableShow(String),
+    TableImport(Option<String>),
+    // The channel's decks of cards, by name: starting one, drawing, looking at
+    // what's next, discarding what's been drawn, shuffling what's left,
+    // shuffling every card back in, and showing where the cards are.
+    DeckNew(String, DeckKind),
+    DeckDraw(String, usize),
+    DeckPeek(String, usize),
+    DeckDiscard(String),
+    DeckShuffle(String),
+    DeckReshuffle(String),
+    DeckShow(String),
+    // The channel's roll log: the last rolls, optionally only one user's, and
+    // the whole log written to the named file or attachment.
+    LogLast(usize, Option<u64>),
+    LogExport(Option<String>, Option<Period>),
+    // Statistics of the rolls logged in the channel, optionally only those
+    // rolled in the last period.
+    Luck(LuckOf, Option<Period>),
     PrintEnv,
-    Help,
+    // The overview of commands, or the page on one topic.
+    Help(Option<HelpTopic>),
+}
+
+// The help pages beyond the overview, each short enough for one Discord
+// message.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HelpTopic {
+    Dice,
+    Variables,
+    Secret,
+    Initiative,
+    Characters,
+    Tables,
+    Decks,
+    Log,
+    Config,
+}
+
+impl HelpTopic {
+    pub const ALL: [HelpTopic; 9] = [
+        HelpTopic::Dice,
+        HelpTopic::Variables,
+        HelpTopic::Secret,
+        HelpTopic::Initiative,
+        HelpTopic::Characters,
+        HelpTopic::Tables,
+        HelpTopic::Decks,
+        HelpTopic::Log,
+        HelpTopic::Config,
+    ];
+
+    // What the topic is called in `!help [topic]`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HelpTopic::Dice => "dice",
+            HelpTopic::Variables => "variables",
+            HelpTopic::Secret => "secret",
+            HelpTopic::Initiative => "init",
+            HelpTopic::Characters => "char",
+            HelpTopic::Tables => "tables",
+            HelpTopic::Decks => "decks",
+            HelpTopic::Log => "log",
+            HelpTopic::Config => "config",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ScopeRef {
+    Current,
+    Home,
+    Channel(String),
+}
+
+// The cards a new deck starts with: 52 cards and two jokers, a 78 card tarot
+// deck, or cards of the user's own.
+#[derive(Debug, PartialEq, Clone)]
+pub enum DeckKind {
+    Standard,
+    Tarot,
+    Custom(Vec<String>),
+}
+
+// Whose rolls `!luck` looks at: your own, another user's or everyone's.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LuckOf {
+    Me,
+    User(u64),
+    Channel,
+}
+
+// A span of time back from now, e.g. 7d for the last seven days.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Period {
+    pub count: u64,
+    pub unit: TimeUnit,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TimeUnit {
+    Minutes,
+    Hours,
+    Days,
+    Weeks,
+}
+
+impl Period {
+    pub fn seconds(&self) -> u64 {
+        let unit = match self.unit {
+            TimeUnit::Minutes => 60,
+            TimeUnit::Hours => 60 * 60,
+            TimeUnit::Days => 24 * 60 * 60,
+            TimeUnit::Weeks => 7 * 24 * 60 * 60,
+        };
+        self.count.saturating_mul(unit)
+    }
+}
+
+// What to do when an imported variable is already set.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OnConflict {
+    Skip,
+    Overwrite,
+    Rename,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -40,33 +208,444 @@ pub enum Expression {
     Term(Box<Expression>, Box<Expression>, Op),
 }
 
+// The faces shown by one dice term, e.g. [3, 5] for a 2d6.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DieRoll {
+    pub sides: i64,
+    pub results: Vec<i64>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Critical {
+    Success,
+    Failure,
+}
+
+// A finished roll, with every die that went into its total.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RollResult {
+    pub expression: String,
+    pub dice: Vec<DieRoll>,
+    pub total: i64,
+}
+
+impl RollResult {
+    // A natural 20 on any d20 is a critical success, otherwise a natural 1 is
+    // a critical failure.
+    pub fn critical(&self) -> Option<Critical> {
+        let d20s: Vec<i64> = self
+            .dice
+            .iter()
+            .filter(|die| die.sides == 20)
+            .flat_map(|die| die.results.iter().copied())
+            .collect();
+        if d20s.contains(&20) {
+            Some(Critical::Success)
+        } else if d20s.contains(&1) {
+            Some(Critical::Failure)
+        } else {
+            None
+        }
+    }
+}
+
+// What a statement evaluated to. Rolls are kept whole so a Renderer can show
+// their dice.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Output {
+    Text(String),
+    Roll(RollResult),
+    // A roll to show only to whoever rolled it and the listed users.
+    SecretRoll(RollResult, Vec<u64>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VariableVersion {
+    pub version: u64,
+    pub expression: Expression,
+    pub source: Option<String>,
+}
+
 pub trait Context {
     fn user_context_key(&self) -> String;
     fn global_context_key(&self) -> String;
+    // The scope shared by everyone in the same server, if there is one.
+    fn guild_context_key(&self) -> Option<String> {
+        None
+    }
+    // The same user in another scope, e.g. another channel.
+    fn scope_context(&self, scope: &str) -> REPLContext;
+    // The id of the user running statements, e.g. to mention them.
+    fn user_id(&self) -> String {
+        (&self.scope_context(HOME_SCOPE)).user_id()
+    }
+    // The name of the scope statements are run in, e.g. a Discord channel's id.
+    fn scope_name(&self) -> String {
+        self.global_context_key()
+    }
+    // The user's home scope, looked in when a variable isn't set in this one.
+    fn home_context(&self) -> Option<REPLContext> {
+        None
+    }
+    // The settings of the server statements are run in.
+    fn config(&self) -> Config {
+        Config::default()
+    }
+    // Whether the user may change the server's settings.
+    fn is_admin(&self) -> bool {
+        false
+    }
 }
 
-pub trait Environment {
+// The scope shared by everyone in ctx's scope, e.g. a channel's settings.
+#[derive(Debug, Clone, Copy)]
+pub struct Global<C>(pub C);
+
+impl<C: Context> Context for Global<C> {
+    fn user_context_key(&self) -> String {
+        self.0.global_context_key()
+    }
+
+    fn global_context_key(&self) -> String {
+        self.0.global_context_key()
+    }
+
+    fn guild_context_key(&self) -> Option<String> {
+        self.0.guild_context_key()
+    }
+
+    fn scope_context(&self, scope: &str) -> REPLContext {
+        self.0.scope_context(scope)
+    }
+
+    fn scope_name(&self) -> String {
+        self.0.scope_name()
+    }
+
+    fn config(&self) -> Config {
+        self.0.config()
+    }
+
+    fn is_admin(&self) -> bool {
+        self.0.is_admin()
+    }
+}
+
+// The scope shared by everyone in ctx's server, or ctx's global scope outside
+// of one, e.g. for the server's settings.
+#[derive(Debug, Clone, Copy)]
+pub struct Guild<C>(pub C);
+
+impl<C: Context> Context for Guild<C> {
+    fn user_context_key(&self) -> String {
+        self.0
+            .guild_context_key()
+            .unwrap_or_else(|| self.0.global_context_key())
+    }
+
+    fn global_context_key(&self) -> String {
+        self.0.global_context_key()
+    }
+
+    fn guild_context_key(&self) -> Option<String> {
+        self.0.guild_context_key()
+    }
+
+    fn scope_context(&self, scope: &str) -> REPLContext {
+        self.0.scope_context(scope)
+    }
+
+    fn scope_name(&self) -> String {
+        self.0.scope_name()
+    }
+
+    fn config(&self) -> Config {
+        self.0.config()
+    }
+
+    fn is_admin(&self) -> bool {
+        self.0.is_admin()
+    }
+}
+
+pub trait Environment: Send + Sync {
+    // A variable's value, or None if it isn't set or couldn't be read.
     fn get<C: Context + Send>(
         &self,
         ctx: C,
         var_name: &str,
     ) -> impl std::future::Future<Output = Option<Expression>> + Send;
+    // Like get, but reports a variable that couldn't be read, e.g. because the
+    // storage is down or the saved value is corrupt, instead of treating it as
+    // unset. Environments that can't fail to read fall back to get.
+    fn try_get<C: Context + Send>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> impl std::future::Future<Output = Result<Option<Expression>, RollerError>> + Send {
+        let value = self.get(ctx, var_name);
+        async move { Ok(value.await) }
+    }
     fn set<C: Context + Send>(
         &mut self,
         ctx: C,
         var_name: &str,
         value: &Expression,
-    ) -> impl std::future::Future<Output = ()> + Send;
+    ) -> impl std::future::Future<Output = Result<(), RollerError>> + Send;
+    // Saves a variable along with the source text it was written as, so it can be
+    // listed and edited in the author's own phrasing. Environments that don't
+    // keep source text save just the value.
+    fn set_with_source<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        var_name: &str,
+        value: &Expression,
+        _source: &str,
+    ) -> impl std::future::Future<Output = Result<(), RollerError>> + Send {
+        self.set(ctx, var_name, value)
+    }
+    // The source text a variable was saved with, if any. Saving a variable
+    // without source text clears it.
+    fn source<C: Context + Send>(
+        &self,
+        _ctx: C,
+        _var_name: &str,
+    ) -> impl std::future::Future<Output = Option<String>> + Send {
+        async { None }
+    }
+    // Like source, reporting source text that couldn't be read.
+    fn try_source<C: Context + Send>(
+        &self,
+        ctx: C,
+        var_name: &str,
+    ) -> impl std::future::Future<Output = Result<Option<String>, RollerError>> + Send {
+        let source = self.source(ctx, var_name);
+        async move { Ok(source.await) }
+    }
+    // Saves several variables, each with the source text it was written as if
+    // it has any, e.g. for an import. Environments that write everything out on
+    // each change save them all in one write.
+    fn set_all<C: Context + Copy + Send>(
+        &mut self,
+        ctx: C,
+        variables: &[(String, Expression, Option<String>)],
+    ) -> impl std::future::Future<Output = Result<(), RollerError>> + Send {
+        async move {
+            for (name, value, source) in variables {
+                match source {
+                    Some(source) => self.set_with_source(ctx, name, value, source).await?,
+                    None => self.set(ctx, name, value).await?,
+                }
+            }
+            Ok(())
+        }
+    }
+    // Copies every variable in one scope into another, along with its source
+    // text, replacing any the target already has. Returns the names copied.
+    fn copy_vars<F: Context + Copy + Send, T: Context + Copy + Send>(
+        &mut self,
+        from: F,
+        to: T,
+    ) -> impl std::future::Future<Output = Result<Vec<String>, RollerError>> + Send {
+        async move {
+            let mut variables: Vec<_> = self
+                .closure_with_sources(from)
+                .await?
+                .into_iter()
+                .map(|(name, (expression, source))| (name, expression, source))
+                .collect();
+            variables.sort_by(|a, b| a.0.cmp(&b.0));
+            self.set_all(to, &variables).await?;
+            Ok(variables.into_iter().map(|(name, _, _)| name).collect())
+        }
+    }
     fn print<C: Context + Send>(&self, ctx: C) -> impl std::future::Future<Output = String> + Send;
     fn closure<C: Context + Send>(
         &self,
         ctx: C,
-    ) -> impl std::future::Future<Output = Result<HashMap<String, Expression>, ()>> + Send;
+    ) -> impl std::future::Future<Output = Result<HashMap<String, Expression>, RollerError>> + Send;
+    // Like closure, along with the source text each variable was saved with.
+    // Environments that can read both at once should, rather than reading each
+    // variable's source on its own.
+    fn closure_with_sources<C: Context + Copy + Send>(
+        &self,
+        ctx: C,
+    ) -> impl std::future::Future<
+        Output = Result<HashMap<String, (Expression, Option<String>)>, RollerError>,
+    > + Send {
+        async move {
+            let mut variables = HashMap::new();
+            for (name, expression) in self.closure(ctx).await? {
+                let source = self.try_source(ctx, &name).await?;
+                variables.insert(name, (expression, source));
+            }
+            Ok(variables)
+        }
+    }
+    // Structured state kept alongside a scope's variables, like a channel's GM
+    // list, stored as JSON under a name. Environments that can't store it
+    // report an error.
+    fn state<C: Context + Send>(
+        &self,
+        _ctx: C,
+        _name: &str,
+    ) -> impl std::future::Future<Output = Result<Option<Value>, RollerError>> + Send {
+        async {
+            Err(RollerError::StorageError(
+                "this environment can't store state".to_string(),
+            ))
+        }
+    }
+    fn set_state<C: Context + Send>(
+        &mut self,
+        _ctx: C,
+        _name: &str,
+        _value: &Value,
+    ) -> impl std::future::Future<Output = Result<(), RollerError>> + Send {
+        async {
+            Err(RollerError::StorageError(
+                "this environment can't store state".to_string(),
+            ))
+        }
+    }
+    // Like state, along with the version it was saved as, so a write based on it
+    // can be turned down if someone else has saved the state since. State that
+    // has never been saved is at version 0, as is every state of environments
+    // that don't number them.
+    fn versioned_state<C: Context + Send>(
+        &self,
+        ctx: C,
+        name: &str,
+    ) -> impl std::future::Future<Output = Result<(Option<Value>, u64), RollerError>> + Send {
+        let state = self.state(ctx, name);
+        async move { Ok((state.await?, 0)) }
+    }
+    // Saves state read at expected_version, failing with a ConflictError if it
+    // has been saved since. Environments that don't number states can only be
+    // written through one handle at a time, so they just save it.
+    fn set_state_version<C: Context + Send>(
+        &mut self,
+        ctx: C,
+        name: &str,
+        value: &Value,
+        _expected_version: u64,
+    ) -> impl std::future::Future<Output = Result<(), RollerError>> + Send {
+        self.set_state(ctx, name, value)
+    }
+    // Moves everything saved in one scope, variables, their history and state,
+    // into another, keeping whatever the target already has. Returns the number
+    // of items moved.
+    fn move_scope<F: Context + Send, T: Context + Send>(
+        &mut self,
+        _from: F,
+        _to: T,
+    ) -> impl std::future::Future<Output = Result<u64, RollerError>> + Send {
+        async {
+            Err(RollerError::StorageError(
+                "this environment can't move scopes".to_string(),
+            ))
+        }
+    }
+    // Remembers the statement behind a message's reroll buttons, so they keep
+    // working after a restart. Environments that can't report an error.
+    fn set_pending_roll(
+        &mut self,
+        _message_id: &str,
+        _statement: &str,
+    ) -> impl std::future::Future<Output = Result<(), RollerError>> + Send {
+        async {
+            Err(RollerError::StorageError(
+                "this environment can't remember rolls".to_string(),
+            ))
+        }
+    }
+    fn pending_roll(
+        &self,
+        _message_id: &str,
+    ) -> impl std::future::Future<Output = Result<Option<String>, RollerError>> + Send {
+        async {
+            Err(RollerError::StorageError(
+                "this environment can't remember rolls".to_string(),
+            ))
+        }
+    }
+    // Adds a roll to the log of ctx's scope. Each roll is saved on its own, so
+    // rolls made at the same time don't overwrite each other. Environments that
+    // can't keep a log report an error.
+    fn log_roll<C: Context + Send>(
+        &mut self,
+        _ctx: C,
+        _roll: &LoggedRoll,
+    ) -> impl std::future::Future<Output = Result<(), RollerError>> + Send {
+        async {
+            Err(RollerError::StorageError(
+                "this environment can't log rolls".to_string(),
+            ))
+        }
+    }
+    // The rolls logged in ctx's scope at or after since, in seconds since the
+    // Unix epoch, oldest first. With a limit, only the newest that many.
+    fn rolls<C: Context + Send>(
+        &self,
+        _ctx: C,
+        _since: u64,
+        _limit: Option<usize>,
+    ) -> impl std::future::Future<Output = Result<Vec<LoggedRoll>, RollerError>> + Send {
+        async {
+            Err(RollerError::StorageError(
+                "this environment can't log rolls".to_string(),
+            ))
+        }
+    }
+    // Previous values of a variable, newest first. Environments that don't keep
+    // history report an error.
+    fn history<C: Context + Send>(
+        &self,
+        _ctx: C,
+        _var_name: &str,
+    ) -> impl std::future::Future<Output = Result<Vec<VariableVersion>, RollerError>> + Send {
+        async {
+            Err(RollerError::StorageError(
+                "this environment doesn't keep variable history".to_string(),
+            ))
+        }
+    }
+    // The version a variable was last saved as, 0 if it isn't set, or None if the
+    // environment doesn't number variables.
+    fn version<C: Context + Send>(
+        &self,
+        _ctx: C,
+        _var_name: &str,
+    ) -> impl std::future::Future<Output = Result<Option<u64>, RollerError>> + Send {
+        async { Ok(None) }
+    }
+    // Saves a variable, with the source text it was written as if there is any,
+    // only if it's still at expected_version. Fails with a ConflictError if it
+    // has been saved since. Environments that don't number variables report an
+    // error.
+    fn set_version<C: Context + Send>(
+        &mut self,
+        _ctx: C,
+        _var_name: &str,
+        _value: &Expression,
+        _source: Option<&str>,
+        _expected_version: u64,
+    ) -> impl std::future::Future<Output = Result<(), RollerError>> + Send {
+        async {
+            Err(RollerError::StorageError(
+                "this environment doesn't number variables".to_string(),
+            ))
+        }
+    }
 }
 
+// An environment whose clones are handles on the same storage, so each task can
+// be given its own clone and write to it without holding a lock over the others.
+pub trait SharedEnvironment: Environment + Clone + Sync {}
+
 pub trait Visitor<S, E> {
-    async fn visit_expression(&mut self, expr: &Expression) -> E;
-    async fn visit_statement(&mut self, stmt: &Statement) -> S;
+    fn visit_expression(&mut self, expr: &Expression) -> impl std::future::Future<Output = E>;
+    fn visit_statement(&mut self, stmt: &Statement) -> impl std::future::Future<Output = S>;
 }
 
 pub trait Parser<E> {
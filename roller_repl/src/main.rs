@@ -1,33 +1,194 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use roller_lang::dynamodb::LOCALSTACK_ENDPOINT;
+use roller_lang::environments::file_environment::FileEnvironment;
 use rustyline::Result;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+enum Storage {
+    /// Keep the environment in memory only, forgetting it on exit
+    Memory,
+    /// Persist environment to a JSON file in the user's data directory
+    File,
+    /// Persist environment to a SQLite database
+    Sqlite,
+    /// Persist environment to DynamoDB, on localstack unless --endpoint says otherwise
+    Dynamodb,
+}
 
 /// REPL for dice-roller commands
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Persist environment to DynamoDB
-    #[arg(short, long, default_value_t = true)]
-    dynamodb: bool,
+    /// Where to persist the environment
+    #[arg(short, long, value_enum, default_value_t = Storage::File)]
+    storage: Storage,
+
+    /// Path of the environment file or SQLite database
+    #[arg(short, long)]
+    path: Option<PathBuf>,
+
+    /// DynamoDB endpoint to connect to
+    #[arg(long, default_value = LOCALSTACK_ENDPOINT, conflicts_with = "aws")]
+    endpoint: String,
+
+    /// Connect to AWS's own DynamoDB endpoint for the configured region
+    #[arg(long)]
+    aws: bool,
+
+    /// Upgrade every stored variable to the current schema and exit, failing if
+    /// any can't be upgraded
+    #[arg(long)]
+    migrate: bool,
+
+    /// Move variables saved under Discord usernames to the ids listed in FILE,
+    /// one `username,id` pair to a line, and exit
+    #[arg(long, value_name = "FILE", conflicts_with = "migrate")]
+    migrate_users: Option<PathBuf>,
 }
 
-fn main() {
+impl Args {
+    fn dynamodb_endpoint(&self) -> Option<&str> {
+        (!self.aws).then_some(self.endpoint.as_str())
+    }
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
+    if args.migrate {
+        return migrate(&args);
+    }
+    if let Some(path) = &args.migrate_users {
+        return migrate_users(&args, path);
+    }
     println!("No dice roll statement. Starting the REPL...\n Use Ctrl+C to quit.",);
-    let repl = if args.dynamodb {
-        repl_with_db()
-    } else {
-        std_repl()
+    let repl = match args.storage {
+        Storage::Memory => std_repl(),
+        Storage::File => file_repl(args.path),
+        Storage::Sqlite => sqlite_repl(args.path),
+        Storage::Dynamodb => repl_with_db(args.dynamodb_endpoint()),
     };
     match repl {
-        Ok(()) => println!("Closing REPL"),
-        Err(err) => println!("Error: {:?}", err),
+        Ok(()) => {
+            println!("Closing REPL");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            println!("Error: {:?}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[tokio::main]
+async fn migrate(args: &Args) -> ExitCode {
+    let report = match args.storage {
+        Storage::Sqlite => {
+            let sqlite_client = match &args.path {
+                Some(path) => roller_lang::sqlite::SqliteClient::open(path),
+                None => roller_lang::sqlite::SqliteClient::with_default_path(),
+            };
+            sqlite_client
+                .expect("failed to open sqlite database")
+                .migrate_expressions()
+                .map_err(|err| err.to_string())
+        }
+        Storage::Dynamodb => roller_lang::dynamodb::DDBClient::with_default_table(
+            roller_lang::dynamodb::make_client(args.dynamodb_endpoint())
+                .await
+                .expect("failed to start dynamo client"),
+        )
+        .migrate_all()
+        .await
+        .map_err(|err| err.to_string()),
+        Storage::Memory | Storage::File => {
+            println!("Nothing to migrate for {:?} storage", args.storage);
+            return ExitCode::SUCCESS;
+        }
+    };
+    match report {
+        Ok(report) if report.succeeded() => {
+            println!("Migration finished: {}", report);
+            ExitCode::SUCCESS
+        }
+        Ok(report) => {
+            eprintln!("Migration finished with failures: {}", report);
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            eprintln!("Migration failed: {}", err);
+            ExitCode::FAILURE
+        }
     }
 }
 
 #[tokio::main]
-async fn repl_with_db() -> Result<()> {
+async fn migrate_users(args: &Args, path: &PathBuf) -> ExitCode {
+    let user_ids = match std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| roller_lang::discord::read_user_ids(&text).map_err(|err| err.to_string()))
+    {
+        Ok(user_ids) => user_ids,
+        Err(err) => {
+            eprintln!("Can't read {}: {}", path.display(), err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let report = match args.storage {
+        Storage::Sqlite => {
+            let sqlite_client = match &args.path {
+                Some(path) => roller_lang::sqlite::SqliteClient::open(path),
+                None => roller_lang::sqlite::SqliteClient::with_default_path(),
+            }
+            .expect("failed to open sqlite database");
+            match sqlite_client.scopes() {
+                Ok(scopes) => {
+                    let repl = &mut roller_lang::repl::REPL::new_sqlite(sqlite_client);
+                    Ok(roller_lang::discord::migrate_usernames(repl, &scopes, &user_ids).await)
+                }
+                Err(err) => Err(err.to_string()),
+            }
+        }
+        Storage::Dynamodb => {
+            let ddb_client = roller_lang::dynamodb::DDBClient::with_default_table(
+                roller_lang::dynamodb::make_client(args.dynamodb_endpoint())
+                    .await
+                    .expect("failed to start dynamo client"),
+            );
+            match ddb_client.partitions().await {
+                Ok(scopes) => {
+                    let repl = &mut roller_lang::repl::REPL::new(ddb_client);
+                    Ok(roller_lang::discord::migrate_usernames(repl, &scopes, &user_ids).await)
+                }
+                Err(err) => Err(err.to_string()),
+            }
+        }
+        Storage::Memory | Storage::File => {
+            println!("Nothing to migrate for {:?} storage", args.storage);
+            return ExitCode::SUCCESS;
+        }
+    };
+    match report {
+        Ok(report) if report.succeeded() => {
+            println!("Migration finished: {}", report);
+            ExitCode::SUCCESS
+        }
+        Ok(report) => {
+            eprintln!("Migration finished with failures: {}", report);
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            eprintln!("Migration failed: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[tokio::main]
+async fn repl_with_db(endpoint: Option<&str>) -> Result<()> {
     let ddb_client = roller_lang::dynamodb::DDBClient::with_default_table(
-        roller_lang::dynamodb::make_client(true)
+        roller_lang::dynamodb::make_client(endpoint)
             .await
             .expect("failed to start dynamo client"),
     );
@@ -35,6 +196,28 @@ async fn repl_with_db() -> Result<()> {
     roller_lang::readline::init(&mut repl).await
 }
 
+#[tokio::main]
+async fn sqlite_repl(path: Option<PathBuf>) -> Result<()> {
+    let sqlite_client = match path {
+        Some(path) => roller_lang::sqlite::SqliteClient::open(path),
+        None => roller_lang::sqlite::SqliteClient::with_default_path(),
+    };
+    let mut repl =
+        roller_lang::repl::REPL::new_sqlite(sqlite_client.expect("failed to open sqlite database"));
+    roller_lang::readline::init(&mut repl).await
+}
+
+#[tokio::main]
+async fn file_repl(path: Option<PathBuf>) -> Result<()> {
+    let environment = match path {
+        Some(path) => FileEnvironment::open(path),
+        None => FileEnvironment::with_default_path(),
+    };
+    let mut repl =
+        roller_lang::repl::REPL::new_file(environment.expect("failed to open environment file"));
+    roller_lang::readline::init(&mut repl).await
+}
+
 #[tokio::main]
 async fn std_repl() -> Result<()> {
     let mut repl = roller_lang::repl::REPL::default();
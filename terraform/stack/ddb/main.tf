@@ -13,4 +13,10 @@ resource "aws_dynamodb_table" "environment_table" {
     name = "sk"
     type = "S"
   }
+
+  # Logged rolls and the rolls behind buttons are tagged with when to drop them.
+  ttl {
+    attribute_name = "expires_at"
+    enabled        = true
+  }
 }